@@ -0,0 +1,128 @@
+use device::Device;
+use rx::RxMode;
+use payload::Payload;
+use config::Configuration;
+use PIPES_COUNT;
+
+/// Ack payloads share the TX FIFO
+const ACK_FIFO_DEPTH: usize = 3;
+
+/// An ack payload waiting for the next packet on its pipe
+pub struct Staged<M> {
+    pub packet: Payload,
+    /// Kept for the owner, e.g. when the packet was staged
+    pub meta: M,
+    /// Written to the TX FIFO
+    in_fifo: bool,
+    /// Kept out of the TX FIFO until the peer returns
+    held: bool,
+}
+
+/// One staged ack payload per pipe, written to the TX FIFO as long as
+/// it has room
+pub struct AckStage<M> {
+    staged: [Option<Staged<M>>; PIPES_COUNT],
+    /// Number of ack payloads in the TX FIFO
+    in_fifo: usize,
+}
+
+impl<M> AckStage<M> {
+    pub fn new() -> Self {
+        AckStage {
+            staged: [None, None, None, None, None, None],
+            in_fifo: 0,
+        }
+    }
+
+    pub fn get(&self, pipe_no: usize) -> Option<&Staged<M>> {
+        self.staged[pipe_no].as_ref()
+    }
+
+    /// Stage `packet` for `pipe_no`, unless one already is
+    pub fn stage(&mut self, pipe_no: usize, packet: Payload, meta: M) {
+        if self.staged[pipe_no].is_none() {
+            self.staged[pipe_no] = Some(Staged {
+                packet,
+                meta,
+                in_fifo: false,
+                held: false,
+            });
+        }
+    }
+
+    /// Account for a packet received on `pipe_no`. Returns the ack
+    /// payload it carried away, if any.
+    pub fn received(&mut self, pipe_no: usize) -> Option<Staged<M>> {
+        match self.staged[pipe_no] {
+            Some(ref staged) if staged.in_fifo => {}
+            Some(ref mut staged) => {
+                staged.held = false;
+                return None;
+            }
+            None => return None,
+        }
+        self.in_fifo -= 1;
+        self.staged[pipe_no].take()
+    }
+
+    /// Write staged packets to the TX FIFO while it has room, starting
+    /// at `first_pipe`. `next` stages packets for pipes without one.
+    pub fn fill<D, F>(&mut self, rx: &mut RxMode<D>, first_pipe: usize, mut next: F) -> Result<(), D::Error>
+    where
+        D: Device,
+        F: FnMut(usize) -> Option<(Payload, M)>,
+    {
+        for i in 0..PIPES_COUNT {
+            if self.in_fifo >= ACK_FIFO_DEPTH {
+                break;
+            }
+
+            let pipe_no = (first_pipe + i) % PIPES_COUNT;
+            if self.staged[pipe_no].is_none() {
+                if let Some((packet, meta)) = next(pipe_no) {
+                    self.stage(pipe_no, packet, meta);
+                }
+            }
+            if let Some(ref mut staged) = self.staged[pipe_no] {
+                if !staged.in_fifo && !staged.held {
+                    rx.write_ack_payload(pipe_no as u8, &staged.packet)?;
+                    staged.in_fifo = true;
+                    self.in_fifo += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the TX FIFO if it holds ack payloads for pipes that are
+    /// not `awake`. Theirs are held until they send again, or dropped
+    /// if `keep` says so. Refill afterwards with `fill()`.
+    pub fn expire<D, A, K>(&mut self, rx: &mut RxMode<D>, awake: A, keep: K) -> Result<(), D::Error>
+    where
+        D: Device,
+        A: Fn(usize) -> bool,
+        K: Fn(&Staged<M>) -> bool,
+    {
+        let any_asleep = (0..PIPES_COUNT).any(|pipe_no| {
+            matches!(self.staged[pipe_no], Some(ref staged) if staged.in_fifo) && !awake(pipe_no)
+        });
+        if !any_asleep {
+            return Ok(());
+        }
+
+        // The TX FIFO can only be flushed as a whole
+        rx.flush_tx()?;
+        self.in_fifo = 0;
+        for pipe_no in 0..PIPES_COUNT {
+            let asleep = !awake(pipe_no);
+            if asleep && matches!(self.staged[pipe_no], Some(ref staged) if !keep(staged)) {
+                self.staged[pipe_no] = None;
+            }
+            if let Some(ref mut staged) = self.staged[pipe_no] {
+                staged.in_fifo = false;
+                staged.held = asleep;
+            }
+        }
+        Ok(())
+    }
+}
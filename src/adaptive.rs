@@ -0,0 +1,490 @@
+//! Adaptive transmit power and air data rate
+//!
+//! `LinkAdapter` runs on the transmitting end. It evaluates the
+//! retransmit counts from `TxMode::observe()` and the losses reported
+//! by `wait_empty()` over windows of packets and steps along a ladder
+//! of `LinkSetting`s: towards less power and faster rates while the
+//! link is strong, towards more power and slower rates while it is
+//! weak. Separate streak thresholds for both directions provide
+//! hysteresis.
+//!
+//! Both ends have to switch together. The adapter therefore proposes
+//! a `LinkChange`, which the application sends to the peer with the
+//! current setting. Only when it has been acknowledged, both apply
+//! it: the transmitter through `LinkAdapter::commit()`, the receiver
+//! through `LinkFollower::handle()`. If the link breaks down anyway,
+//! both ends fall back to the most robust setting, the last one of
+//! the ladder.
+
+use clock::Clock;
+use config::{Configuration, DataRate, PAControl};
+use device::Device;
+
+/// First byte of an encoded `LinkChange`
+pub const LINK_CHANGE_MAGIC: u8 = 0xAD;
+
+/// Air data rate and output power, one step of the ladder
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LinkSetting {
+    pub rate: DataRate,
+    pub power: PAControl,
+}
+
+impl LinkSetting {
+    pub fn apply<C: Configuration>(&self, radio: &mut C) -> Result<(), <C::Inner as Device>::Error> {
+        radio.set_rf(self.rate, self.power)
+    }
+}
+
+/// From most efficient to most robust
+pub const DEFAULT_LADDER: [LinkSetting; 6] = [
+    LinkSetting { rate: DataRate::R2Mbps, power: PAControl::PAMin },
+    LinkSetting { rate: DataRate::R2Mbps, power: PAControl::PAMinus12 },
+    LinkSetting { rate: DataRate::R2Mbps, power: PAControl::PAMinus6 },
+    LinkSetting { rate: DataRate::R2Mbps, power: PAControl::PAMax },
+    LinkSetting { rate: DataRate::R1Mbps, power: PAControl::PAMax },
+    LinkSetting { rate: DataRate::R250Kbps, power: PAControl::PAMax },
+];
+
+/// Thresholds for the `LinkAdapter`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct AdaptivePolicy {
+    /// Packets per evaluation window
+    pub window: u8,
+    /// A window with at most this many retransmits in total and no
+    /// loss counts as strong
+    pub strong_retransmits: u16,
+    /// A window with at least this many retransmits in total, or any
+    /// loss, counts as weak
+    pub weak_retransmits: u16,
+    /// Strong windows in a row before stepping to a more efficient
+    /// setting
+    pub strong_windows: u8,
+    /// Weak windows in a row before stepping to a more robust setting
+    pub weak_windows: u8,
+    /// Lost packets in a row after which the peer is considered
+    /// unreachable and the most robust setting is used right away
+    pub fallback_losses: u8,
+}
+
+impl Default for AdaptivePolicy {
+    fn default() -> Self {
+        AdaptivePolicy {
+            window: 16,
+            strong_retransmits: 1,
+            weak_retransmits: 16,
+            strong_windows: 4,
+            weak_windows: 1,
+            fallback_losses: 3,
+        }
+    }
+}
+
+/// Announcement of a new setting to the peer
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LinkChange {
+    /// Distinguishes announcements so that retransmits are not
+    /// applied twice
+    pub epoch: u8,
+    pub setting: LinkSetting,
+}
+
+impl LinkChange {
+    /// Length of the encoding
+    pub const LEN: usize = 4;
+
+    /// Returns the used part of `buf`, which must hold `LEN` bytes
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        buf[0] = LINK_CHANGE_MAGIC;
+        buf[1] = self.epoch;
+        buf[2] = match self.setting.rate {
+            DataRate::R250Kbps => 0,
+            DataRate::R1Mbps => 1,
+            DataRate::R2Mbps => 2,
+        };
+        buf[3] = self.setting.power as u8;
+        &buf[0..Self::LEN]
+    }
+
+    /// `None` if `packet` is no announcement
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        if packet.len() != Self::LEN || packet[0] != LINK_CHANGE_MAGIC {
+            return None;
+        }
+
+        let rate = match packet[2] {
+            0 => DataRate::R250Kbps,
+            1 => DataRate::R1Mbps,
+            2 => DataRate::R2Mbps,
+            _ => return None,
+        };
+        let power = match packet[3] {
+            0b00 => PAControl::PAMin,
+            0b01 => PAControl::PAMinus12,
+            0b10 => PAControl::PAMinus6,
+            0b11 => PAControl::PAMax,
+            _ => return None,
+        };
+        Some(LinkChange {
+            epoch: packet[1],
+            setting: LinkSetting { rate, power },
+        })
+    }
+}
+
+/// Result of `LinkAdapter::record()`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Adaptation {
+    /// Send the encoded change to the peer with the current setting.
+    /// Then call `commit()` if it was acknowledged, `abort()` if not.
+    Announce(LinkChange),
+    /// The peer has become unreachable, apply this most robust
+    /// setting right away
+    Fallback(LinkSetting),
+}
+
+/// Transmitting end of the adaptation, see the module documentation
+#[derive(Debug)]
+pub struct LinkAdapter<'a> {
+    ladder: &'a [LinkSetting],
+    policy: AdaptivePolicy,
+    level: usize,
+    epoch: u8,
+    /// Level announced but not yet committed
+    pending: Option<usize>,
+    packets: u8,
+    retransmits: u16,
+    losses: u8,
+    losses_in_row: u8,
+    strong_streak: u8,
+    weak_streak: u8,
+}
+
+impl<'a> LinkAdapter<'a> {
+    /// Starts at `level` of `ladder`, which goes from most efficient
+    /// to most robust
+    pub fn new(ladder: &'a [LinkSetting], level: usize, policy: AdaptivePolicy) -> Self {
+        assert!(level < ladder.len());
+        LinkAdapter {
+            ladder,
+            policy,
+            level,
+            epoch: 0,
+            pending: None,
+            packets: 0,
+            retransmits: 0,
+            losses: 0,
+            losses_in_row: 0,
+            strong_streak: 0,
+            weak_streak: 0,
+        }
+    }
+
+    /// Setting currently in effect
+    pub fn setting(&self) -> LinkSetting {
+        self.ladder[self.level]
+    }
+
+    /// Position in the ladder
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Account for one sent packet
+    ///
+    /// `retransmits` is `arc_cnt()` of `TxMode::observe()`,
+    /// `delivered` the result of `wait_empty()`.
+    pub fn record(&mut self, retransmits: u8, delivered: bool) -> Option<Adaptation> {
+        if self.pending.is_some() {
+            // Waiting for commit() or abort()
+            return None;
+        }
+
+        if delivered {
+            self.losses_in_row = 0;
+        } else {
+            self.losses = self.losses.saturating_add(1);
+            self.losses_in_row = self.losses_in_row.saturating_add(1);
+            if self.losses_in_row >= self.policy.fallback_losses {
+                return self.fallback();
+            }
+        }
+        self.retransmits = self.retransmits.saturating_add(retransmits.into());
+        self.packets += 1;
+        if self.packets < self.policy.window {
+            return None;
+        }
+
+        // Evaluate the window
+        let strong = self.losses == 0 &&
+            self.retransmits <= self.policy.strong_retransmits;
+        let weak = self.losses > 0 ||
+            self.retransmits >= self.policy.weak_retransmits;
+        self.packets = 0;
+        self.retransmits = 0;
+        self.losses = 0;
+
+        if strong {
+            self.weak_streak = 0;
+            self.strong_streak = self.strong_streak.saturating_add(1);
+            if self.strong_streak >= self.policy.strong_windows && self.level > 0 {
+                self.strong_streak = 0;
+                return Some(self.announce(self.level - 1));
+            }
+        } else if weak {
+            self.strong_streak = 0;
+            self.weak_streak = self.weak_streak.saturating_add(1);
+            if self.weak_streak >= self.policy.weak_windows && self.level + 1 < self.ladder.len() {
+                self.weak_streak = 0;
+                return Some(self.announce(self.level + 1));
+            }
+        } else {
+            self.strong_streak = 0;
+            self.weak_streak = 0;
+        }
+        None
+    }
+
+    fn announce(&mut self, level: usize) -> Adaptation {
+        self.epoch = self.epoch.wrapping_add(1);
+        self.pending = Some(level);
+        Adaptation::Announce(LinkChange {
+            epoch: self.epoch,
+            setting: self.ladder[level],
+        })
+    }
+
+    fn fallback(&mut self) -> Option<Adaptation> {
+        let robust = self.ladder.len() - 1;
+        self.losses_in_row = 0;
+        self.reset_window();
+        if self.level == robust {
+            return None;
+        }
+        self.level = robust;
+        Some(Adaptation::Fallback(self.ladder[robust]))
+    }
+
+    fn reset_window(&mut self) {
+        self.packets = 0;
+        self.retransmits = 0;
+        self.losses = 0;
+        self.strong_streak = 0;
+        self.weak_streak = 0;
+    }
+
+    /// The announcement was acknowledged, returns the setting to apply
+    pub fn commit(&mut self) -> Option<LinkSetting> {
+        let level = self.pending.take()?;
+        self.level = level;
+        self.reset_window();
+        Some(self.ladder[level])
+    }
+
+    /// The announcement was not acknowledged, stay at the current
+    /// setting
+    pub fn abort(&mut self) {
+        self.pending = None;
+        self.reset_window();
+    }
+}
+
+/// Receiving end of the adaptation, see the module documentation
+#[derive(Debug)]
+pub struct LinkFollower {
+    setting: LinkSetting,
+    robust: LinkSetting,
+    epoch: Option<u8>,
+    silence_us: u32,
+    last_heard: Option<u32>,
+}
+
+impl LinkFollower {
+    /// Falls back to `robust` after not hearing any packet for
+    /// `silence_us`
+    pub fn new(setting: LinkSetting, robust: LinkSetting, silence_us: u32) -> Self {
+        LinkFollower {
+            setting,
+            robust,
+            epoch: None,
+            silence_us,
+            last_heard: None,
+        }
+    }
+
+    /// Setting currently in effect
+    pub fn setting(&self) -> LinkSetting {
+        self.setting
+    }
+
+    /// Pass every received packet. Returns the setting to apply if
+    /// it was a new announcement.
+    pub fn handle<C: Clock>(&mut self, packet: &[u8], clock: &mut C) -> Option<LinkSetting> {
+        self.last_heard = Some(clock.now_us());
+
+        let change = LinkChange::decode(packet)?;
+        if self.epoch == Some(change.epoch) {
+            // Retransmitted announcement
+            return None;
+        }
+        self.epoch = Some(change.epoch);
+        if change.setting == self.setting {
+            return None;
+        }
+        self.setting = change.setting;
+        Some(change.setting)
+    }
+
+    /// Call regularly. Returns the robust setting to apply once the
+    /// peer has been silent for too long.
+    pub fn check_silence<C: Clock>(&mut self, clock: &mut C) -> Option<LinkSetting> {
+        let now = clock.now_us();
+        let last_heard = *self.last_heard.get_or_insert(now);
+        if now.wrapping_sub(last_heard) < self.silence_us {
+            return None;
+        }
+
+        self.last_heard = Some(now);
+        if self.setting == self.robust {
+            return None;
+        }
+        self.setting = self.robust;
+        Some(self.robust)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::TestClock;
+
+    const POLICY: AdaptivePolicy = AdaptivePolicy {
+        window: 4,
+        strong_retransmits: 1,
+        weak_retransmits: 4,
+        strong_windows: 2,
+        weak_windows: 2,
+        fallback_losses: 3,
+    };
+
+    /// Record a window of delivered packets with `retransmits` each
+    fn window(adapter: &mut LinkAdapter, retransmits: u8) -> Option<Adaptation> {
+        let mut result = None;
+        for _ in 0..POLICY.window {
+            assert_eq!(result, None);
+            result = adapter.record(retransmits, true);
+        }
+        result
+    }
+
+    fn announced(epoch: u8, level: usize) -> Option<Adaptation> {
+        Some(Adaptation::Announce(LinkChange { epoch, setting: DEFAULT_LADDER[level] }))
+    }
+
+    #[test]
+    fn codec() {
+        let change = LinkChange { epoch: 7, setting: DEFAULT_LADDER[4] };
+        let mut buf = [0; LinkChange::LEN];
+        assert_eq!(LinkChange::decode(change.encode(&mut buf)), Some(change));
+        assert_eq!(LinkChange::decode(&[LINK_CHANGE_MAGIC, 7, 3, 0]), None);
+        assert_eq!(LinkChange::decode(&[0, 7, 1, 0]), None);
+    }
+
+    #[test]
+    fn step_down() {
+        let mut adapter = LinkAdapter::new(&DEFAULT_LADDER, 3, POLICY);
+        assert_eq!(window(&mut adapter, 0), None);
+        // A middling window breaks the streak
+        assert_eq!(window(&mut adapter, 2), None);
+        assert_eq!(window(&mut adapter, 0), None);
+        assert_eq!(window(&mut adapter, 0), announced(1, 2));
+
+        // Nothing is evaluated until committed
+        assert_eq!(window(&mut adapter, 4), None);
+        assert_eq!(adapter.commit(), Some(DEFAULT_LADDER[2]));
+        assert_eq!(adapter.level(), 2);
+        assert_eq!(adapter.commit(), None);
+
+        let mut adapter = LinkAdapter::new(&DEFAULT_LADDER, 0, POLICY);
+        assert_eq!(window(&mut adapter, 0), None);
+        assert_eq!(window(&mut adapter, 0), None);
+    }
+
+    #[test]
+    fn step_up() {
+        let mut adapter = LinkAdapter::new(&DEFAULT_LADDER, 3, POLICY);
+        assert_eq!(window(&mut adapter, 1), None);
+        // A strong window breaks the streak
+        assert_eq!(window(&mut adapter, 0), None);
+        assert_eq!(window(&mut adapter, 1), None);
+        assert_eq!(window(&mut adapter, 1), announced(1, 4));
+
+        adapter.abort();
+        assert_eq!(adapter.level(), 3);
+        assert_eq!(adapter.setting(), DEFAULT_LADDER[3]);
+        // The streak starts over
+        assert_eq!(window(&mut adapter, 1), None);
+        // A single loss makes a window weak
+        assert_eq!(adapter.record(0, false), None);
+        for _ in 1..POLICY.window - 1 {
+            assert_eq!(adapter.record(0, true), None);
+        }
+        assert_eq!(adapter.record(0, true), announced(2, 4));
+        assert_eq!(adapter.commit(), Some(DEFAULT_LADDER[4]));
+    }
+
+    #[test]
+    fn fallback() {
+        let mut adapter = LinkAdapter::new(&DEFAULT_LADDER, 1, POLICY);
+        assert_eq!(adapter.record(0, false), None);
+        assert_eq!(adapter.record(0, false), None);
+        assert_eq!(adapter.record(0, true), None);
+        assert_eq!(adapter.record(0, false), None);
+        assert_eq!(adapter.record(0, false), None);
+        assert_eq!(adapter.record(0, false), Some(Adaptation::Fallback(DEFAULT_LADDER[5])));
+        assert_eq!(adapter.level(), 5);
+
+        for _ in 0..POLICY.fallback_losses {
+            assert_eq!(adapter.record(0, false), None);
+        }
+    }
+
+    #[test]
+    fn follow() {
+        let mut follower = LinkFollower::new(DEFAULT_LADDER[3], DEFAULT_LADDER[5], 1_000);
+        let mut clock = TestClock::new(0);
+        let mut buf = [0; LinkChange::LEN];
+        let change = LinkChange { epoch: 1, setting: DEFAULT_LADDER[2] };
+
+        assert_eq!(follower.handle(change.encode(&mut buf), &mut clock), Some(DEFAULT_LADDER[2]));
+        // Retransmitted
+        assert_eq!(follower.handle(change.encode(&mut buf), &mut clock), None);
+        assert_eq!(follower.handle(b"data", &mut clock), None);
+        assert_eq!(follower.setting(), DEFAULT_LADDER[2]);
+
+        let change = LinkChange { epoch: 2, setting: DEFAULT_LADDER[2] };
+        assert_eq!(follower.handle(change.encode(&mut buf), &mut clock), None);
+        let change = LinkChange { epoch: 3, setting: DEFAULT_LADDER[1] };
+        assert_eq!(follower.handle(change.encode(&mut buf), &mut clock), Some(DEFAULT_LADDER[1]));
+    }
+
+    #[test]
+    fn silence() {
+        let mut follower = LinkFollower::new(DEFAULT_LADDER[3], DEFAULT_LADDER[5], 1_000);
+        let mut clock = TestClock::new(0);
+
+        assert_eq!(follower.check_silence(&mut clock), None);
+        clock.now = 999;
+        assert_eq!(follower.check_silence(&mut clock), None);
+        // Any packet counts
+        clock.now = 500;
+        follower.handle(b"data", &mut clock);
+        clock.now = 1_499;
+        assert_eq!(follower.check_silence(&mut clock), None);
+        clock.now = 1_500;
+        assert_eq!(follower.check_silence(&mut clock), Some(DEFAULT_LADDER[5]));
+        assert_eq!(follower.setting(), DEFAULT_LADDER[5]);
+        clock.now = 3_000;
+        assert_eq!(follower.check_silence(&mut clock), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! Decode captured SPI traffic to an nRF24L01
+//!
+//! ```text
+//! nrf24-decode [--format raw|csv|vcd] [--signals CSN,SCK,MOSI,MISO] CAPTURE [OTHER]
+//! ```
+//!
+//! Prints every command with the register changes it caused. Given a
+//! second capture, e.g. from a working node, it prints the registers
+//! that ended up configured differently instead.
+
+extern crate embedded_nrf24l01;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use embedded_nrf24l01::decode::{
+    Decoder, Transaction, VcdSignals, parse_raw, parse_csv, parse_vcd,
+    register_name, describe_register,
+};
+
+const USAGE: &str = "usage: nrf24-decode [--format raw|csv|vcd] [--signals CSN,SCK,MOSI,MISO] CAPTURE [OTHER]";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1)
+}
+
+fn load(path: &str, format: Option<&str>, signals: &VcdSignals) -> Vec<Transaction> {
+    let input = fs::read_to_string(path)
+        .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let format = format.unwrap_or_else(|| {
+        if path.ends_with(".csv") {
+            "csv"
+        } else if path.ends_with(".vcd") {
+            "vcd"
+        } else {
+            "raw"
+        }
+    });
+    let result = match format {
+        "raw" => parse_raw(&input),
+        "csv" => parse_csv(&input),
+        "vcd" => parse_vcd(&input, signals),
+        _ => fail(USAGE),
+    };
+    result.unwrap_or_else(|e| fail(&format!("{}: {}", path, e)))
+}
+
+fn hex(value: &Option<Vec<u8>>) -> String {
+    match *value {
+        Some(ref value) => value.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => "?".to_string(),
+    }
+}
+
+fn main() {
+    let mut format = None;
+    let mut signals = VcdSignals::default();
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => format = Some(args.next().unwrap_or_else(|| fail(USAGE))),
+            "--signals" => {
+                let names = args.next().unwrap_or_else(|| fail(USAGE));
+                let names: Vec<&str> = names.split(',').collect();
+                if names.len() != 4 {
+                    fail(USAGE);
+                }
+                signals = VcdSignals {
+                    csn: names[0].to_string(),
+                    sck: names[1].to_string(),
+                    mosi: names[2].to_string(),
+                    miso: names[3].to_string(),
+                };
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let format = format.as_deref();
+
+    match paths.len() {
+        1 => {
+            let mut decoder = Decoder::new();
+            for (i, transaction) in load(&paths[0], format, &signals).iter().enumerate() {
+                let (command, change) = decoder.feed(transaction);
+                let status = transaction.miso.first()
+                    .map(|status| format!("{:02x}", status))
+                    .unwrap_or_default();
+                println!("{:5}  [{}] {}", i, status, command);
+                if let Some(change) = change {
+                    println!("       {}", change);
+                }
+            }
+        }
+        2 => {
+            let mut ours = Decoder::new();
+            for transaction in load(&paths[0], format, &signals) {
+                ours.feed(&transaction);
+            }
+            let mut theirs = Decoder::new();
+            for transaction in load(&paths[1], format, &signals) {
+                theirs.feed(&transaction);
+            }
+
+            let diff = ours.diff(&theirs);
+            if diff.is_empty() {
+                println!("No differences in configuration registers");
+            }
+            for register in diff {
+                println!("{}: {} | {}", register_name(register.addr),
+                         hex(&register.ours), hex(&register.theirs));
+                for value in [register.ours, register.theirs].iter() {
+                    if let Some(ref value) = *value {
+                        println!("    {}", describe_register(register.addr, value));
+                    }
+                }
+            }
+        }
+        _ => fail(USAGE),
+    }
+}
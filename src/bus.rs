@@ -0,0 +1,74 @@
+use core::cell::RefCell;
+use core::fmt::Debug;
+use embedded_hal::blocking::spi::Transfer as SpiTransfer;
+
+/// Access to an SPI bus, possibly shared with other devices
+///
+/// The driver locks the bus for no longer than one `CSN`-framed
+/// transaction. Any `embedded_hal` SPI peripheral can be passed
+/// directly for exclusive use. To share one, wrap it in a
+/// `RefCell` and pass `RefCellSpi` handles, or use
+/// `CriticalSectionSpi` with the `critical-section` feature if the
+/// bus is also used from interrupt handlers.
+pub trait SpiBus {
+    type Error: Debug;
+    type Spi: SpiTransfer<u8, Error = Self::Error>;
+
+    /// Run `f` with exclusive access to the bus
+    fn lock<R, F: FnOnce(&mut Self::Spi) -> R>(&mut self, f: F) -> R;
+}
+
+impl<SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debug> SpiBus for SPI {
+    type Error = SPIE;
+    type Spi = SPI;
+
+    fn lock<R, F: FnOnce(&mut SPI) -> R>(&mut self, f: F) -> R {
+        f(self)
+    }
+}
+
+/// Shares an SPI bus among devices used from the same context
+pub struct RefCellSpi<'a, SPI: 'a> {
+    bus: &'a RefCell<SPI>,
+}
+
+impl<'a, SPI> RefCellSpi<'a, SPI> {
+    pub fn new(bus: &'a RefCell<SPI>) -> Self {
+        RefCellSpi { bus }
+    }
+}
+
+impl<'a, SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debug> SpiBus for RefCellSpi<'a, SPI> {
+    type Error = SPIE;
+    type Spi = SPI;
+
+    /// Panics if the bus is already borrowed
+    fn lock<R, F: FnOnce(&mut SPI) -> R>(&mut self, f: F) -> R {
+        f(&mut self.bus.borrow_mut())
+    }
+}
+
+/// Shares an SPI bus among devices used from different interrupt
+/// priorities by locking it in a critical section
+#[cfg(feature = "critical-section")]
+pub struct CriticalSectionSpi<'a, SPI: 'a> {
+    bus: &'a ::critical_section::Mutex<RefCell<SPI>>,
+}
+
+#[cfg(feature = "critical-section")]
+impl<'a, SPI> CriticalSectionSpi<'a, SPI> {
+    pub fn new(bus: &'a ::critical_section::Mutex<RefCell<SPI>>) -> Self {
+        CriticalSectionSpi { bus }
+    }
+}
+
+#[cfg(feature = "critical-section")]
+impl<'a, SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debug> SpiBus for CriticalSectionSpi<'a, SPI> {
+    type Error = SPIE;
+    type Spi = SPI;
+
+    fn lock<R, F: FnOnce(&mut SPI) -> R>(&mut self, f: F) -> R {
+        let bus = self.bus;
+        ::critical_section::with(|cs| f(&mut bus.borrow_ref_mut(cs)))
+    }
+}
@@ -0,0 +1,9 @@
+/// Monotonic time source, to be implemented by the user for their
+/// timer peripheral
+///
+/// The counter may wrap around as only differences between two
+/// readings are evaluated.
+pub trait Clock {
+    /// Current time in microseconds
+    fn now_us(&mut self) -> u32;
+}
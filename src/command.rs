@@ -46,6 +46,10 @@ impl<R: Register> WriteRegister<R> {
     pub fn new(register: R) -> Self {
         WriteRegister { register }
     }
+
+    pub fn register(&self) -> &R {
+        &self.register
+    }
 }
 
 impl<R: Register> Command for WriteRegister<R> {
@@ -111,6 +115,55 @@ impl<'a> Command for WriteTxPayload<'a> {
     fn decode_response(_: &[u8]) -> Self::Response {}
 }
 
+pub struct WriteTxPayloadNoAck<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> WriteTxPayloadNoAck<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        WriteTxPayloadNoAck { data }
+    }
+}
+
+impl<'a> Command for WriteTxPayloadNoAck<'a> {
+    fn len(&self) -> usize {
+        1 + self.data.len()
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = 0b1011_0000;
+        buf[1..].copy_from_slice(self.data);
+    }
+
+    type Response = ();
+    fn decode_response(_: &[u8]) -> Self::Response {}
+}
+
+pub struct WriteAckPayload<'a> {
+    pipe: u8,
+    data: &'a [u8]
+}
+
+impl<'a> WriteAckPayload<'a> {
+    pub fn new(pipe: u8, data: &'a [u8]) -> Self {
+        WriteAckPayload { pipe, data }
+    }
+}
+
+impl<'a> Command for WriteAckPayload<'a> {
+    fn len(&self) -> usize {
+        1 + self.data.len()
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = 0b1010_1000 | (self.pipe & 0b111);
+        buf[1..].copy_from_slice(self.data);
+    }
+
+    type Response = ();
+    fn decode_response(_: &[u8]) -> Self::Response {}
+}
+
 pub struct ReadRxPayloadWidth;
 
 impl Command for ReadRxPayloadWidth {
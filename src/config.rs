@@ -168,6 +168,29 @@ pub trait Configuration {
         Ok(())
     }
 
+    /// Send payloads written with `RxMode::write_ack_payload()` along
+    /// with acks. Both ends need this and dynamic payload lengths on
+    /// the pipes concerned, see `set_pipes_rx_lengths()`.
+    fn set_ack_payloads(&mut self, enable: bool) -> Result<(), <<Self as Configuration>::Inner as Device>::Error> {
+        self.device()
+            .update_register::<Feature, _, _>(|feature| {
+                feature.set_en_ack_pay(enable);
+                if enable {
+                    feature.set_en_dpl(true);
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Allow sending packets without ack with `TxMode::send_no_ack()`
+    fn set_dynamic_ack(&mut self, enable: bool) -> Result<(), <<Self as Configuration>::Inner as Device>::Error> {
+        self.device()
+            .update_register::<Feature, _, _>(|feature| {
+                feature.set_en_dyn_ack(enable);
+            })?;
+        Ok(())
+    }
+
     fn get_address_width(&mut self) -> Result<u8, <<Self as Configuration>::Inner as Device>::Error> {
         let (_, register) =
             self.device()
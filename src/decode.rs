@@ -0,0 +1,700 @@
+//! Offline decoder for captured SPI traffic to the nRF24L01
+//!
+//! Available with the `std` feature. Reads CSN-framed transactions
+//! from logic analyzer exports (CSV or VCD) or from a raw list of
+//! MOSI/MISO byte pairs, decodes the commands and keeps track of
+//! register values so that changes can be annotated.
+//!
+//! The `nrf24-decode` binary wraps this module.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use registers::{
+    Register, Config, EnAa, EnRxaddr, SetupAw, SetupRetr, RfCh, RfSetup, Status, ObserveTx,
+    RxPwP0, RxPwP1, RxPwP2, RxPwP3, RxPwP4, RxPwP5, FifoStatus, Dynpd, Feature,
+    is_volatile,
+};
+
+/// One CSN-framed SPI transaction
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Transaction {
+    pub mosi: Vec<u8>,
+    pub miso: Vec<u8>,
+}
+
+/// Failure to read a capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// 1-based line number in the input
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new<S: ToString>(line: usize, message: S) -> Self {
+        ParseError {
+            line,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for ParseError {}
+
+fn parse_byte(s: &str, line: usize) -> Result<u8, ParseError> {
+    let s = s.trim().trim_matches('"');
+    let hex = s.trim_start_matches("0x").trim_start_matches("0X");
+    u8::from_str_radix(hex, 16)
+        .map_err(|_| ParseError::new(line, format!("invalid byte {:?}", s)))
+}
+
+/// Parse one transaction per line, written as whitespace-separated
+/// `MOSI/MISO` hex byte pairs:
+///
+/// ```text
+/// # W_REGISTER CONFIG
+/// 20/0e 0e/00
+/// ```
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_raw(input: &str) -> Result<Vec<Transaction>, ParseError> {
+    let mut transactions = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut transaction = Transaction::default();
+        for pair in line.split_whitespace() {
+            let mut bytes = pair.splitn(2, '/');
+            let mosi = bytes.next().unwrap_or("");
+            let miso = bytes.next()
+                .ok_or_else(|| ParseError::new(i + 1, format!("expected MOSI/MISO, got {:?}", pair)))?;
+            transaction.mosi.push(parse_byte(mosi, i + 1)?);
+            transaction.miso.push(parse_byte(miso, i + 1)?);
+        }
+        transactions.push(transaction);
+    }
+    Ok(transactions)
+}
+
+/// Parse the CSV export of a logic analyzer's SPI protocol decoder
+///
+/// The header must name a `MOSI` and a `MISO` column. Transactions
+/// are framed either by a `Packet ID` column (Saleae Logic 1) or by
+/// `enable`/`disable` rows in a `type` column (Saleae Logic 2).
+pub fn parse_csv(input: &str) -> Result<Vec<Transaction>, ParseError> {
+    let mut lines = input.lines().enumerate()
+        .filter(|&(_, line)| ! line.trim().is_empty());
+    let header: Vec<String> = match lines.next() {
+        Some((_, header)) => header.split(',')
+            .map(|column| column.trim().trim_matches('"').to_lowercase())
+            .collect(),
+        None => return Ok(Vec::new()),
+    };
+    let find = |name: &str| header.iter().position(|column| column.contains(name));
+    let mosi_col = find("mosi")
+        .ok_or_else(|| ParseError::new(1, "no MOSI column"))?;
+    let miso_col = find("miso")
+        .ok_or_else(|| ParseError::new(1, "no MISO column"))?;
+    let packet_col = find("packet");
+    let type_col = header.iter().position(|column| column == "type");
+
+    let mut transactions = Vec::new();
+    let mut current = Transaction::default();
+    let mut current_packet = None;
+    for (i, line) in lines {
+        let fields: Vec<&str> = line.split(',')
+            .map(|field| field.trim().trim_matches('"'))
+            .collect();
+        let field = |col: usize| fields.get(col).cloned().unwrap_or("");
+
+        if let Some(col) = type_col {
+            match field(col) {
+                "enable" | "disable" => {
+                    if ! current.mosi.is_empty() {
+                        transactions.push(current);
+                        current = Transaction::default();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        if let Some(col) = packet_col {
+            let packet = Some(field(col).to_string());
+            if packet != current_packet && ! current.mosi.is_empty() {
+                transactions.push(current);
+                current = Transaction::default();
+            }
+            current_packet = packet;
+        }
+
+        let (mosi, miso) = (field(mosi_col), field(miso_col));
+        if mosi.is_empty() && miso.is_empty() {
+            continue;
+        }
+        current.mosi.push(parse_byte(mosi, i + 1)?);
+        current.miso.push(parse_byte(miso, i + 1)?);
+    }
+    if ! current.mosi.is_empty() {
+        transactions.push(current);
+    }
+    Ok(transactions)
+}
+
+/// Names of the wires in a VCD file
+#[derive(Debug, Clone, PartialEq)]
+pub struct VcdSignals {
+    pub csn: String,
+    pub sck: String,
+    pub mosi: String,
+    pub miso: String,
+}
+
+impl Default for VcdSignals {
+    fn default() -> Self {
+        VcdSignals {
+            csn: "csn".to_string(),
+            sck: "sck".to_string(),
+            mosi: "mosi".to_string(),
+            miso: "miso".to_string(),
+        }
+    }
+}
+
+/// Sample the SPI wires from a VCD dump
+///
+/// Bits are sampled MSB first on the rising edge of `SCK` while `CSN`
+/// is low, as in `setup::spi_mode()`.
+pub fn parse_vcd(input: &str, signals: &VcdSignals) -> Result<Vec<Transaction>, ParseError> {
+    const CSN: usize = 0;
+    const SCK: usize = 1;
+    const MOSI: usize = 2;
+    const MISO: usize = 3;
+    let names = [&signals.csn, &signals.sck, &signals.mosi, &signals.miso];
+
+    // Identifier code of each wire
+    let mut ids: [Option<String>; 4] = [None, None, None, None];
+    // Line number and token, for error reporting
+    let mut tokens = input.lines().enumerate()
+        .flat_map(|(i, line)| line.split_whitespace().map(move |token| (i + 1, token)));
+
+    // Header
+    let mut definitions_done = false;
+    while let Some((_, token)) = tokens.next() {
+        match token {
+            "$var" => {
+                let var: Vec<&str> = tokens.by_ref()
+                    .map(|(_, token)| token)
+                    .take_while(|&token| token != "$end")
+                    .collect();
+                // $var <type> <width> <id> <name> [range] $end
+                if var.len() >= 4 {
+                    for (n, name) in names.iter().enumerate() {
+                        if var[3].eq_ignore_ascii_case(name) {
+                            ids[n] = Some(var[2].to_string());
+                        }
+                    }
+                }
+            }
+            "$enddefinitions" => {
+                definitions_done = true;
+                break;
+            }
+            _ => {}
+        }
+    }
+    if ! definitions_done {
+        return Err(ParseError::new(input.lines().count(), "no $enddefinitions"));
+    }
+    for (n, id) in ids.iter().enumerate() {
+        if id.is_none() {
+            return Err(ParseError::new(1, format!("no wire named {:?}", names[n])));
+        }
+    }
+
+    let mut state = [true, false, false, false];
+    let mut prev = state;
+    let mut transactions = Vec::new();
+    let mut current = Transaction::default();
+    let mut bits = 0;
+    let mut mosi_byte = 0u8;
+    let mut miso_byte = 0u8;
+
+    let mut evaluate = |prev: &[bool; 4], state: &[bool; 4]| {
+        if prev[CSN] && ! state[CSN] {
+            // CSN falling: transaction begins
+            current = Transaction::default();
+            bits = 0;
+        } else if ! prev[CSN] && state[CSN] {
+            // CSN rising: transaction ends
+            if ! current.mosi.is_empty() {
+                transactions.push(current.clone());
+            }
+        } else if ! state[CSN] && ! prev[SCK] && state[SCK] {
+            mosi_byte = (mosi_byte << 1) | state[MOSI] as u8;
+            miso_byte = (miso_byte << 1) | state[MISO] as u8;
+            bits += 1;
+            if bits == 8 {
+                current.mosi.push(mosi_byte);
+                current.miso.push(miso_byte);
+                bits = 0;
+            }
+        }
+    };
+
+    while let Some((line, token)) = tokens.next() {
+        if token.starts_with('#') {
+            // New timestamp: act on all changes of the previous one
+            evaluate(&prev, &state);
+            prev = state;
+            continue;
+        }
+        if token.starts_with('$') {
+            // $dumpvars, $end and similar
+            continue;
+        }
+
+        let (value, id) = match token.as_bytes()[0] {
+            // Vector or real value, followed by the identifier
+            b'b' | b'B' | b'r' | b'R' => match tokens.next() {
+                // The SPI wires are single bits, a vector has them last
+                Some((_, id)) => (&token[token.len() - 1..], id),
+                None => return Err(ParseError::new(line, format!("no identifier after {:?}", token))),
+            },
+            _ => token.split_at(1),
+        };
+        let n = match ids.iter().position(|wire| wire.as_ref().map(|wire| wire == id).unwrap_or(false)) {
+            Some(n) => n,
+            // Another signal
+            None => continue,
+        };
+        state[n] = match value {
+            "0" => false,
+            "1" => true,
+            // x, z: leave as is
+            "x" | "X" | "z" | "Z" => continue,
+            _ => return Err(ParseError::new(line, format!("unsupported value change {:?}", token))),
+        };
+    }
+    evaluate(&prev, &state);
+
+    Ok(transactions)
+}
+
+/// A transaction, decoded into the commands this crate sends
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodedCommand {
+    ReadRegister { addr: u8, value: Vec<u8> },
+    WriteRegister { addr: u8, value: Vec<u8> },
+    ReadRxPayload(Vec<u8>),
+    WriteTxPayload(Vec<u8>),
+    WriteTxPayloadNoack(Vec<u8>),
+    WriteAckPayload { pipe: u8, data: Vec<u8> },
+    ReadRxPayloadWidth(u8),
+    FlushTx,
+    FlushRx,
+    ReuseTxPl,
+    Activate(u8),
+    Nop,
+    Unknown(Vec<u8>),
+}
+
+impl DecodedCommand {
+    pub fn decode(transaction: &Transaction) -> Self {
+        let mosi = &transaction.mosi;
+        let miso = &transaction.miso;
+        let opcode = match mosi.first() {
+            Some(&opcode) => opcode,
+            None => return DecodedCommand::Unknown(Vec::new()),
+        };
+        let mosi_data = mosi[1..].to_vec();
+        let miso_data = miso.get(1..).unwrap_or(&[]).to_vec();
+
+        match opcode {
+            0b0000_0000..=0b0001_1111 =>
+                DecodedCommand::ReadRegister { addr: opcode, value: miso_data },
+            0b0010_0000..=0b0011_1111 =>
+                DecodedCommand::WriteRegister { addr: opcode & 0b1_1111, value: mosi_data },
+            0b0110_0001 => DecodedCommand::ReadRxPayload(miso_data),
+            0b1010_0000 => DecodedCommand::WriteTxPayload(mosi_data),
+            0b1011_0000 => DecodedCommand::WriteTxPayloadNoack(mosi_data),
+            0b1010_1000..=0b1010_1101 =>
+                DecodedCommand::WriteAckPayload { pipe: opcode & 0b111, data: mosi_data },
+            0b0110_0000 =>
+                DecodedCommand::ReadRxPayloadWidth(miso_data.first().cloned().unwrap_or(0)),
+            0b1110_0001 => DecodedCommand::FlushTx,
+            0b1110_0010 => DecodedCommand::FlushRx,
+            0b1110_0011 => DecodedCommand::ReuseTxPl,
+            0b0101_0000 => DecodedCommand::Activate(mosi_data.first().cloned().unwrap_or(0)),
+            0b1111_1111 => DecodedCommand::Nop,
+            _ => DecodedCommand::Unknown(mosi.clone()),
+        }
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl<'a> fmt::Display for Hex<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DecodedCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodedCommand::ReadRegister { addr, ref value } =>
+                write!(f, "R_REGISTER {} = {}", register_name(addr), Hex(value)),
+            DecodedCommand::WriteRegister { addr, ref value } =>
+                write!(f, "W_REGISTER {} = {}", register_name(addr), Hex(value)),
+            DecodedCommand::ReadRxPayload(ref data) =>
+                write!(f, "R_RX_PAYLOAD [{}] {}", data.len(), Hex(data)),
+            DecodedCommand::WriteTxPayload(ref data) =>
+                write!(f, "W_TX_PAYLOAD [{}] {}", data.len(), Hex(data)),
+            DecodedCommand::WriteTxPayloadNoack(ref data) =>
+                write!(f, "W_TX_PAYLOAD_NOACK [{}] {}", data.len(), Hex(data)),
+            DecodedCommand::WriteAckPayload { pipe, ref data } =>
+                write!(f, "W_ACK_PAYLOAD P{} [{}] {}", pipe, data.len(), Hex(data)),
+            DecodedCommand::ReadRxPayloadWidth(width) =>
+                write!(f, "R_RX_PL_WID = {}", width),
+            DecodedCommand::FlushTx => write!(f, "FLUSH_TX"),
+            DecodedCommand::FlushRx => write!(f, "FLUSH_RX"),
+            DecodedCommand::ReuseTxPl => write!(f, "REUSE_TX_PL"),
+            DecodedCommand::Activate(data) => write!(f, "ACTIVATE {:02x}", data),
+            DecodedCommand::Nop => write!(f, "NOP"),
+            DecodedCommand::Unknown(ref data) => write!(f, "unknown {}", Hex(data)),
+        }
+    }
+}
+
+/// Datasheet name of a register
+pub fn register_name(addr: u8) -> &'static str {
+    match addr {
+        0x00 => "CONFIG",
+        0x01 => "EN_AA",
+        0x02 => "EN_RXADDR",
+        0x03 => "SETUP_AW",
+        0x04 => "SETUP_RETR",
+        0x05 => "RF_CH",
+        0x06 => "RF_SETUP",
+        0x07 => "STATUS",
+        0x08 => "OBSERVE_TX",
+        0x09 => "RPD",
+        0x0A => "RX_ADDR_P0",
+        0x0B => "RX_ADDR_P1",
+        0x0C => "RX_ADDR_P2",
+        0x0D => "RX_ADDR_P3",
+        0x0E => "RX_ADDR_P4",
+        0x0F => "RX_ADDR_P5",
+        0x10 => "TX_ADDR",
+        0x11 => "RX_PW_P0",
+        0x12 => "RX_PW_P1",
+        0x13 => "RX_PW_P2",
+        0x14 => "RX_PW_P3",
+        0x15 => "RX_PW_P4",
+        0x16 => "RX_PW_P5",
+        0x17 => "FIFO_STATUS",
+        0x1C => "DYNPD",
+        0x1D => "FEATURE",
+        _ => "reserved",
+    }
+}
+
+/// Register value with its fields decoded, using the definitions
+/// from the driver
+pub fn describe_register(addr: u8, value: &[u8]) -> String {
+    macro_rules! fields {
+        ($name: ident) => (
+            format!("{:?}", $name::decode(&value[0..1]))
+        )
+    }
+    macro_rules! pipes {
+        ($name: ident) => (
+            format!("{:?}", $name::decode(&value[0..1]).to_bools())
+        )
+    }
+
+    if value.is_empty() {
+        return String::new();
+    }
+    match addr {
+        0x00 => fields!(Config),
+        0x01 => pipes!(EnAa),
+        0x02 => pipes!(EnRxaddr),
+        0x03 => fields!(SetupAw),
+        0x04 => fields!(SetupRetr),
+        0x05 => fields!(RfCh),
+        0x06 => fields!(RfSetup),
+        0x07 => fields!(Status),
+        0x08 => fields!(ObserveTx),
+        0x11 => fields!(RxPwP0),
+        0x12 => fields!(RxPwP1),
+        0x13 => fields!(RxPwP2),
+        0x14 => fields!(RxPwP3),
+        0x15 => fields!(RxPwP4),
+        0x16 => fields!(RxPwP5),
+        0x17 => fields!(FifoStatus),
+        0x1C => pipes!(Dynpd),
+        0x1D => fields!(Feature),
+        _ => Hex(value).to_string(),
+    }
+}
+
+/// Change of a known register value
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterChange {
+    pub addr: u8,
+    /// `None` if not seen before
+    pub old: Option<Vec<u8>>,
+    pub new: Vec<u8>,
+}
+
+impl fmt::Display for RegisterChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: ", register_name(self.addr))?;
+        match self.old {
+            Some(ref old) => write!(f, "{} -> ", Hex(old))?,
+            None => write!(f, "? -> ")?,
+        }
+        write!(f, "{}  {}", Hex(&self.new), describe_register(self.addr, &self.new))
+    }
+}
+
+/// Register configured differently in two captures
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterDiff {
+    pub addr: u8,
+    /// `None` if not seen
+    pub ours: Option<Vec<u8>>,
+    /// `None` if not seen
+    pub theirs: Option<Vec<u8>>,
+}
+
+/// Decodes transactions in order, tracking the configuration
+/// registers as they are read and written
+#[derive(Debug, Clone, Default)]
+pub struct Decoder {
+    registers: BTreeMap<u8, Vec<u8>>,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    /// Decode the next transaction, returning the command and the
+    /// register change it revealed
+    pub fn feed(&mut self, transaction: &Transaction) -> (DecodedCommand, Option<RegisterChange>) {
+        let command = DecodedCommand::decode(transaction);
+        let change = match command {
+            DecodedCommand::ReadRegister { addr, ref value } |
+            DecodedCommand::WriteRegister { addr, ref value }
+                if ! is_volatile(addr) && ! value.is_empty() =>
+                self.update(addr, value),
+            _ => None,
+        };
+        (command, change)
+    }
+
+    fn update(&mut self, addr: u8, value: &[u8]) -> Option<RegisterChange> {
+        let old = self.registers.insert(addr, value.to_vec());
+        if old.as_ref().map(|old| &old[..]) == Some(value) {
+            None
+        } else {
+            Some(RegisterChange {
+                addr,
+                old,
+                new: value.to_vec(),
+            })
+        }
+    }
+
+    /// Last known value of every configuration register seen
+    pub fn registers(&self) -> &BTreeMap<u8, Vec<u8>> {
+        &self.registers
+    }
+
+    /// Registers whose last known values differ from `other`
+    pub fn diff(&self, other: &Decoder) -> Vec<RegisterDiff> {
+        let mut addrs: Vec<u8> = self.registers.keys()
+            .chain(other.registers.keys())
+            .cloned()
+            .collect();
+        addrs.sort();
+        addrs.dedup();
+        addrs.into_iter()
+            .filter_map(|addr| {
+                let ours = self.registers.get(&addr);
+                let theirs = other.registers.get(&addr);
+                if ours != theirs {
+                    Some(RegisterDiff {
+                        addr,
+                        ours: ours.cloned(),
+                        theirs: theirs.cloned(),
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(mosi: &[u8], miso: &[u8]) -> Transaction {
+        Transaction {
+            mosi: mosi.to_vec(),
+            miso: miso.to_vec(),
+        }
+    }
+
+    /// VCD dump of one transaction, with a vector and a real signal
+    /// changing in between
+    fn vcd(mosi: &[u8], miso: &[u8]) -> String {
+        let mut vcd = String::from(
+            "$timescale 1ns $end\n\
+             $scope module top $end\n\
+             $var wire 1 ! csn $end\n\
+             $var wire 1 \" sck $end\n\
+             $var wire 1 # MOSI $end\n\
+             $var wire 1 $ miso $end\n\
+             $var wire 8 % data [7:0] $end\n\
+             $var real 64 & temp $end\n\
+             $upscope $end\n\
+             $enddefinitions $end\n\
+             #0\n$dumpvars\n1!\n0\"\n0#\n0$\nb0 %\nr0.0 &\n$end\n\
+             #1\n0!\n",
+        );
+        let mut time = 2;
+        for (&mosi, &miso) in mosi.iter().zip(miso.iter()) {
+            for bit in (0..8).rev() {
+                vcd += &format!("#{}\n0\"\n{}#\nb{}{} $\nb{:08b} %\n", time, mosi >> bit & 1, miso >> bit & 1, miso >> bit & 1, mosi);
+                vcd += &format!("#{}\n1\"\nr{}.5 &\n", time + 1, bit);
+                time += 2;
+            }
+        }
+        vcd + &format!("#{}\n0\"\n#{}\n1!\n", time, time + 1)
+    }
+
+    #[test]
+    fn raw() {
+        let input = "# W_REGISTER CONFIG\n20/0e 0E/00\n\n  ff/0x0e\n";
+        assert_eq!(parse_raw(input), Ok(vec![
+            transaction(&[0x20, 0x0E], &[0x0E, 0x00]),
+            transaction(&[0xFF], &[0x0E]),
+        ]));
+        assert_eq!(parse_raw("20/0e 0e").unwrap_err().line, 1);
+        assert_eq!(parse_raw("\n20/0g").unwrap_err().line, 2);
+    }
+
+    #[test]
+    fn csv_packet_ids() {
+        let input = "Time [s],Packet ID,MOSI,MISO\n\
+                     0.1,0,0x20,0x0E\n\
+                     0.2,0,0x0E,0x00\n\
+                     0.3,1,0xFF,0x0E\n";
+        assert_eq!(parse_csv(input), Ok(vec![
+            transaction(&[0x20, 0x0E], &[0x0E, 0x00]),
+            transaction(&[0xFF], &[0x0E]),
+        ]));
+    }
+
+    #[test]
+    fn csv_enable_rows() {
+        let input = "\"name\",\"type\",\"start_time\",\"mosi\",\"miso\"\n\
+                     \"SPI\",\"enable\",0.1,,\n\
+                     \"SPI\",\"result\",0.2,0x61,0x40\n\
+                     \"SPI\",\"result\",0.3,0x00,0x2A\n\
+                     \"SPI\",\"disable\",0.4,,\n\
+                     \"SPI\",\"enable\",0.5,,\n\
+                     \"SPI\",\"result\",0.6,0xE2,0x0E\n";
+        assert_eq!(parse_csv(input), Ok(vec![
+            transaction(&[0x61, 0x00], &[0x40, 0x2A]),
+            transaction(&[0xE2], &[0x0E]),
+        ]));
+        assert!(parse_csv("a,b\n1,2\n").is_err());
+        assert_eq!(parse_csv(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn vcd_samples_on_rising_sck() {
+        let input = vcd(&[0x20, 0xA5], &[0x0E, 0x5A]);
+        assert_eq!(parse_vcd(&input, &VcdSignals::default()), Ok(vec![
+            transaction(&[0x20, 0xA5], &[0x0E, 0x5A]),
+        ]));
+
+        let signals = VcdSignals {
+            csn: "cs".to_string(),
+            ..VcdSignals::default()
+        };
+        assert!(parse_vcd(&input, &signals).is_err());
+        assert!(parse_vcd("$var wire 1 ! csn $end", &VcdSignals::default()).is_err());
+    }
+
+    #[test]
+    fn decodes_commands() {
+        let decode = |mosi: &[u8], miso: &[u8]| DecodedCommand::decode(&transaction(mosi, miso));
+        assert_eq!(decode(&[0x05, 0xFF], &[0x0E, 0x4C]), DecodedCommand::ReadRegister { addr: 0x05, value: vec![0x4C] });
+        assert_eq!(decode(&[0x2A, 1, 2, 3], &[0x0E, 0, 0, 0]), DecodedCommand::WriteRegister { addr: 0x0A, value: vec![1, 2, 3] });
+        assert_eq!(decode(&[0xA9, 7], &[0x0E, 0]), DecodedCommand::WriteAckPayload { pipe: 1, data: vec![7] });
+        assert_eq!(decode(&[0x60, 0xFF], &[0x40, 4]), DecodedCommand::ReadRxPayloadWidth(4));
+        assert_eq!(decode(&[0xFF], &[0x0E]), DecodedCommand::Nop);
+        assert_eq!(decode(&[0xA0, 1, 2], &[0x0E, 0, 0]).to_string(), "W_TX_PAYLOAD [2] 01 02");
+        assert_eq!(decode(&[0x05, 0xFF], &[0x0E, 0x4C]).to_string(), "R_REGISTER RF_CH = 4c");
+    }
+
+    #[test]
+    fn tracks_register_changes() {
+        let mut decoder = Decoder::new();
+        let (_, change) = decoder.feed(&transaction(&[0x25, 0x4C], &[0x0E, 0x00]));
+        assert_eq!(change, Some(RegisterChange { addr: 0x05, old: None, new: vec![0x4C] }));
+        // Read back unchanged
+        let (_, change) = decoder.feed(&transaction(&[0x05, 0xFF], &[0x0E, 0x4C]));
+        assert_eq!(change, None);
+        let (_, change) = decoder.feed(&transaction(&[0x25, 0x10], &[0x0E, 0x00]));
+        assert_eq!(change, Some(RegisterChange { addr: 0x05, old: Some(vec![0x4C]), new: vec![0x10] }));
+        // STATUS is not configuration
+        let (_, change) = decoder.feed(&transaction(&[0x27, 0x70], &[0x0E, 0x00]));
+        assert_eq!(change, None);
+        assert_eq!(decoder.registers().keys().cloned().collect::<Vec<_>>(), vec![0x05]);
+    }
+
+    #[test]
+    fn diff() {
+        let mut ours = Decoder::new();
+        ours.feed(&transaction(&[0x25, 0x4C], &[0x0E, 0x00]));
+        ours.feed(&transaction(&[0x26, 0x0E], &[0x0E, 0x00]));
+        let mut theirs = Decoder::new();
+        theirs.feed(&transaction(&[0x25, 0x4C], &[0x0E, 0x00]));
+        theirs.feed(&transaction(&[0x26, 0x06], &[0x0E, 0x00]));
+        theirs.feed(&transaction(&[0x3C, 0x3F], &[0x0E, 0x00]));
+
+        assert_eq!(ours.diff(&theirs), vec![
+            RegisterDiff { addr: 0x06, ours: Some(vec![0x0E]), theirs: Some(vec![0x06]) },
+            RegisterDiff { addr: 0x1C, ours: None, theirs: Some(vec![0x3F]) },
+        ]);
+        assert!(ours.diff(&ours.clone()).is_empty());
+    }
+}
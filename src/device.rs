@@ -1,10 +1,11 @@
-use command::Command;
+use command::{Command, ReadRxPayload};
 use registers::{Register, Config, Status};
+use error::ModeError;
 
 /// Trait that hides all the GPIO/SPI type parameters for use by the
 /// operation modes
 pub trait Device {
-    type Error;
+    type Error: ModeError;
 
     fn ce_enable(&mut self);
     fn ce_disable(&mut self);
@@ -21,7 +22,20 @@ pub trait Device {
     }
 
     fn send_command<C: Command>(&mut self, command: &C) -> Result<(Status, C::Response), Self::Error>;
+    /// Read the next RX payload straight into `buf`, which must not
+    /// be longer than that payload
+    ///
+    /// By default, it is read with `send_command()` and copied.
+    fn read_rx_payload_into(&mut self, buf: &mut [u8]) -> Result<Status, Self::Error> {
+        let (status, payload) =
+            self.send_command(&ReadRxPayload::new(buf.len()))?;
+        buf.copy_from_slice(&payload);
+        Ok(status)
+    }
     fn write_register<R: Register>(&mut self, register: R) -> Result<Status, Self::Error>;
+    /// Read a register, along with the status. An implementation
+    /// that serves the register from a cache may return the status of
+    /// an earlier transaction.
     fn read_register<R: Register>(&mut self) -> Result<(Status, R), Self::Error>;
 
     fn update_register<Reg, F, R>(&mut self, f: F) -> Result<R, Self::Error>
@@ -0,0 +1,203 @@
+use core::mem::replace;
+use device::Device;
+use rx::RxMode;
+use payload::Payload;
+use PIPES_COUNT;
+
+/// What to do with a packet arriving at a full queue
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum OverflowPolicy {
+    /// Keep the queue as is, discard the new packet
+    DropNewest,
+    /// Discard the oldest queued packet to make room
+    DropOldest,
+}
+
+/// Counters kept per pipe by the `Dispatcher`
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct PipeStats {
+    /// Packets read from the RX FIFO for this pipe
+    pub received: u32,
+    /// Packets lost to the overflow policy or for lack of a route
+    pub dropped: u32,
+}
+
+/// Ring buffer of payloads in caller-provided storage
+pub struct PayloadQueue<'a> {
+    slots: &'a mut [Payload],
+    head: usize,
+    len: usize,
+}
+
+impl<'a> PayloadQueue<'a> {
+    /// The capacity is `slots.len()`. Their contents are ignored.
+    pub fn new(slots: &'a mut [Payload]) -> Self {
+        PayloadQueue {
+            slots,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == self.slots.len()
+    }
+
+    /// Enqueue `payload`, returns `false` if a packet had to be
+    /// dropped according to `policy`
+    pub fn push(&mut self, payload: Payload, policy: OverflowPolicy) -> bool {
+        if self.slots.is_empty() {
+            return false;
+        }
+
+        let mut dropped = false;
+        if self.is_full() {
+            match policy {
+                OverflowPolicy::DropNewest => return false,
+                OverflowPolicy::DropOldest => {
+                    self.pop();
+                    dropped = true;
+                }
+            }
+        }
+
+        let tail = (self.head + self.len) % self.slots.len();
+        self.slots[tail] = payload;
+        self.len += 1;
+        !dropped
+    }
+
+    /// Oldest packet, if any
+    pub fn peek(&self) -> Option<&Payload> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(&self.slots[self.head])
+        }
+    }
+
+    /// Dequeue the oldest packet
+    pub fn pop(&mut self) -> Option<Payload> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let payload = replace(&mut self.slots[self.head], Payload::new(&[]));
+        self.head = (self.head + 1) % self.slots.len();
+        self.len -= 1;
+        Some(payload)
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+/// Where the `Dispatcher` delivers packets of a pipe
+pub enum Route<'a> {
+    /// Called for every packet right away
+    Handler(&'a mut dyn FnMut(Payload)),
+    /// Collected for later processing
+    Queue(PayloadQueue<'a>, OverflowPolicy),
+}
+
+/// Routes received packets by pipe number to a handler or a queue
+/// each, and keeps `PipeStats`
+pub struct Dispatcher<'a> {
+    routes: [Option<Route<'a>>; PIPES_COUNT],
+    stats: [PipeStats; PIPES_COUNT],
+}
+
+impl<'a> Default for Dispatcher<'a> {
+    fn default() -> Self {
+        Dispatcher::new()
+    }
+}
+
+impl<'a> Dispatcher<'a> {
+    /// Without any routes, all packets are dropped
+    pub fn new() -> Self {
+        Dispatcher {
+            routes: [None, None, None, None, None, None],
+            stats: [PipeStats::default(); PIPES_COUNT],
+        }
+    }
+
+    /// Ignored for pipe numbers from `PIPES_COUNT`
+    pub fn set_route(&mut self, pipe_no: usize, route: Option<Route<'a>>) {
+        if let Some(slot) = self.routes.get_mut(pipe_no) {
+            *slot = route;
+        }
+    }
+
+    pub fn set_handler(&mut self, pipe_no: usize, handler: &'a mut dyn FnMut(Payload)) {
+        self.set_route(pipe_no, Some(Route::Handler(handler)));
+    }
+
+    pub fn set_queue(&mut self, pipe_no: usize, queue: PayloadQueue<'a>, policy: OverflowPolicy) {
+        self.set_route(pipe_no, Some(Route::Queue(queue, policy)));
+    }
+
+    /// The queue of `pipe_no`, if one has been set
+    pub fn queue(&mut self, pipe_no: usize) -> Option<&mut PayloadQueue<'a>> {
+        match self.routes.get_mut(pipe_no) {
+            Some(&mut Some(Route::Queue(ref mut queue, _))) => Some(queue),
+            _ => None,
+        }
+    }
+
+    pub fn stats(&self, pipe_no: usize) -> PipeStats {
+        self.stats.get(pipe_no).cloned().unwrap_or_default()
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = [PipeStats::default(); PIPES_COUNT];
+    }
+
+    /// Route one packet that was received on `pipe_no`
+    pub fn dispatch(&mut self, pipe_no: u8, payload: Payload) {
+        let pipe_no = pipe_no as usize;
+        if pipe_no >= PIPES_COUNT {
+            return;
+        }
+
+        let stats = &mut self.stats[pipe_no];
+        stats.received = stats.received.wrapping_add(1);
+        let delivered = match self.routes[pipe_no] {
+            Some(Route::Handler(ref mut handler)) => {
+                handler(payload);
+                true
+            }
+            Some(Route::Queue(ref mut queue, policy)) =>
+                queue.push(payload, policy),
+            None => false,
+        };
+        if ! delivered {
+            stats.dropped = stats.dropped.wrapping_add(1);
+        }
+    }
+
+    /// Read the RX FIFO until it is empty, routing each packet.
+    /// Returns the number of packets read.
+    pub fn drain<D: Device>(&mut self, rx: &mut RxMode<D>) -> Result<usize, D::Error> {
+        let mut count = 0;
+        while let Some(pipe_no) = rx.can_read()? {
+            let payload = rx.read()?;
+            self.dispatch(pipe_no, payload);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
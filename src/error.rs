@@ -3,6 +3,78 @@ use core::fmt::Debug;
 #[derive(Debug)]
 pub enum Error<SPIE: Debug> {
     SpiError(SPIE),
+    /// The chip did not finish in time
+    Timeout,
+    /// The chip reported a payload width over 32 bytes. The RX FIFO
+    /// has been flushed.
+    CorruptPayload,
+    /// A register did not read back as written, see
+    /// `NRF24L01::set_write_verify()`
+    VerifyFailed {
+        addr: u8,
+        wrote: u8,
+        read: u8,
+    },
+    /// A message did not fit into the 32 bytes of a payload. Only
+    /// with the `serde` feature.
+    MessageTooLarge,
+    /// A message could not be (de)serialized. Only with the `serde`
+    /// feature.
+    Message(CodecError),
+}
+
+/// Why a typed message could not be (de)serialized
+///
+/// Opaque so that `Error` has the same variants with and without
+/// the `serde` feature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecError {
+    #[cfg(feature = "serde")]
+    inner: ::postcard::Error,
+}
+
+#[cfg(feature = "serde")]
+impl CodecError {
+    pub fn postcard(&self) -> &::postcard::Error {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<SPIE: Debug + defmt::Format> defmt::Format for Error<SPIE> {
+    fn format(&self, f: defmt::Formatter) {
+        match *self {
+            Error::SpiError(ref e) => defmt::write!(f, "SpiError({})", e),
+            Error::Timeout => defmt::write!(f, "Timeout"),
+            Error::CorruptPayload => defmt::write!(f, "CorruptPayload"),
+            Error::VerifyFailed { addr, wrote, read } =>
+                defmt::write!(f, "VerifyFailed {{ addr: {=u8:#x}, wrote: {=u8:#x}, read: {=u8:#x} }}", addr, wrote, read),
+            Error::MessageTooLarge => defmt::write!(f, "MessageTooLarge"),
+            #[cfg(feature = "serde")]
+            Error::Message(ref e) => defmt::write!(f, "Message({})", defmt::Debug2Format(e.postcard())),
+            #[cfg(not(feature = "serde"))]
+            Error::Message(_) => defmt::write!(f, "Message"),
+        }
+    }
+}
+
+#[cfg(feature = "ufmt")]
+impl<SPIE: Debug + ufmt::uDebug> ufmt::uDebug for Error<SPIE> {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        match *self {
+            Error::SpiError(ref e) => f.debug_tuple("SpiError")?.field(e)?.finish(),
+            Error::Timeout => f.write_str("Timeout"),
+            Error::CorruptPayload => f.write_str("CorruptPayload"),
+            Error::VerifyFailed { addr, wrote, read } =>
+                f.debug_struct("VerifyFailed")?
+                    .field("addr", &addr)?
+                    .field("wrote", &wrote)?
+                    .field("read", &read)?
+                    .finish(),
+            Error::MessageTooLarge => f.write_str("MessageTooLarge"),
+            Error::Message(_) => f.write_str("Message"),
+        }
+    }
 }
 
 impl<SPIE: Debug> From<SPIE> for Error<SPIE> {
@@ -10,3 +82,39 @@ impl<SPIE: Debug> From<SPIE> for Error<SPIE> {
         Error::SpiError(e)
     }
 }
+
+/// Errors that the operation modes raise themselves, required of
+/// `Device::Error`
+pub trait ModeError {
+    /// The chip did not finish in time
+    fn timeout() -> Self;
+    /// The chip reported a payload width over 32 bytes
+    fn corrupt_payload() -> Self;
+}
+
+impl<SPIE: Debug> ModeError for Error<SPIE> {
+    fn timeout() -> Self {
+        Error::Timeout
+    }
+
+    fn corrupt_payload() -> Self {
+        Error::CorruptPayload
+    }
+}
+
+/// Errors of (de)serializing typed messages, required of
+/// `Device::Error` by `send_message()` and `read_message()`
+#[cfg(feature = "serde")]
+pub trait MessageError {
+    fn message(e: ::postcard::Error) -> Self;
+}
+
+#[cfg(feature = "serde")]
+impl<SPIE: Debug> MessageError for Error<SPIE> {
+    fn message(e: ::postcard::Error) -> Self {
+        match e {
+            ::postcard::Error::SerializeBufferFull => Error::MessageTooLarge,
+            e => Error::Message(CodecError { inner: e }),
+        }
+    }
+}
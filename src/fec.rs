@@ -0,0 +1,502 @@
+//! Forward error correction for links without hardware CRC
+//!
+//! With `set_crc(None)`, corrupted packets are delivered instead of
+//! dropped. A `FecCodec` protects whole packets so the receiver can
+//! repair bit errors, and optionally verifies the result with a
+//! software CRC.
+//!
+//! Packets always have the codec's `packet_len()`, so use it as the
+//! static payload width on both ends. The payload length field of
+//! dynamic payloads is not protected.
+
+use MAX_PAYLOAD_BYTES;
+
+/// CRC-16-CCITT, as used by the nRF24L01 for 2-byte CRCs
+///
+/// Start with `0xFFFF`, feed data in any number of parts.
+pub fn crc16(crc: u16, data: &[u8]) -> u16 {
+    let mut crc = crc;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            let mask = (crc >> 15).wrapping_neg();
+            crc = (crc << 1) ^ (0x1021 & mask);
+        }
+    }
+    crc
+}
+
+/// How packets are protected
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FecScheme {
+    /// Extended Hamming (8,4) code on every nibble at rate 1/2.
+    /// Corrects one bit error in every byte of the packet.
+    Hamming,
+    /// Reed-Solomon code over GF(256) with `parity` bytes. Corrects
+    /// up to `parity / 2` corrupted bytes, however many bits each.
+    ReedSolomon { parity: u8 },
+}
+
+/// Why `FecCodec::decode()` failed
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FecError {
+    /// The packet is not `packet_len()` bytes long
+    WrongLength,
+    /// Too many errors to correct
+    Uncorrectable,
+    /// The corrected packet fails the software CRC
+    CrcMismatch,
+}
+
+/// Result of `FecCodec::decode()`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Decoded<'b> {
+    pub data: &'b [u8],
+    /// Bits (Hamming) or bytes (Reed-Solomon) that were corrected
+    pub corrected: usize,
+}
+
+/// Encodes data into packets of a fixed length, see the module
+/// documentation
+///
+/// Inside the code, a packet carries a length byte, the data padded
+/// with zeroes and, if enabled, a CRC-16 of both.
+#[derive(Debug, Clone)]
+pub struct FecCodec {
+    scheme: FecScheme,
+    packet_len: usize,
+    crc: bool,
+}
+
+impl FecCodec {
+    /// Packets of `packet_len` bytes, at most 32. `Hamming` needs an
+    /// even length.
+    pub fn new(scheme: FecScheme, packet_len: usize, crc: bool) -> Self {
+        assert!(packet_len <= MAX_PAYLOAD_BYTES);
+        match scheme {
+            FecScheme::Hamming => assert!(packet_len % 2 == 0),
+            FecScheme::ReedSolomon { parity } =>
+                assert!(parity > 0 && (parity as usize) < packet_len),
+        }
+        let codec = FecCodec {
+            scheme,
+            packet_len,
+            crc,
+        };
+        assert!(codec.message_len() > codec.overhead());
+        codec
+    }
+
+    pub fn scheme(&self) -> FecScheme {
+        self.scheme
+    }
+
+    /// Length of every encoded packet
+    pub fn packet_len(&self) -> usize {
+        self.packet_len
+    }
+
+    /// Bytes of data per packet
+    pub fn data_bytes(&self) -> usize {
+        self.message_len() - self.overhead()
+    }
+
+    /// Bytes protected by the code
+    fn message_len(&self) -> usize {
+        match self.scheme {
+            FecScheme::Hamming => self.packet_len / 2,
+            FecScheme::ReedSolomon { parity } => self.packet_len - parity as usize,
+        }
+    }
+
+    /// Length byte and CRC
+    fn overhead(&self) -> usize {
+        if self.crc { 3 } else { 1 }
+    }
+
+    /// Encode `data` of up to `data_bytes()` into `buf`
+    pub fn encode<'b>(&self, data: &[u8], buf: &'b mut [u8]) -> &'b [u8] {
+        assert!(data.len() <= self.data_bytes());
+        let message_len = self.message_len();
+        let mut message = [0; MAX_PAYLOAD_BYTES];
+        message[0] = data.len() as u8;
+        message[1..1 + data.len()].copy_from_slice(data);
+        if self.crc {
+            let crc = crc16(0xFFFF, &message[0..message_len - 2]);
+            message[message_len - 2] = (crc >> 8) as u8;
+            message[message_len - 1] = crc as u8;
+        }
+        let message = &message[0..message_len];
+
+        let packet = &mut buf[0..self.packet_len];
+        match self.scheme {
+            FecScheme::Hamming => {
+                for (i, byte) in message.iter().enumerate() {
+                    packet[2 * i] = HAMMING[(byte >> 4) as usize];
+                    packet[2 * i + 1] = HAMMING[(byte & 0xF) as usize];
+                }
+            }
+            FecScheme::ReedSolomon { parity } =>
+                rs_encode(message, parity as usize, packet),
+        }
+        packet
+    }
+
+    /// Correct and decode a received `packet`, using `buf` of 32
+    /// bytes as scratch space for the data
+    pub fn decode<'b>(&self, packet: &[u8], buf: &'b mut [u8]) -> Result<Decoded<'b>, FecError> {
+        if packet.len() != self.packet_len {
+            return Err(FecError::WrongLength);
+        }
+        let message_len = self.message_len();
+        let mut codeword = [0; MAX_PAYLOAD_BYTES];
+        let corrected = match self.scheme {
+            FecScheme::Hamming => {
+                let mut corrected = 0;
+                for i in 0..message_len {
+                    let (high, high_errors) = hamming_decode(packet[2 * i])?;
+                    let (low, low_errors) = hamming_decode(packet[2 * i + 1])?;
+                    codeword[i] = (high << 4) | low;
+                    corrected += high_errors + low_errors;
+                }
+                corrected
+            }
+            FecScheme::ReedSolomon { parity } => {
+                codeword[0..self.packet_len].copy_from_slice(packet);
+                rs_correct(&mut codeword[0..self.packet_len], parity as usize)?
+            }
+        };
+        let message = &codeword[0..message_len];
+
+        if self.crc {
+            let crc = crc16(0xFFFF, &message[0..message_len - 2]);
+            let expected = ((message[message_len - 2] as u16) << 8) |
+                (message[message_len - 1] as u16);
+            if crc != expected {
+                return Err(FecError::CrcMismatch);
+            }
+        }
+        let len = message[0] as usize;
+        if len > self.data_bytes() {
+            return Err(FecError::Uncorrectable);
+        }
+        buf[0..len].copy_from_slice(&message[1..1 + len]);
+        Ok(Decoded {
+            data: &buf[0..len],
+            corrected,
+        })
+    }
+}
+
+/// Codewords of every nibble. Data bits are the low nibble, then
+/// three parity bits and one bit of overall parity.
+const HAMMING: [u8; 16] = hamming_table();
+
+const fn hamming_table() -> [u8; 16] {
+    let mut table = [0; 16];
+    let mut nibble = 0;
+    while nibble < 16 {
+        let d = nibble as u8;
+        let (d1, d2, d3, d4) = (d & 1, (d >> 1) & 1, (d >> 2) & 1, (d >> 3) & 1);
+        let word = d |
+            ((d1 ^ d2 ^ d4) << 4) |
+            ((d1 ^ d3 ^ d4) << 5) |
+            ((d2 ^ d3 ^ d4) << 6);
+        table[nibble] = word | (((word.count_ones() & 1) as u8) << 7);
+        nibble += 1;
+    }
+    table
+}
+
+/// The nibble of the nearest codeword, and the number of bits
+/// corrected
+fn hamming_decode(word: u8) -> Result<(u8, usize), FecError> {
+    let mut nearest = (0, 8);
+    for (nibble, codeword) in HAMMING.iter().enumerate() {
+        let distance = (word ^ codeword).count_ones();
+        if distance < nearest.1 {
+            nearest = (nibble as u8, distance);
+        }
+    }
+    match nearest {
+        (nibble, distance) if distance <= 1 => Ok((nibble, distance as usize)),
+        // Two bit errors are detected, but ambiguous
+        _ => Err(FecError::Uncorrectable),
+    }
+}
+
+/// Powers of the primitive element 2, twice for fewer modulo
+/// operations
+const GF_EXP: [u8; 512] = gf_exp_table();
+const GF_LOG: [u8; 256] = gf_log_table();
+
+const fn gf_exp_table() -> [u8; 512] {
+    let mut exp = [0; 512];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 512 {
+        exp[i] = x as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11D;
+        }
+        i += 1;
+    }
+    exp
+}
+
+const fn gf_log_table() -> [u8; 256] {
+    let exp = gf_exp_table();
+    let mut log = [0; 256];
+    let mut i = 0;
+    while i < 255 {
+        log[exp[i] as usize] = i as u8;
+        i += 1;
+    }
+    log
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    GF_EXP[GF_LOG[a as usize] as usize + GF_LOG[b as usize] as usize]
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    GF_EXP[GF_LOG[a as usize] as usize + 255 - GF_LOG[b as usize] as usize]
+}
+
+/// 2 to the power of `exponent`
+fn gf_alpha(exponent: usize) -> u8 {
+    GF_EXP[exponent % 255]
+}
+
+/// Evaluate a polynomial with the highest coefficient first
+fn eval_high_first(poly: &[u8], x: u8) -> u8 {
+    poly.iter().fold(0, |y, coef| gf_mul(y, x) ^ coef)
+}
+
+/// Evaluate a polynomial with the lowest coefficient first
+fn eval_low_first(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |y, coef| gf_mul(y, x) ^ coef)
+}
+
+/// Append `parity` bytes to `message`, writing both to `packet`
+fn rs_encode(message: &[u8], parity: usize, packet: &mut [u8]) {
+    // Generator with roots 2^0 .. 2^(parity - 1), highest
+    // coefficient first
+    let mut generator = [0; MAX_PAYLOAD_BYTES + 1];
+    generator[0] = 1;
+    for i in 0..parity {
+        let root = gf_alpha(i);
+        for j in (1..=i + 1).rev() {
+            generator[j] ^= gf_mul(generator[j - 1], root);
+        }
+    }
+
+    // Remainder of the polynomial division
+    let k = message.len();
+    packet[0..k].copy_from_slice(message);
+    for byte in packet[k..].iter_mut() {
+        *byte = 0;
+    }
+    for i in 0..k {
+        let coef = packet[i];
+        if coef != 0 {
+            for j in 1..=parity {
+                packet[i + j] ^= gf_mul(generator[j], coef);
+            }
+        }
+    }
+    packet[0..k].copy_from_slice(message);
+}
+
+/// Correct `codeword` in place. Returns the number of corrected
+/// bytes.
+fn rs_correct(codeword: &mut [u8], parity: usize) -> Result<usize, FecError> {
+    let n = codeword.len();
+    let mut syndromes = [0; MAX_PAYLOAD_BYTES];
+    for (j, syndrome) in syndromes[0..parity].iter_mut().enumerate() {
+        *syndrome = eval_high_first(codeword, gf_alpha(j));
+    }
+    if syndromes.iter().all(|s| *s == 0) {
+        return Ok(0);
+    }
+
+    // Berlekamp-Massey for the error locator, lowest coefficient
+    // first
+    let mut locator = [0; MAX_PAYLOAD_BYTES + 1];
+    locator[0] = 1;
+    let mut previous = locator;
+    let mut errors = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1;
+    for i in 0..parity {
+        let mut discrepancy = syndromes[i];
+        for j in 1..=errors {
+            discrepancy ^= gf_mul(locator[j], syndromes[i - j]);
+        }
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+
+        let coef = gf_div(discrepancy, previous_discrepancy);
+        let last = locator;
+        for j in 0..locator.len() - shift {
+            locator[j + shift] ^= gf_mul(coef, previous[j]);
+        }
+        if 2 * errors <= i {
+            errors = i + 1 - errors;
+            previous = last;
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+    }
+    if 2 * errors > parity {
+        return Err(FecError::Uncorrectable);
+    }
+    let locator = &locator[0..=errors];
+
+    // Chien search: byte i is wrong if the locator has a root at the
+    // inverse of 2^(n - 1 - i)
+    let mut positions = [0; MAX_PAYLOAD_BYTES];
+    let mut found = 0;
+    for i in 0..n {
+        let inverse = gf_alpha(255 - (n - 1 - i) % 255);
+        if eval_low_first(locator, inverse) == 0 {
+            positions[found] = i;
+            found += 1;
+        }
+    }
+    if found != errors {
+        return Err(FecError::Uncorrectable);
+    }
+
+    // Forney: the evaluator is syndromes times locator, modulo
+    // x^parity
+    let mut evaluator = [0; MAX_PAYLOAD_BYTES];
+    for k in 0..parity {
+        for j in 0..=k.min(errors) {
+            evaluator[k] ^= gf_mul(locator[j], syndromes[k - j]);
+        }
+    }
+    for &i in &positions[0..found] {
+        let x = gf_alpha(n - 1 - i);
+        let inverse = gf_alpha(255 - (n - 1 - i) % 255);
+        // Formal derivative of the locator at the inverse
+        let mut derivative = 0;
+        let mut j = 1;
+        while j <= errors {
+            derivative ^= gf_mul(locator[j], gf_alpha(GF_LOG[inverse as usize] as usize * (j - 1)));
+            j += 2;
+        }
+        if derivative == 0 {
+            return Err(FecError::Uncorrectable);
+        }
+        let magnitude = gf_div(gf_mul(x, eval_low_first(&evaluator[0..parity], inverse)), derivative);
+        codeword[i] ^= magnitude;
+    }
+
+    for j in 0..parity {
+        if eval_high_first(codeword, gf_alpha(j)) != 0 {
+            return Err(FecError::Uncorrectable);
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"forward error correction";
+
+    /// Encode `DATA`, flip the bits of `errors` given as position and
+    /// mask, and decode. Returns the number of corrections.
+    fn transmit(codec: &FecCodec, errors: &[(usize, u8)]) -> Result<usize, FecError> {
+        let data = &DATA[0..codec.data_bytes().min(DATA.len())];
+        let mut packet = [0; MAX_PAYLOAD_BYTES];
+        let len = codec.encode(data, &mut packet).len();
+        assert_eq!(len, codec.packet_len());
+        for &(i, mask) in errors {
+            packet[i] ^= mask;
+        }
+
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let decoded = codec.decode(&packet[0..len], &mut buf)?;
+        assert_eq!(decoded.data, data);
+        Ok(decoded.corrected)
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(0xFFFF, b"123456789"), 0x29B1);
+        assert_eq!(crc16(crc16(0xFFFF, b"1234"), b"56789"), 0x29B1);
+    }
+
+    #[test]
+    fn hamming_round_trip() {
+        for &crc in &[false, true] {
+            let codec = FecCodec::new(FecScheme::Hamming, 32, crc);
+            assert_eq!(transmit(&codec, &[]), Ok(0));
+        }
+    }
+
+    #[test]
+    fn hamming_corrects_one_bit_per_byte() {
+        let codec = FecCodec::new(FecScheme::Hamming, 32, true);
+        let mut errors = [(0, 0); 32];
+        for (i, error) in errors.iter_mut().enumerate() {
+            *error = (i, 1 << (i % 8));
+        }
+        assert_eq!(transmit(&codec, &errors), Ok(32));
+    }
+
+    #[test]
+    fn hamming_detects_two_bits_per_byte() {
+        let codec = FecCodec::new(FecScheme::Hamming, 32, false);
+        assert_eq!(transmit(&codec, &[(5, 0b1001)]), Err(FecError::Uncorrectable));
+    }
+
+    #[test]
+    fn reed_solomon_round_trip() {
+        for &parity in &[2, 4, 8, 16] {
+            for &crc in &[false, true] {
+                let codec = FecCodec::new(FecScheme::ReedSolomon { parity }, 32, crc);
+                assert_eq!(transmit(&codec, &[]), Ok(0));
+            }
+        }
+    }
+
+    #[test]
+    fn reed_solomon_corrects_up_to_half_the_parity() {
+        // Data, length byte and parity bytes alike
+        let errors = [(0, 0xFF), (31, 0x01), (13, 0x5A), (27, 0x80), (6, 0x33), (20, 0xC4), (1, 0x7E), (30, 0x10)];
+        for &parity in &[2u8, 4, 8, 16] {
+            let codec = FecCodec::new(FecScheme::ReedSolomon { parity }, 32, true);
+            let t = parity as usize / 2;
+            for count in 1..=t.min(errors.len()) {
+                assert_eq!(transmit(&codec, &errors[0..count]), Ok(count));
+            }
+        }
+    }
+
+    #[test]
+    fn reed_solomon_detects_more_than_half_the_parity() {
+        let errors = [(2, 0x01), (9, 0xFF), (17, 0x42), (24, 0x99), (30, 0x07)];
+        for &parity in &[2u8, 4, 8] {
+            let codec = FecCodec::new(FecScheme::ReedSolomon { parity }, 32, true);
+            let t = parity as usize / 2;
+            assert!(transmit(&codec, &errors[0..t + 1]).is_err());
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use device::Device;
+use rx::RxMode;
+use payload::Payload;
+use dispatch::{PayloadQueue, OverflowPolicy};
+use ack_stage::AckStage;
+use clock::Clock;
+use PIPES_COUNT;
+
+/// What the `Hub` knows about the peripheral on a pipe
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct PeripheralStats {
+    /// Packets received from the peripheral
+    pub check_ins: u32,
+    /// Time of the last check-in
+    pub last_seen: Option<u32>,
+    /// Time between the last two check-ins
+    pub interval_us: Option<u32>,
+    /// Downstream packets delivered in acks
+    pub delivered: u32,
+    /// Time from leaving the queue until delivery, of the last
+    /// downstream packet
+    pub latency_us: Option<u32>,
+}
+
+/// Star-topology hub that stays in RX mode
+///
+/// Peripherals check in by sending packets on their pipe. Packets
+/// queued for them with `send()` are delivered as ack payloads, one
+/// per check-in. Enable `set_ack_payloads(true)` and dynamic payload
+/// lengths on both ends.
+///
+/// A packet arriving just while the ack payload for its pipe is
+/// written may be counted as having carried it.
+pub struct Hub<'a> {
+    queues: [Option<PayloadQueue<'a>>; PIPES_COUNT],
+    /// Downstream packets taken from their queue, and when
+    staged: AckStage<u32>,
+    stats: [PeripheralStats; PIPES_COUNT],
+    /// Where to start filling the TX FIFO, for fairness
+    next_pipe: usize,
+}
+
+impl<'a> Default for Hub<'a> {
+    fn default() -> Self {
+        Hub::new()
+    }
+}
+
+impl<'a> Hub<'a> {
+    /// Without queues, nothing can be sent downstream
+    pub fn new() -> Self {
+        Hub {
+            queues: [None, None, None, None, None, None],
+            staged: AckStage::new(),
+            stats: [PeripheralStats::default(); PIPES_COUNT],
+            next_pipe: 0,
+        }
+    }
+
+    /// Downstream queue for the peripheral on `pipe_no`
+    ///
+    /// Pipes beyond `PIPES_COUNT` are ignored.
+    pub fn set_queue(&mut self, pipe_no: usize, queue: Option<PayloadQueue<'a>>) {
+        if let Some(slot) = self.queues.get_mut(pipe_no) {
+            *slot = queue;
+        }
+    }
+
+    /// Queue `data` for the peripheral on `pipe_no`. Returns `false`
+    /// if there is no queue or it is full.
+    pub fn send(&mut self, pipe_no: usize, data: &[u8]) -> bool {
+        match self.queues.get_mut(pipe_no) {
+            Some(&mut Some(ref mut queue)) =>
+                queue.push(Payload::new(data), OverflowPolicy::DropNewest),
+            _ => false,
+        }
+    }
+
+    /// Downstream packets not yet delivered to `pipe_no`
+    pub fn pending(&self, pipe_no: usize) -> usize {
+        if pipe_no >= PIPES_COUNT {
+            return 0;
+        }
+        let queued = self.queues[pipe_no].as_ref()
+            .map_or(0, |queue| queue.len());
+        queued + self.staged.get(pipe_no).map_or(0, |_| 1)
+    }
+
+    pub fn stats(&self, pipe_no: usize) -> PeripheralStats {
+        self.stats.get(pipe_no).cloned().unwrap_or_default()
+    }
+
+    /// Has the peripheral on `pipe_no` checked in within
+    /// `timeout_us`?
+    pub fn is_alive<C: Clock>(&self, pipe_no: usize, clock: &mut C, timeout_us: u32) -> bool {
+        match self.stats(pipe_no).last_seen {
+            Some(last_seen) => clock.now_us().wrapping_sub(last_seen) < timeout_us,
+            None => false,
+        }
+    }
+
+    /// Receive one packet, if any, and keep the TX FIFO filled with
+    /// ack payloads
+    pub fn poll<D: Device, C: Clock>(&mut self, rx: &mut RxMode<D>, clock: &mut C) -> Result<Option<Payload>, D::Error> {
+        let now = clock.now_us();
+        let received = match rx.can_read()? {
+            Some(_) => {
+                let payload = rx.read()?;
+                self.check_in(payload.pipe() as usize, now);
+                Some(payload)
+            }
+            None => None,
+        };
+        self.fill(rx, now)?;
+        Ok(received)
+    }
+
+    fn check_in(&mut self, pipe_no: usize, now: u32) {
+        if pipe_no >= PIPES_COUNT {
+            return;
+        }
+
+        let stats = &mut self.stats[pipe_no];
+        stats.check_ins = stats.check_ins.wrapping_add(1);
+        stats.interval_us = stats.last_seen
+            .map(|last_seen| now.wrapping_sub(last_seen));
+        stats.last_seen = Some(now);
+
+        if let Some(staged) = self.staged.received(pipe_no) {
+            stats.delivered = stats.delivered.wrapping_add(1);
+            stats.latency_us = Some(now.wrapping_sub(staged.meta));
+        }
+    }
+
+    fn fill<D: Device>(&mut self, rx: &mut RxMode<D>, now: u32) -> Result<(), D::Error> {
+        let queues = &mut self.queues;
+        self.staged.fill(rx, self.next_pipe, |pipe_no| {
+            queues[pipe_no].as_mut()
+                .and_then(|queue| queue.pop())
+                .map(|payload| (payload, now))
+        })?;
+        self.next_pipe = (self.next_pipe + 1) % PIPES_COUNT;
+        Ok(())
+    }
+
+    /// Free the TX FIFO from ack payloads of peripherals that have
+    /// not checked in within `timeout_us`. Their packets are kept
+    /// until they return.
+    pub fn expire<D: Device, C: Clock>(&mut self, rx: &mut RxMode<D>, clock: &mut C, timeout_us: u32) -> Result<(), D::Error> {
+        let mut alive = [false; PIPES_COUNT];
+        for (pipe_no, alive) in alive.iter_mut().enumerate() {
+            *alive = self.is_alive(pipe_no, clock, timeout_us);
+        }
+        self.staged.expire(rx, |pipe_no| alive[pipe_no], |_| true)?;
+        let now = clock.now_us();
+        self.fill(rx, now)
+    }
+}
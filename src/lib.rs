@@ -5,57 +5,150 @@
 // those terms.
 
 #![no_std]
+#[cfg(any(feature = "std", test))]
+#[macro_use]
+extern crate std;
 extern crate embedded_hal;
+extern crate nb;
 #[macro_use]
 extern crate bitfield;
+#[cfg(feature = "critical-section")]
+extern crate critical_section;
+#[cfg(feature = "defmt")]
+extern crate defmt;
+#[cfg(feature = "ufmt")]
+extern crate ufmt;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate postcard;
 
 use core::fmt;
 use core::fmt::Debug;
 use embedded_hal::digital::OutputPin;
 use embedded_hal::blocking::spi::Transfer as SpiTransfer;
 
+mod bus;
+pub use bus::{SpiBus, RefCellSpi};
+#[cfg(feature = "critical-section")]
+pub use bus::CriticalSectionSpi;
 mod config;
 pub use config::{Configuration, CrcMode, DataRate, PAControl};
 pub mod setup;
+#[cfg(feature = "std")]
+pub mod decode;
 
 mod registers;
-use registers::{Register, Config, Status, SetupAw};
+use registers::{Register, Config, Status, FifoStatus, SetupAw, writable_mask};
+mod shadow;
+use shadow::Shadow;
 mod command;
-use command::{Command, ReadRegister, WriteRegister};
+use command::{Command, ReadRegister, WriteRegister, ReadRxPayload};
 mod payload;
 pub use payload::Payload;
 mod error;
-pub use error::Error;
+pub use error::{Error, ModeError, CodecError};
+#[cfg(feature = "serde")]
+pub use error::MessageError;
+
+mod clock;
+pub use clock::Clock;
 
 mod device;
 pub use device::Device;
 mod standby;
 pub use standby::StandbyMode;
+mod power_down;
+pub use power_down::{PowerDownMode, POWER_UP_DELAY_US};
 mod rx;
-pub use rx::RxMode;
+pub use rx::{RxMode, RxInfo};
 mod tx;
-pub use tx::TxMode;
+pub use tx::{TxMode, MAX_TX_DWELL_US, MIN_CE_PULSE_US};
+mod pipeline;
+pub use pipeline::{PipelinedTx, PacketHandle, Outcome};
+mod dispatch;
+pub use dispatch::{Dispatcher, Route, PayloadQueue, OverflowPolicy, PipeStats};
+mod ack_stage;
+mod hub;
+pub use hub::{Hub, PeripheralStats};
+mod adaptive;
+pub use adaptive::{LinkAdapter, LinkFollower, LinkSetting, LinkChange, Adaptation, AdaptivePolicy, DEFAULT_LADDER, LINK_CHANGE_MAGIC};
+mod pubsub;
+pub use pubsub::{Broker, BrokerEvent, Publish, Subscribe, Subscription, Retained, TopicId, PUBSUB_DATA_BYTES};
+mod rpc;
+pub use rpc::{RpcClient, RpcServer, RpcHandler, RpcStatus, RpcError, RPC_DATA_BYTES, MAX_HANDLERS};
+mod ota;
+pub use ota::{OtaSender, OtaReceiver, OtaEvent, ImageStorage, Manifest, crc32, BLOCK_BYTES};
+mod pairing;
+pub use pairing::{PairingNode, PairingHub, Binding, NodeId, enter_pairing, PAIRING_CHANNEL, PAIRING_ADDR, KEY_BYTES};
+mod mqttsn;
+pub use mqttsn::{MqttSnClient, MqttSnGateway, MqttSnError, ClientState, ClientEvent, BrokerConnection, GatewayClient, GatewayTopic, GatewaySubscription, Outgoing, Message, QoS, ReturnCode, encapsulate, decapsulate, MAX_CLIENT_ID_BYTES, MAX_TOPIC_NAME_BYTES, MAX_PENDING_ACKS};
+mod fec;
+pub use fec::{FecCodec, FecScheme, FecError, Decoded, crc16};
+mod stream;
+pub use stream::{StreamSender, StreamFrame, JitterBuffer, Concealment, Silence, RepeatLast, Playout, StreamStats, STREAM_DATA_BYTES};
+mod mailbox;
+pub use mailbox::{Mailbox, MailboxClient, MAIL_DATA_BYTES};
+mod repeater;
+pub use repeater::{Repeater, MeshRoute, RelayAction, RelayOutcome, MeshNode, MESH_DATA_BYTES, MAX_ATTEMPTS};
+mod timesync;
+pub use timesync::{TimeSync, TimeSyncServer, LinkTiming, SyncSample, send_beacon, TX_SETTLING_US};
+#[cfg(test)]
+mod mock;
 
 pub const PIPES_COUNT: usize = 6;
 pub const MIN_ADDR_BYTES: usize = 3;
 pub const MAX_ADDR_BYTES: usize = 5;
+pub const MAX_PAYLOAD_BYTES: usize = 32;
 
 
 /// Driver for the nRF24L01+
-pub struct NRF24L01<CE: OutputPin, CSN: OutputPin, SPI: SpiTransfer<u8>> {
+pub struct NRF24L01<CE: OutputPin, CSN: OutputPin, SPI: SpiBus> {
     ce: CE,
     csn: CSN,
     spi: SPI,
     config: Config,
+    /// Status received with the last SPI transaction
+    status: Status,
+    /// FIFO status read last
+    fifo_status: FifoStatus,
+    shadow: Option<Shadow>,
+    /// Retries for writes that do not read back as written, or
+    /// `None` to not verify.
+    write_verify: Option<u8>,
 }
 
-impl<CE: OutputPin, CSN: OutputPin, SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debug> fmt::Debug for NRF24L01<CE, CSN, SPI> {
+impl<CE: OutputPin, CSN: OutputPin, SPI: SpiBus<Error=SPIE>, SPIE: Debug> fmt::Debug for NRF24L01<CE, CSN, SPI> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "NRF24L01")
     }
 }
 
-impl<CE: OutputPin, CSN: OutputPin, SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debug> NRF24L01<CE, CSN, SPI> {
+/// Prints the registers as of the last SPI transaction
+#[cfg(feature = "defmt")]
+impl<CE: OutputPin, CSN: OutputPin, SPI: SpiBus> defmt::Format for NRF24L01<CE, CSN, SPI> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "NRF24L01 {{ config: {}, status: {}, fifo_status: {} }}",
+            self.config, self.status, self.fifo_status
+        )
+    }
+}
+
+/// Prints the registers as of the last SPI transaction
+#[cfg(feature = "ufmt")]
+impl<CE: OutputPin, CSN: OutputPin, SPI: SpiBus> ufmt::uDebug for NRF24L01<CE, CSN, SPI> {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("NRF24L01")?
+            .field("config", &self.config)?
+            .field("status", &self.status)?
+            .field("fifo_status", &self.fifo_status)?
+            .finish()
+    }
+}
+
+impl<CE: OutputPin, CSN: OutputPin, SPI: SpiBus<Error=SPIE>, SPIE: Debug> NRF24L01<CE, CSN, SPI> {
     /// Construct a new driver instance.
     pub fn new(mut ce: CE, mut csn: CSN, spi: SPI) -> Result<StandbyMode<Self>, Error<SPIE>> {
         ce.set_low();
@@ -69,6 +162,11 @@ impl<CE: OutputPin, CSN: OutputPin, SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debu
         let mut device = NRF24L01 {
             ce, csn, spi,
             config,
+            status: Status(0),
+            // Reset value
+            fifo_status: FifoStatus(0b0001_0001),
+            shadow: None,
+            write_verify: None,
         };
         assert!(device.is_connected().unwrap());
 
@@ -79,16 +177,134 @@ impl<CE: OutputPin, CSN: OutputPin, SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debu
     }
 
     pub fn is_connected(&mut self) -> Result<bool, Error<SPIE>> {
+        // Bypass the shadow
         let (_, setup_aw) =
-            self.read_register::<SetupAw>()?;
+            self.send_command(&ReadRegister::<SetupAw>::new())?;
         let valid =
             setup_aw.aw() >= 3 &&
             setup_aw.aw() <= 5;
         Ok(valid)
     }
+
+    /// Keep a shadow copy of all registers that only change when
+    /// written, so that reading them takes no SPI traffic.
+    ///
+    /// Reads of these return the status of the last SPI
+    /// transaction. Stays disabled if reading the registers fails.
+    pub fn enable_shadow(&mut self) -> Result<(), Error<SPIE>> {
+        self.shadow = Some(Shadow::new());
+        let result = self.resync();
+        if result.is_err() {
+            self.shadow = None;
+        }
+        result
+    }
+
+    pub fn disable_shadow(&mut self) {
+        self.shadow = None;
+    }
+
+    /// Read back every register write, and write again up to
+    /// `retries` times if it differs. Then fail with
+    /// `Error::VerifyFailed`.
+    ///
+    /// Pass `None` to stop verifying.
+    pub fn set_write_verify(&mut self, retries: Option<u8>) {
+        self.write_verify = retries;
+    }
+
+    /// Compare the chip's register with what has been written,
+    /// ignoring read-only and reserved bits
+    fn verify_register<R: Register>(&mut self, register: &R) -> Result<(), Error<SPIE>> {
+        let mask = writable_mask(R::addr());
+        if mask == 0 {
+            return Ok(());
+        }
+
+        let mut wrote = [0; MAX_ADDR_BYTES];
+        let len = register.write_len();
+        register.encode(&mut wrote[0..len]);
+        let (_, read_register) =
+            self.send_command(&ReadRegister::<R>::new())?;
+        let mut read = [0; MAX_ADDR_BYTES];
+        read_register.encode(&mut read[0..read_register.write_len()]);
+
+        for i in 0..len {
+            if (wrote[i] ^ read[i]) & mask != 0 {
+                return Err(Error::VerifyFailed {
+                    addr: R::addr(),
+                    wrote: wrote[i],
+                    read: read[i],
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Lock the bus and run `f` with `CSN` low
+    fn transaction<F>(&mut self, f: F) -> Result<(), Error<SPIE>>
+    where
+        F: FnOnce(&mut SPI::Spi) -> Result<(), SPIE>,
+    {
+        let csn = &mut self.csn;
+        let transfer_result = self.spi.lock(|spi| {
+            csn.set_low();
+            let result = f(spi);
+            csn.set_high();
+            result
+        });
+        // Propagate Err only after csn.set_high():
+        transfer_result?;
+        Ok(())
+    }
+
+    /// Read back the configuration from the chip, e.g. after it may
+    /// have been reset. Refills the shadow, if enabled, or leaves it
+    /// as it was on error.
+    pub fn resync(&mut self) -> Result<(), Error<SPIE>> {
+        let (_, config) =
+            self.send_command(&ReadRegister::<Config>::new())?;
+        self.config = config;
+
+        // Read from the chip, not from the shadow
+        let previous = match self.shadow.take() {
+            Some(previous) => previous,
+            None => return Ok(()),
+        };
+        match self.read_shadow() {
+            Ok(shadow) => {
+                self.shadow = Some(shadow);
+                Ok(())
+            }
+            Err(e) => {
+                // Keep what was known before
+                self.shadow = Some(previous);
+                Err(e)
+            }
+        }
+    }
+
+    /// Read the registers to shadow from the chip, while the shadow
+    /// is disabled
+    fn read_shadow(&mut self) -> Result<Shadow, Error<SPIE>> {
+        let mut shadow = Shadow::new();
+        macro_rules! resync {
+            ($($name: ident),+) => ($({
+                use registers::$name;
+                let (_, register) =
+                    self.send_command(&ReadRegister::<$name>::new())?;
+                shadow.set(&register);
+            })+)
+        }
+        resync!(Config, EnAa, EnRxaddr, SetupAw, SetupRetr, RfCh, RfSetup,
+                RxAddrP0, RxAddrP1, RxAddrP2, RxAddrP3, RxAddrP4, RxAddrP5, TxAddr,
+                RxPwP0, RxPwP1, RxPwP2, RxPwP3, RxPwP4, RxPwP5,
+                Dynpd, Feature);
+        Ok(shadow)
+    }
 }
 
-impl<CE: OutputPin, CSN: OutputPin, SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debug> Device for NRF24L01<CE, CSN, SPI> {
+impl<CE: OutputPin, CSN: OutputPin, SPI: SpiBus<Error=SPIE>, SPIE: Debug> Device for NRF24L01<CE, CSN, SPI> {
     type Error = Error<SPIE>;
 
     fn ce_enable(&mut self) {
@@ -108,27 +324,73 @@ impl<CE: OutputPin, CSN: OutputPin, SPI: SpiTransfer<u8, Error=SPIE>, SPIE: Debu
         command.encode(buf);
 
         // SPI transaction
-        self.csn.set_low();
-        let transfer_result = self.spi.transfer(buf)
-            .map(|_| {});
-        self.csn.set_high();
-        // Propagate Err only after csn.set_high():
-        transfer_result?;
+        self.transaction(|spi| spi.transfer(buf).map(|_| {}))?;
 
         // Parse response
         let status = Status(buf[0]);
+        self.status = status.clone();
         let response = C::decode_response(buf);
 
         Ok((status, response))
     }
 
+    fn read_rx_payload_into(&mut self, buf: &mut [u8]) -> Result<Status, Self::Error> {
+        let mut opcode = [0];
+        ReadRxPayload::new(0).encode(&mut opcode);
+
+        // Opcode and payload in one SPI transaction
+        self.transaction(|spi| {
+            spi.transfer(&mut opcode)
+                .and_then(|_| spi.transfer(buf))
+                .map(|_| {})
+        })?;
+
+        let status = Status(opcode[0]);
+        self.status = status.clone();
+        Ok(status)
+    }
+
     fn write_register<R: Register>(&mut self, register: R) -> Result<Status, Self::Error> {
-        let (status, ()) = self.send_command(&WriteRegister::new(register))?;
+        let command = WriteRegister::new(register);
+        let mut attempts = 0;
+        let status = loop {
+            let (status, ()) = self.send_command(&command)?;
+            let retries = match self.write_verify {
+                Some(retries) => retries,
+                None => break status,
+            };
+            match self.verify_register(command.register()) {
+                Ok(()) => break status,
+                Err(Error::VerifyFailed { .. }) if attempts < retries =>
+                    attempts += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        if let Some(ref mut shadow) = self.shadow {
+            shadow.set(command.register());
+        }
         Ok(status)
     }
 
+    /// With the shadow enabled, registers served from it come with
+    /// the status of the last SPI transaction, which may be stale.
+    /// Volatile registers such as `FifoStatus` are always read from
+    /// the chip.
     fn read_register<R: Register>(&mut self) -> Result<(Status, R), Self::Error> {
-        self.send_command(&ReadRegister::new())
+        if let Some(register) = self.shadow.as_ref().and_then(|shadow| shadow.get()) {
+            return Ok((self.status.clone(), register));
+        }
+
+        let (status, register) = self.send_command(&ReadRegister::<R>::new())?;
+        if let Some(ref mut shadow) = self.shadow {
+            shadow.set(&register);
+        }
+        if R::addr() == FifoStatus::addr() {
+            let mut value = [0];
+            register.encode(&mut value);
+            self.fifo_status = FifoStatus(value[0]);
+        }
+        Ok((status, register))
     }
 
     fn update_config<F, R>(&mut self, f: F) -> Result<R, Self::Error>
@@ -0,0 +1,246 @@
+//! Mail for sleepy children, delivered in ack payloads
+//!
+//! A parent in RX mode keeps a `Mailbox` with a queue per pipe. A
+//! child powers up now and then, sends polls and receives one message
+//! in the ack payload of each, until the parent marks the last one.
+//!
+//! A message is staged for the ack payload once its child polls, so
+//! the first poll after waking usually comes back empty-handed. Mail
+//! posted while a child's last message is already in the TX FIFO
+//! waits for its next wake-up. Both ends need
+//! `set_ack_payloads(true)` and dynamic payload lengths.
+
+use embedded_hal::blocking::delay::DelayUs;
+use device::Device;
+use standby::StandbyMode;
+use rx::RxMode;
+use tx::SEND_TIMEOUT_US;
+use power_down::PowerDownMode;
+use payload::Payload;
+use dispatch::{PayloadQueue, OverflowPolicy};
+use ack_stage::AckStage;
+use clock::Clock;
+use {PIPES_COUNT, MAX_PAYLOAD_BYTES};
+
+const POLL: u8 = 0xA0;
+const MAIL: u8 = 0xA1;
+const EMPTY: u8 = 0xA2;
+const FLAG_MORE: u8 = 0b1;
+const MAIL_HEADER_LEN: usize = 2;
+/// Bytes of data per message
+pub const MAIL_DATA_BYTES: usize = MAX_PAYLOAD_BYTES - MAIL_HEADER_LEN;
+/// Parent end, see the module documentation
+pub struct Mailbox<'a> {
+    queues: [Option<PayloadQueue<'a>>; PIPES_COUNT],
+    /// Ack payloads waiting for their child's poll
+    staged: AckStage<()>,
+    last_poll: [Option<u32>; PIPES_COUNT],
+}
+
+impl<'a> Default for Mailbox<'a> {
+    fn default() -> Self {
+        Mailbox::new()
+    }
+}
+
+impl<'a> Mailbox<'a> {
+    /// Without queues, no mail can be posted
+    pub fn new() -> Self {
+        Mailbox {
+            queues: [None, None, None, None, None, None],
+            staged: AckStage::new(),
+            last_poll: [None; PIPES_COUNT],
+        }
+    }
+
+    /// Mail queue for the child on `pipe_no`
+    ///
+    /// Pipes beyond `PIPES_COUNT` are ignored.
+    pub fn set_queue(&mut self, pipe_no: usize, queue: Option<PayloadQueue<'a>>) {
+        if let Some(slot) = self.queues.get_mut(pipe_no) {
+            *slot = queue;
+        }
+    }
+
+    /// Post up to `MAIL_DATA_BYTES` of `data` to the child on
+    /// `pipe_no`. Returns `false` if there is no queue, it is full or
+    /// `data` is too long.
+    pub fn post(&mut self, pipe_no: usize, data: &[u8]) -> bool {
+        if data.len() > MAIL_DATA_BYTES {
+            return false;
+        }
+        match self.queues.get_mut(pipe_no) {
+            Some(&mut Some(ref mut queue)) =>
+                queue.push(Payload::new(data), OverflowPolicy::DropNewest),
+            _ => false,
+        }
+    }
+
+    /// Messages not yet delivered to `pipe_no`
+    pub fn pending(&self, pipe_no: usize) -> usize {
+        if pipe_no >= PIPES_COUNT {
+            return 0;
+        }
+        let queued = self.queues[pipe_no].as_ref()
+            .map_or(0, |queue| queue.len());
+        let staged = matches!(self.staged.get(pipe_no), Some(staged) if staged.packet[0] == MAIL);
+        queued + staged as usize
+    }
+
+    /// Time of the last poll from `pipe_no`
+    pub fn last_poll(&self, pipe_no: usize) -> Option<u32> {
+        self.last_poll.get(pipe_no).cloned().unwrap_or_default()
+    }
+
+    /// Receive one packet, if any. Polls are answered, other packets
+    /// returned.
+    pub fn poll<D: Device, C: Clock>(&mut self, rx: &mut RxMode<D>, clock: &mut C) -> Result<Option<Payload>, D::Error> {
+        let payload = match rx.can_read()? {
+            Some(_) => rx.read()?,
+            None => return Ok(None),
+        };
+        let pipe_no = payload.pipe() as usize;
+        if pipe_no >= PIPES_COUNT {
+            return Ok(Some(payload));
+        }
+
+        // Any packet from the child carries away its ack payload
+        let consumed = self.staged.received(pipe_no).is_some();
+        let is_poll = payload.len() == 1 && payload[0] == POLL;
+        if is_poll {
+            self.last_poll[pipe_no] = Some(clock.now_us());
+            if self.staged.get(pipe_no).is_none() {
+                if let Some(packet) = self.next_packet(pipe_no, consumed) {
+                    self.staged.stage(pipe_no, packet, ());
+                }
+            }
+        }
+        self.staged.fill(rx, 0, |_| None)?;
+        Ok(if is_poll { None } else { Some(payload) })
+    }
+
+    /// The next message, or after a poll that got nothing, word that
+    /// there is none
+    fn next_packet(&mut self, pipe_no: usize, consumed: bool) -> Option<Payload> {
+        let queue = self.queues[pipe_no].as_mut();
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let packet = match queue.and_then(|queue| queue.pop().map(|data| (data, !queue.is_empty()))) {
+            Some((data, more)) => {
+                buf[0] = MAIL;
+                buf[1] = if more { FLAG_MORE } else { 0 };
+                let len = MAIL_HEADER_LEN + data.len();
+                buf[MAIL_HEADER_LEN..len].copy_from_slice(&data);
+                &buf[0..len]
+            }
+            // The child got the last message and stops polling
+            None if consumed => return None,
+            None => {
+                buf[0] = EMPTY;
+                &buf[0..1]
+            }
+        };
+        Some(Payload::new(packet))
+    }
+
+    /// Free the TX FIFO from ack payloads of children that have not
+    /// polled within `timeout_us`, so that those awake get theirs.
+    /// Messages are kept for the next wake-up.
+    pub fn expire<D: Device, C: Clock>(&mut self, rx: &mut RxMode<D>, clock: &mut C, timeout_us: u32) -> Result<(), D::Error> {
+        let now = clock.now_us();
+        let last_poll = self.last_poll;
+        let asleep = |pipe_no: usize| match last_poll[pipe_no] {
+            Some(last_poll) => now.wrapping_sub(last_poll) >= timeout_us,
+            None => true,
+        };
+        // Messages stay staged until the child polls again, but word
+        // of an empty mailbox would be stale by then
+        self.staged.expire(rx, |pipe_no| !asleep(pipe_no), |staged| staged.packet[0] == MAIL)?;
+        self.staged.fill(rx, 0, |_| None)
+    }
+}
+
+/// Child end, see the module documentation
+#[derive(Debug)]
+pub struct MailboxClient {
+    retry_us: u32,
+    max_polls: u8,
+}
+
+impl MailboxClient {
+    /// Waits `retry_us` before polling again after a poll without
+    /// mail, and gives up after `max_polls` polls without mail
+    pub fn new(retry_us: u32, max_polls: u8) -> Self {
+        MailboxClient {
+            retry_us,
+            max_polls,
+        }
+    }
+
+    /// Power up, fetch all mail, passing each message to `handler`,
+    /// and power down again. Returns the number of messages.
+    ///
+    /// On error, the chip has been powered down if possible. Each poll
+    /// gives up with `Error::Timeout` if the chip does not respond.
+    pub fn wake_and_fetch<D, DL, F>(
+        &self,
+        power_down: PowerDownMode<D>,
+        delay: &mut DL,
+        mut handler: F,
+    ) -> Result<(PowerDownMode<D>, usize), (D, D::Error)>
+    where
+        D: Device,
+        DL: DelayUs<u32>,
+        F: FnMut(&[u8]),
+    {
+        let standby = power_down.power_up(delay)
+            .map_err(|(device, e)| shut_down(device, e))?;
+        let mut tx = standby.tx()
+            .map_err(|(device, e)| shut_down(device, e))?;
+
+        let mut fetched = 0;
+        let mut result = Ok(());
+        let mut empty_polls = 0;
+        while empty_polls < self.max_polls {
+            let reply = tx.send_sync_timeout(&[POLL], delay, SEND_TIMEOUT_US)
+                .and_then(|acked| if acked { tx.read_ack_payload() } else { Ok(None) });
+            let reply = match reply {
+                Ok(reply) => reply,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            match reply {
+                Some(ref packet) if packet.len() >= MAIL_HEADER_LEN && packet[0] == MAIL => {
+                    handler(&packet[MAIL_HEADER_LEN..]);
+                    fetched += 1;
+                    if packet[1] & FLAG_MORE == 0 {
+                        break;
+                    }
+                    empty_polls = 0;
+                }
+                Some(ref packet) if packet.len() == 1 && packet[0] == EMPTY => break,
+                // Not acked, or the parent has not staged anything yet
+                _ => {
+                    empty_polls += 1;
+                    delay.delay_us(self.retry_us);
+                }
+            }
+        }
+
+        let power_down = tx.into_standby().power_down()?;
+        match result {
+            Ok(()) => Ok((power_down, fetched)),
+            Err(e) => Err((power_down.into_device(), e)),
+        }
+    }
+}
+
+/// Power down after `e`, if the chip still responds
+fn shut_down<D: Device>(device: D, e: D::Error) -> (D, D::Error) {
+    match StandbyMode::from_rx_tx(device).power_down() {
+        Ok(power_down) => (power_down.into_device(), e),
+        Err((device, _)) => (device, e),
+    }
+}
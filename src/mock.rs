@@ -0,0 +1,271 @@
+//! Simulated chip for unit tests
+//!
+//! Interprets the SPI commands like an nRF24L01+ with its register
+//! map and FIFOs. In TX mode with `CE` high, the head of the TX FIFO
+//! goes on air when the driver polls by reading a register; the
+//! outcome is taken from `acks`, and `responder` may answer with a
+//! packet for the RX FIFO.
+
+use core::fmt;
+use std::boxed::Box;
+use std::collections::VecDeque;
+use std::vec::Vec;
+use embedded_hal::blocking::delay::DelayUs;
+use command::{Command, ReadRegister, WriteRegister};
+use registers::{Register, Config, Status};
+use device::Device;
+use clock::Clock;
+use error::Error;
+
+const RX_DR: u8 = 0x40;
+const TX_DS: u8 = 0x20;
+const MAX_RT: u8 = 0x10;
+const FIFO_DEPTH: usize = 3;
+
+/// Returns the pipe and data of a packet to receive in reply
+pub type Responder = Box<dyn FnMut(&[u8]) -> Option<(u8, Vec<u8>)>>;
+
+/// A packet that went on air
+#[derive(Debug, PartialEq, Clone)]
+pub struct Sent {
+    pub addr: Vec<u8>,
+    pub data: Vec<u8>,
+    pub acked: bool,
+}
+
+pub struct MockRadio {
+    regs: [[u8; 5]; 0x20],
+    flags: u8,
+    ce: bool,
+    tx: VecDeque<(Vec<u8>, bool)>,
+    /// Pipe and data of received packets
+    pub rx: VecDeque<(u8, Vec<u8>)>,
+    /// Outcomes of the next transmissions; acked when empty
+    pub acks: VecDeque<bool>,
+    /// Returned with the next acks
+    pub ack_payloads: VecDeque<Vec<u8>>,
+    /// Written with W_ACK_PAYLOAD, with their pipe
+    pub written_acks: Vec<(u8, Vec<u8>)>,
+    /// Answers an acknowledged packet with one to receive
+    pub responder: Option<Responder>,
+    pub sent: Vec<Sent>,
+    /// Stop transmitting, like a radio that hangs
+    pub stuck: bool,
+    /// Packets sent on air per poll, 1 by default
+    pub burst: usize,
+}
+
+impl MockRadio {
+    pub fn new() -> Self {
+        let mut regs = [[0; 5]; 0x20];
+        regs[0x00][0] = 0x08;
+        regs[0x01][0] = 0x3F;
+        regs[0x02][0] = 0x03;
+        regs[0x03][0] = 0x03;
+        regs[0x04][0] = 0x03;
+        regs[0x05][0] = 0x02;
+        regs[0x06][0] = 0x0E;
+        regs[0x0A] = [0xE7; 5];
+        regs[0x0B] = [0xC2; 5];
+        regs[0x10] = [0xE7; 5];
+        MockRadio {
+            regs,
+            flags: 0,
+            ce: false,
+            tx: VecDeque::new(),
+            rx: VecDeque::new(),
+            acks: VecDeque::new(),
+            ack_payloads: VecDeque::new(),
+            written_acks: Vec::new(),
+            responder: None,
+            sent: Vec::new(),
+            stuck: false,
+            burst: 1,
+        }
+    }
+
+    /// Queue a packet as if received on `pipe`
+    pub fn receive(&mut self, pipe: u8, data: &[u8]) {
+        self.rx.push_back((pipe, data.to_vec()));
+        self.flags |= RX_DR;
+    }
+
+    fn addr_len(&self) -> usize {
+        self.regs[0x03][0] as usize + 2
+    }
+
+    fn status(&self) -> u8 {
+        let rx_p_no = self.rx.front().map_or(0b111, |&(pipe, _)| pipe);
+        let tx_full = (self.tx.len() >= FIFO_DEPTH) as u8;
+        self.flags | (rx_p_no << 1) | tx_full
+    }
+
+    fn fifo_status(&self) -> u8 {
+        ((self.tx.len() >= FIFO_DEPTH) as u8) << 5 |
+            (self.tx.is_empty() as u8) << 4 |
+            ((self.rx.len() >= FIFO_DEPTH) as u8) << 1 |
+            self.rx.is_empty() as u8
+    }
+
+    /// Send `burst` packets if the chip is transmitting
+    fn step(&mut self) {
+        for _ in 0..self.burst {
+            self.send_head();
+        }
+    }
+
+    /// Send the head of the TX FIFO if the chip is transmitting
+    fn send_head(&mut self) {
+        let config = self.regs[0x00][0];
+        let transmitting = config & 0b11 == 0b10 && self.ce && !self.stuck;
+        if !transmitting || self.flags & MAX_RT != 0 {
+            return;
+        }
+        let (data, no_ack) = match self.tx.front() {
+            Some(packet) => packet.clone(),
+            None => return,
+        };
+        let acked = no_ack || self.acks.pop_front().unwrap_or(true);
+        let addr = self.regs[0x10][0..self.addr_len()].to_vec();
+        self.sent.push(Sent { addr, data: data.clone(), acked });
+        if !acked {
+            self.flags |= MAX_RT;
+            return;
+        }
+
+        self.tx.pop_front();
+        self.flags |= TX_DS;
+        if !no_ack {
+            if let Some(payload) = self.ack_payloads.pop_front() {
+                self.receive(0, &payload);
+            }
+            let response = match self.responder {
+                Some(ref mut responder) => responder(&data),
+                None => None,
+            };
+            if let Some((pipe, response)) = response {
+                self.receive(pipe, &response);
+            }
+        }
+    }
+
+    fn transfer(&mut self, buf: &mut [u8]) {
+        let opcode = buf[0];
+        if opcode < 0x20 || opcode == 0xFF {
+            self.step();
+        }
+        let status = self.status();
+        match opcode {
+            0x00..=0x1F => {
+                let addr = opcode as usize;
+                let value = match addr {
+                    0x07 => [status; 5],
+                    0x17 => [self.fifo_status(); 5],
+                    0x08 => [0; 5],
+                    _ => self.regs[addr],
+                };
+                let len = (buf.len() - 1).min(5);
+                buf[1..1 + len].copy_from_slice(&value[0..len]);
+            }
+            0x20..=0x3F => {
+                let addr = (opcode & 0x1F) as usize;
+                if addr == 0x07 {
+                    self.flags &= !(buf[1] & (RX_DR | TX_DS | MAX_RT));
+                } else {
+                    let len = (buf.len() - 1).min(5);
+                    self.regs[addr][0..len].copy_from_slice(&buf[1..1 + len]);
+                }
+            }
+            0x60 => buf[1] = self.rx.front().map_or(0, |(_, data)| data.len() as u8),
+            0x61 => {
+                if let Some((_, data)) = self.rx.pop_front() {
+                    let len = data.len().min(buf.len() - 1);
+                    buf[1..1 + len].copy_from_slice(&data[0..len]);
+                }
+            }
+            0xA0 | 0xB0 if self.tx.len() < FIFO_DEPTH =>
+                self.tx.push_back((buf[1..].to_vec(), opcode == 0xB0)),
+            0xA8..=0xAD => self.written_acks.push((opcode & 0x7, buf[1..].to_vec())),
+            0xE1 => self.tx.clear(),
+            0xE2 => self.rx.clear(),
+            _ => {}
+        }
+        buf[0] = status;
+    }
+}
+
+impl fmt::Debug for MockRadio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockRadio")
+            .field("tx", &self.tx)
+            .field("rx", &self.rx)
+            .field("sent", &self.sent)
+            .finish()
+    }
+}
+
+impl Device for MockRadio {
+    type Error = Error<()>;
+
+    fn ce_enable(&mut self) {
+        self.ce = true;
+    }
+
+    fn ce_disable(&mut self) {
+        self.ce = false;
+    }
+
+    fn send_command<C: Command>(&mut self, command: &C) -> Result<(Status, C::Response), Self::Error> {
+        let mut buf_storage = [0; 33];
+        let buf = &mut buf_storage[0..command.len()];
+        command.encode(buf);
+        self.transfer(buf);
+        Ok((Status::decode(&buf[0..1]), C::decode_response(buf)))
+    }
+
+    fn write_register<R: Register>(&mut self, register: R) -> Result<Status, Self::Error> {
+        let (status, ()) = self.send_command(&WriteRegister::new(register))?;
+        Ok(status)
+    }
+
+    fn read_register<R: Register>(&mut self) -> Result<(Status, R), Self::Error> {
+        self.send_command(&ReadRegister::<R>::new())
+    }
+
+    fn update_config<F, R>(&mut self, f: F) -> Result<R, Self::Error>
+    where
+        F: FnOnce(&mut Config) -> R,
+    {
+        let (_, mut config) = self.read_register::<Config>()?;
+        let result = f(&mut config);
+        self.write_register(config)?;
+        Ok(result)
+    }
+}
+
+/// Advances by `step_us` on every reading
+pub struct TestClock {
+    pub now: u32,
+    pub step_us: u32,
+}
+
+impl TestClock {
+    pub fn new(step_us: u32) -> Self {
+        TestClock { now: 0, step_us }
+    }
+}
+
+impl Clock for TestClock {
+    fn now_us(&mut self) -> u32 {
+        let now = self.now;
+        self.now = self.now.wrapping_add(self.step_us);
+        now
+    }
+}
+
+/// Returns at once
+pub struct NoDelay;
+
+impl DelayUs<u32> for NoDelay {
+    fn delay_us(&mut self, _us: u32) {}
+}
@@ -0,0 +1,1620 @@
+//! MQTT-SN over nRF24L01 links
+//!
+//! `MqttSnClient` is the client state machine, `MqttSnGateway` the
+//! gateway endpoint which bridges clients to an MQTT broker through
+//! a `BrokerConnection`. Both only encode and decode packets; send
+//! and receive them with `TxMode` and `RxMode`.
+//!
+//! Packets from clients are wrapped in the forwarder encapsulation of
+//! MQTT-SN 1.2, carrying the client's own RX address as wireless node
+//! id. That tells the gateway where to send its replies and lets
+//! clients share a pipe. With 5-byte addresses, this leaves 24 bytes
+//! for the MQTT-SN message.
+//!
+//! Supported are CONNECT, REGISTER, PUBLISH with QoS 0 and 1,
+//! SUBSCRIBE to normal topic names without wildcards, PINGREQ and
+//! sleeping clients. Will messages, QoS 2 and predefined or short
+//! topic ids are not.
+
+use device::Device;
+use tx::TxMode;
+use config::Configuration;
+use clock::Clock;
+use {MAX_ADDR_BYTES, MAX_PAYLOAD_BYTES};
+
+const CONNECT: u8 = 0x04;
+const CONNACK: u8 = 0x05;
+const REGISTER: u8 = 0x0A;
+const REGACK: u8 = 0x0B;
+const PUBLISH: u8 = 0x0C;
+const PUBACK: u8 = 0x0D;
+const SUBSCRIBE: u8 = 0x12;
+const SUBACK: u8 = 0x13;
+const PINGREQ: u8 = 0x16;
+const PINGRESP: u8 = 0x17;
+const DISCONNECT: u8 = 0x18;
+const ENCAPSULATED: u8 = 0xFE;
+
+const FLAG_DUP: u8 = 0x80;
+const FLAG_QOS: u8 = 0x60;
+const FLAG_RETAIN: u8 = 0x10;
+const FLAG_CLEAN_SESSION: u8 = 0x04;
+const FLAG_TOPIC_ID_TYPE: u8 = 0x03;
+const PROTOCOL_ID: u8 = 0x01;
+
+/// Longest client id permitted by MQTT-SN
+pub const MAX_CLIENT_ID_BYTES: usize = 23;
+/// Longest topic name the gateway stores
+pub const MAX_TOPIC_NAME_BYTES: usize = 24;
+/// Acknowledgements an `MqttSnClient` holds until `poll()`
+pub const MAX_PENDING_ACKS: usize = 4;
+
+fn read_u16(buf: &[u8]) -> u16 {
+    ((buf[0] as u16) << 8) | (buf[1] as u16)
+}
+
+fn write_u16(buf: &mut [u8], value: u16) {
+    buf[0] = (value >> 8) as u8;
+    buf[1] = value as u8;
+}
+
+/// Return code of acknowledgements
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ReturnCode {
+    Accepted = 0,
+    Congestion = 1,
+    InvalidTopicId = 2,
+    NotSupported = 3,
+}
+
+impl ReturnCode {
+    fn from_u8(rc: u8) -> Self {
+        match rc {
+            0 => ReturnCode::Accepted,
+            1 => ReturnCode::Congestion,
+            2 => ReturnCode::InvalidTopicId,
+            _ => ReturnCode::NotSupported,
+        }
+    }
+}
+
+/// Quality of service
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QoS {
+    AtMostOnce,
+    AtLeastOnce,
+}
+
+impl QoS {
+    fn flags(self) -> u8 {
+        match self {
+            QoS::AtMostOnce => 0x00,
+            QoS::AtLeastOnce => 0x20,
+        }
+    }
+
+    /// `None` for QoS 2 and -1
+    fn from_flags(flags: u8) -> Option<Self> {
+        match flags & FLAG_QOS {
+            0x00 => Some(QoS::AtMostOnce),
+            0x20 => Some(QoS::AtLeastOnce),
+            _ => None,
+        }
+    }
+}
+
+/// An MQTT-SN message
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Message<'p> {
+    Connect { clean_session: bool, duration: u16, client_id: &'p [u8] },
+    Connack { rc: ReturnCode },
+    Register { topic_id: u16, msg_id: u16, topic_name: &'p [u8] },
+    Regack { topic_id: u16, msg_id: u16, rc: ReturnCode },
+    Publish { dup: bool, qos: QoS, retain: bool, topic_id: u16, msg_id: u16, data: &'p [u8] },
+    Puback { topic_id: u16, msg_id: u16, rc: ReturnCode },
+    Subscribe { dup: bool, qos: QoS, msg_id: u16, topic_name: &'p [u8] },
+    Suback { qos: QoS, topic_id: u16, msg_id: u16, rc: ReturnCode },
+    /// `client_id` is empty except when a sleeping client wakes up
+    Pingreq { client_id: &'p [u8] },
+    Pingresp,
+    /// With a duration, the client goes to sleep
+    Disconnect { duration: Option<u16> },
+}
+
+impl<'p> Message<'p> {
+    /// Number of bytes `encode()` writes
+    pub fn encoded_len(&self) -> usize {
+        match *self {
+            Message::Connect { client_id, .. } => 6 + client_id.len(),
+            Message::Connack { .. } => 3,
+            Message::Register { topic_name, .. } => 6 + topic_name.len(),
+            Message::Regack { .. } | Message::Puback { .. } => 7,
+            Message::Publish { data, .. } => 7 + data.len(),
+            Message::Subscribe { topic_name, .. } => 5 + topic_name.len(),
+            Message::Suback { .. } => 8,
+            Message::Pingreq { client_id } => 2 + client_id.len(),
+            Message::Pingresp => 2,
+            Message::Disconnect { duration: Some(_) } => 4,
+            Message::Disconnect { duration: None } => 2,
+        }
+    }
+
+    /// Encode into `buf`. Panics if it does not fit.
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        let len = match *self {
+            Message::Connect { clean_session, duration, client_id } => {
+                buf[1] = CONNECT;
+                buf[2] = if clean_session { FLAG_CLEAN_SESSION } else { 0 };
+                buf[3] = PROTOCOL_ID;
+                write_u16(&mut buf[4..6], duration);
+                buf[6..6 + client_id.len()].copy_from_slice(client_id);
+                6 + client_id.len()
+            }
+            Message::Connack { rc } => {
+                buf[1] = CONNACK;
+                buf[2] = rc as u8;
+                3
+            }
+            Message::Register { topic_id, msg_id, topic_name } => {
+                buf[1] = REGISTER;
+                write_u16(&mut buf[2..4], topic_id);
+                write_u16(&mut buf[4..6], msg_id);
+                buf[6..6 + topic_name.len()].copy_from_slice(topic_name);
+                6 + topic_name.len()
+            }
+            Message::Regack { topic_id, msg_id, rc } => {
+                buf[1] = REGACK;
+                write_u16(&mut buf[2..4], topic_id);
+                write_u16(&mut buf[4..6], msg_id);
+                buf[6] = rc as u8;
+                7
+            }
+            Message::Publish { dup, qos, retain, topic_id, msg_id, data } => {
+                buf[1] = PUBLISH;
+                buf[2] = qos.flags();
+                if dup {
+                    buf[2] |= FLAG_DUP;
+                }
+                if retain {
+                    buf[2] |= FLAG_RETAIN;
+                }
+                write_u16(&mut buf[3..5], topic_id);
+                write_u16(&mut buf[5..7], msg_id);
+                buf[7..7 + data.len()].copy_from_slice(data);
+                7 + data.len()
+            }
+            Message::Puback { topic_id, msg_id, rc } => {
+                buf[1] = PUBACK;
+                write_u16(&mut buf[2..4], topic_id);
+                write_u16(&mut buf[4..6], msg_id);
+                buf[6] = rc as u8;
+                7
+            }
+            Message::Subscribe { dup, qos, msg_id, topic_name } => {
+                buf[1] = SUBSCRIBE;
+                buf[2] = qos.flags();
+                if dup {
+                    buf[2] |= FLAG_DUP;
+                }
+                write_u16(&mut buf[3..5], msg_id);
+                buf[5..5 + topic_name.len()].copy_from_slice(topic_name);
+                5 + topic_name.len()
+            }
+            Message::Suback { qos, topic_id, msg_id, rc } => {
+                buf[1] = SUBACK;
+                buf[2] = qos.flags();
+                write_u16(&mut buf[3..5], topic_id);
+                write_u16(&mut buf[5..7], msg_id);
+                buf[7] = rc as u8;
+                8
+            }
+            Message::Pingreq { client_id } => {
+                buf[1] = PINGREQ;
+                buf[2..2 + client_id.len()].copy_from_slice(client_id);
+                2 + client_id.len()
+            }
+            Message::Pingresp => {
+                buf[1] = PINGRESP;
+                2
+            }
+            Message::Disconnect { duration } => {
+                buf[1] = DISCONNECT;
+                match duration {
+                    Some(duration) => {
+                        write_u16(&mut buf[2..4], duration);
+                        4
+                    }
+                    None => 2,
+                }
+            }
+        };
+        buf[0] = len as u8;
+        &buf[0..len]
+    }
+
+    /// `None` for malformed or unsupported messages
+    pub fn decode(packet: &'p [u8]) -> Option<Self> {
+        if packet.len() < 2 || packet[0] as usize != packet.len() {
+            return None;
+        }
+        let len = packet.len();
+        let message = match packet[1] {
+            CONNECT if len >= 6 && packet[3] == PROTOCOL_ID => Message::Connect {
+                clean_session: packet[2] & FLAG_CLEAN_SESSION != 0,
+                duration: read_u16(&packet[4..6]),
+                client_id: &packet[6..],
+            },
+            CONNACK if len == 3 => Message::Connack {
+                rc: ReturnCode::from_u8(packet[2]),
+            },
+            REGISTER if len >= 6 => Message::Register {
+                topic_id: read_u16(&packet[2..4]),
+                msg_id: read_u16(&packet[4..6]),
+                topic_name: &packet[6..],
+            },
+            REGACK if len == 7 => Message::Regack {
+                topic_id: read_u16(&packet[2..4]),
+                msg_id: read_u16(&packet[4..6]),
+                rc: ReturnCode::from_u8(packet[6]),
+            },
+            PUBLISH if len >= 7 && packet[2] & FLAG_TOPIC_ID_TYPE == 0 => Message::Publish {
+                dup: packet[2] & FLAG_DUP != 0,
+                qos: QoS::from_flags(packet[2])?,
+                retain: packet[2] & FLAG_RETAIN != 0,
+                topic_id: read_u16(&packet[3..5]),
+                msg_id: read_u16(&packet[5..7]),
+                data: &packet[7..],
+            },
+            PUBACK if len == 7 => Message::Puback {
+                topic_id: read_u16(&packet[2..4]),
+                msg_id: read_u16(&packet[4..6]),
+                rc: ReturnCode::from_u8(packet[6]),
+            },
+            SUBSCRIBE if len >= 5 && packet[2] & FLAG_TOPIC_ID_TYPE == 0 => Message::Subscribe {
+                dup: packet[2] & FLAG_DUP != 0,
+                qos: QoS::from_flags(packet[2])?,
+                msg_id: read_u16(&packet[3..5]),
+                topic_name: &packet[5..],
+            },
+            SUBACK if len == 8 => Message::Suback {
+                qos: QoS::from_flags(packet[2])?,
+                topic_id: read_u16(&packet[3..5]),
+                msg_id: read_u16(&packet[5..7]),
+                rc: ReturnCode::from_u8(packet[7]),
+            },
+            PINGREQ => Message::Pingreq {
+                client_id: &packet[2..],
+            },
+            PINGRESP if len == 2 => Message::Pingresp,
+            DISCONNECT if len == 2 => Message::Disconnect { duration: None },
+            DISCONNECT if len == 4 => Message::Disconnect {
+                duration: Some(read_u16(&packet[2..4])),
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+/// Wrap `message` with the sender's `addr` into `buf`
+pub fn encapsulate<'b>(addr: &[u8], message: &Message, buf: &'b mut [u8]) -> &'b [u8] {
+    let header_len = 3 + addr.len();
+    buf[0] = header_len as u8;
+    buf[1] = ENCAPSULATED;
+    // Broadcast radius, unused
+    buf[2] = 0;
+    buf[3..header_len].copy_from_slice(addr);
+    let len = header_len + message.encode(&mut buf[header_len..]).len();
+    &buf[0..len]
+}
+
+/// The sender's address and the message, if `packet` is encapsulated
+pub fn decapsulate(packet: &[u8]) -> Option<(&[u8], Message<'_>)> {
+    if packet.len() < 3 || packet[1] != ENCAPSULATED {
+        return None;
+    }
+    let header_len = packet[0] as usize;
+    if !(3..=3 + MAX_ADDR_BYTES).contains(&header_len) || header_len > packet.len() {
+        return None;
+    }
+    let message = Message::decode(&packet[header_len..])?;
+    Some((&packet[3..header_len], message))
+}
+
+/// Why a client request could not be made
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MqttSnError {
+    /// Another request is waiting for its acknowledgement
+    Busy,
+    /// Not connected, or asleep
+    NotConnected,
+    /// Does not fit into a packet
+    TooLarge,
+}
+
+/// State of an `MqttSnClient`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClientState {
+    Disconnected,
+    Connecting,
+    Active,
+    /// Going to sleep, waiting for the gateway's DISCONNECT
+    Sleeping,
+    Asleep,
+    /// Woken up by `wake()`, receiving buffered messages until
+    /// PINGRESP
+    Awake,
+    /// The gateway did not answer after all retries
+    Lost,
+}
+
+/// What happened on `MqttSnClient::handle()`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ClientEvent<'p> {
+    /// Not for the client, or not expected
+    Ignored,
+    Connected,
+    ConnectRejected(ReturnCode),
+    Registered { msg_id: u16, topic_id: u16, rc: ReturnCode },
+    /// A QoS 1 publication has been acknowledged
+    Published { msg_id: u16, rc: ReturnCode },
+    Subscribed { msg_id: u16, topic_id: u16, qos: QoS, rc: ReturnCode },
+    /// The gateway has assigned an id to a topic name
+    TopicRegistered { topic_id: u16, topic_name: &'p [u8] },
+    /// A publication has arrived
+    Received { topic_id: u16, data: &'p [u8], qos: QoS, retain: bool },
+    /// The gateway has answered a PINGREQ
+    Pong,
+    /// The gateway has confirmed going to sleep, or after `wake()`
+    /// has delivered all buffered messages
+    Asleep,
+    Disconnected,
+}
+
+/// A request waiting for its acknowledgement
+struct Outstanding {
+    /// Expected acknowledgement
+    ack: u8,
+    msg_id: u16,
+    packet: [u8; MAX_PAYLOAD_BYTES],
+    len: usize,
+    /// Position of the flags for setting DUP on retransmit
+    flags_at: Option<usize>,
+    sent_at: u32,
+    retries: u8,
+}
+
+/// MQTT-SN client, see the module documentation
+///
+/// Every method producing a packet writes it into `buf`, which must
+/// hold 32 bytes. Send it to the gateway.
+pub struct MqttSnClient<'a> {
+    client_id: &'a [u8],
+    addr: &'a [u8],
+    keep_alive_s: u16,
+    state: ClientState,
+    next_msg_id: u16,
+    outstanding: Option<Outstanding>,
+    /// Acknowledgements owed to the gateway, oldest first
+    replies: [Option<Message<'static>>; MAX_PENDING_ACKS],
+    last_sent: u32,
+    retry_us: u32,
+    max_retries: u8,
+}
+
+impl<'a> MqttSnClient<'a> {
+    /// `addr` is the client's RX address, where the gateway sends to
+    pub fn new(client_id: &'a [u8], addr: &'a [u8], keep_alive_s: u16) -> Self {
+        assert!(client_id.len() <= MAX_CLIENT_ID_BYTES);
+        assert!(addr.len() <= MAX_ADDR_BYTES);
+        MqttSnClient {
+            client_id,
+            addr,
+            keep_alive_s,
+            state: ClientState::Disconnected,
+            next_msg_id: 1,
+            outstanding: None,
+            replies: [None; MAX_PENDING_ACKS],
+            last_sent: 0,
+            retry_us: 1_000_000,
+            max_retries: 3,
+        }
+    }
+
+    /// Retransmit unacknowledged requests after `retry_us`, up to
+    /// `max_retries` times. Defaults to 1 s and 3 times.
+    pub fn set_retries(&mut self, retry_us: u32, max_retries: u8) {
+        self.retry_us = retry_us;
+        self.max_retries = max_retries;
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    /// Is a request waiting for its acknowledgement?
+    pub fn is_busy(&self) -> bool {
+        self.outstanding.is_some()
+    }
+
+    fn msg_id(&mut self) -> u16 {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1).max(1);
+        msg_id
+    }
+
+    fn encode<'b>(&self, message: &Message, buf: &'b mut [u8]) -> Result<&'b [u8], MqttSnError> {
+        if 3 + self.addr.len() + message.encoded_len() > MAX_PAYLOAD_BYTES {
+            return Err(MqttSnError::TooLarge);
+        }
+        Ok(encapsulate(self.addr, message, buf))
+    }
+
+    /// Send a request that expects the acknowledgement `ack`
+    fn request<'b, C: Clock>(
+        &mut self,
+        message: &Message,
+        ack: u8,
+        msg_id: u16,
+        clock: &mut C,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], MqttSnError> {
+        if self.outstanding.is_some() {
+            return Err(MqttSnError::Busy);
+        }
+        let packet = self.encode(message, buf)?;
+
+        let mut outstanding = Outstanding {
+            ack,
+            msg_id,
+            packet: [0; MAX_PAYLOAD_BYTES],
+            len: packet.len(),
+            flags_at: match *message {
+                Message::Publish { .. } | Message::Subscribe { .. } =>
+                    Some(3 + self.addr.len() + 2),
+                _ => None,
+            },
+            sent_at: clock.now_us(),
+            retries: 0,
+        };
+        outstanding.packet[0..packet.len()].copy_from_slice(packet);
+        self.outstanding = Some(outstanding);
+        self.last_sent = clock.now_us();
+        Ok(packet)
+    }
+
+    fn check_active(&self) -> Result<(), MqttSnError> {
+        match self.state {
+            ClientState::Active => Ok(()),
+            _ => Err(MqttSnError::NotConnected),
+        }
+    }
+
+    pub fn connect<'b, C: Clock>(&mut self, clean_session: bool, clock: &mut C, buf: &'b mut [u8]) -> Result<&'b [u8], MqttSnError> {
+        let message = Message::Connect {
+            clean_session,
+            duration: self.keep_alive_s,
+            client_id: self.client_id,
+        };
+        let packet = self.request(&message, CONNACK, 0, clock, buf)?;
+        self.state = ClientState::Connecting;
+        Ok(packet)
+    }
+
+    /// Ask for a topic id for `topic_name`. Returns the message id.
+    pub fn register<'b, C: Clock>(&mut self, topic_name: &[u8], clock: &mut C, buf: &'b mut [u8]) -> Result<(u16, &'b [u8]), MqttSnError> {
+        self.check_active()?;
+        let msg_id = self.msg_id();
+        let message = Message::Register { topic_id: 0, msg_id, topic_name };
+        let packet = self.request(&message, REGACK, msg_id, clock, buf)?;
+        Ok((msg_id, packet))
+    }
+
+    /// Publish to a registered topic. Returns the message id, which
+    /// `ClientEvent::Published` reports for QoS 1.
+    pub fn publish<'b, C: Clock>(
+        &mut self,
+        topic_id: u16,
+        data: &[u8],
+        qos: QoS,
+        retain: bool,
+        clock: &mut C,
+        buf: &'b mut [u8],
+    ) -> Result<(u16, &'b [u8]), MqttSnError> {
+        self.check_active()?;
+        if qos == QoS::AtMostOnce {
+            let message = Message::Publish { dup: false, qos, retain, topic_id, msg_id: 0, data };
+            let packet = self.encode(&message, buf)?;
+            self.last_sent = clock.now_us();
+            return Ok((0, packet));
+        }
+
+        let msg_id = self.msg_id();
+        let message = Message::Publish { dup: false, qos, retain, topic_id, msg_id, data };
+        let packet = self.request(&message, PUBACK, msg_id, clock, buf)?;
+        Ok((msg_id, packet))
+    }
+
+    /// Subscribe to `topic_name`. Returns the message id.
+    pub fn subscribe<'b, C: Clock>(&mut self, topic_name: &[u8], qos: QoS, clock: &mut C, buf: &'b mut [u8]) -> Result<(u16, &'b [u8]), MqttSnError> {
+        self.check_active()?;
+        let msg_id = self.msg_id();
+        let message = Message::Subscribe { dup: false, qos, msg_id, topic_name };
+        let packet = self.request(&message, SUBACK, msg_id, clock, buf)?;
+        Ok((msg_id, packet))
+    }
+
+    /// Go to sleep for up to `duration_s`. The gateway buffers
+    /// publications meanwhile; fetch them with `wake()`.
+    pub fn sleep<'b, C: Clock>(&mut self, duration_s: u16, clock: &mut C, buf: &'b mut [u8]) -> Result<&'b [u8], MqttSnError> {
+        self.check_active()?;
+        let message = Message::Disconnect { duration: Some(duration_s) };
+        let packet = self.request(&message, DISCONNECT, 0, clock, buf)?;
+        self.state = ClientState::Sleeping;
+        Ok(packet)
+    }
+
+    /// While asleep, ask the gateway for the buffered publications.
+    /// Listen until `ClientEvent::Asleep`.
+    pub fn wake<'b, C: Clock>(&mut self, clock: &mut C, buf: &'b mut [u8]) -> Result<&'b [u8], MqttSnError> {
+        if self.state != ClientState::Asleep {
+            return Err(MqttSnError::NotConnected);
+        }
+        let message = Message::Pingreq { client_id: self.client_id };
+        let packet = self.request(&message, PINGRESP, 0, clock, buf)?;
+        self.state = ClientState::Awake;
+        Ok(packet)
+    }
+
+    /// Wake up for good and resume normal operation
+    pub fn reconnect<'b, C: Clock>(&mut self, clock: &mut C, buf: &'b mut [u8]) -> Result<&'b [u8], MqttSnError> {
+        self.outstanding = None;
+        self.connect(false, clock, buf)
+    }
+
+    pub fn disconnect<'b, C: Clock>(&mut self, clock: &mut C, buf: &'b mut [u8]) -> Result<&'b [u8], MqttSnError> {
+        self.outstanding = None;
+        let packet = self.encode(&Message::Disconnect { duration: None }, buf)?;
+        self.last_sent = clock.now_us();
+        self.state = ClientState::Disconnected;
+        Ok(packet)
+    }
+
+    /// Call regularly, and until it returns `None` after receiving.
+    /// Returns a packet to send if there is an acknowledgement owed,
+    /// a request to retransmit or a keep-alive PINGREQ due.
+    pub fn poll<'b, C: Clock>(&mut self, clock: &mut C, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        let now = clock.now_us();
+        if let Some(reply) = self.replies[0].take() {
+            self.replies.rotate_left(1);
+            self.last_sent = now;
+            return self.encode(&reply, buf).ok();
+        }
+
+        if let Some(ref mut outstanding) = self.outstanding {
+            if now.wrapping_sub(outstanding.sent_at) < self.retry_us {
+                return None;
+            }
+            if outstanding.retries < self.max_retries {
+                outstanding.retries += 1;
+                outstanding.sent_at = now;
+                if let Some(flags_at) = outstanding.flags_at {
+                    outstanding.packet[flags_at] |= FLAG_DUP;
+                }
+                let len = outstanding.len;
+                buf[0..len].copy_from_slice(&outstanding.packet[0..len]);
+                self.last_sent = now;
+                return Some(&buf[0..len]);
+            }
+        }
+        if self.outstanding.take().is_some() {
+            self.state = ClientState::Lost;
+            return None;
+        }
+
+        let keep_alive_us = (self.keep_alive_s as u32).saturating_mul(1_000_000);
+        if self.state == ClientState::Active &&
+            keep_alive_us > 0 &&
+            now.wrapping_sub(self.last_sent) >= keep_alive_us
+        {
+            let message = Message::Pingreq { client_id: &[] };
+            return self.request(&message, PINGRESP, 0, clock, buf).ok();
+        }
+        None
+    }
+
+    /// Owe `reply` to the gateway. When `MAX_PENDING_ACKS` are
+    /// owed already, it is dropped and the gateway retransmits.
+    fn reply(&mut self, reply: Message<'static>) {
+        if let Some(slot) = self.replies.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(reply);
+        }
+    }
+
+    /// Takes the acknowledgement for the outstanding request
+    fn acknowledge(&mut self, ack: u8, msg_id: u16) -> bool {
+        match self.outstanding {
+            Some(ref outstanding) if outstanding.ack == ack && outstanding.msg_id == msg_id => {
+                self.outstanding = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Process a packet received from the gateway
+    pub fn handle<'p>(&mut self, packet: &'p [u8]) -> ClientEvent<'p> {
+        let message = match Message::decode(packet) {
+            Some(message) => message,
+            None => return ClientEvent::Ignored,
+        };
+
+        match message {
+            Message::Connack { rc } if self.acknowledge(CONNACK, 0) => {
+                if rc == ReturnCode::Accepted {
+                    self.state = ClientState::Active;
+                    ClientEvent::Connected
+                } else {
+                    self.state = ClientState::Disconnected;
+                    ClientEvent::ConnectRejected(rc)
+                }
+            }
+            Message::Regack { topic_id, msg_id, rc } if self.acknowledge(REGACK, msg_id) =>
+                ClientEvent::Registered { msg_id, topic_id, rc },
+            Message::Puback { msg_id, rc, .. } if self.acknowledge(PUBACK, msg_id) =>
+                ClientEvent::Published { msg_id, rc },
+            Message::Suback { qos, topic_id, msg_id, rc } if self.acknowledge(SUBACK, msg_id) =>
+                ClientEvent::Subscribed { msg_id, topic_id, qos, rc },
+            Message::Pingresp if self.acknowledge(PINGRESP, 0) => {
+                if self.state == ClientState::Awake {
+                    self.state = ClientState::Asleep;
+                    ClientEvent::Asleep
+                } else {
+                    ClientEvent::Pong
+                }
+            }
+            Message::Disconnect { .. } => {
+                if self.state == ClientState::Sleeping && self.acknowledge(DISCONNECT, 0) {
+                    self.state = ClientState::Asleep;
+                    ClientEvent::Asleep
+                } else {
+                    self.outstanding = None;
+                    self.state = ClientState::Disconnected;
+                    ClientEvent::Disconnected
+                }
+            }
+            Message::Register { topic_id, msg_id, topic_name } => {
+                self.reply(Message::Regack { topic_id, msg_id, rc: ReturnCode::Accepted });
+                ClientEvent::TopicRegistered { topic_id, topic_name }
+            }
+            Message::Publish { qos, retain, topic_id, msg_id, data, .. } => {
+                if qos == QoS::AtLeastOnce {
+                    self.reply(Message::Puback { topic_id, msg_id, rc: ReturnCode::Accepted });
+                }
+                ClientEvent::Received { topic_id, data, qos, retain }
+            }
+            _ => ClientEvent::Ignored,
+        }
+    }
+}
+
+/// Connection of the gateway to an MQTT broker, on the host side
+pub trait BrokerConnection {
+    type Error;
+
+    /// Returns whether the broker accepted the client
+    fn connect(&mut self, client_id: &[u8], clean_session: bool, keep_alive_s: u16) -> Result<bool, Self::Error>;
+    fn disconnect(&mut self, client_id: &[u8]) -> Result<(), Self::Error>;
+    fn publish(&mut self, client_id: &[u8], topic_name: &[u8], data: &[u8], qos: QoS, retain: bool) -> Result<(), Self::Error>;
+    /// Returns the granted QoS, or `None` if refused
+    fn subscribe(&mut self, client_id: &[u8], topic_name: &[u8], qos: QoS) -> Result<Option<QoS>, Self::Error>;
+}
+
+/// Slot of the gateway's client table
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct GatewayClient {
+    pipe: u8,
+    addr: [u8; MAX_ADDR_BYTES],
+    addr_len: u8,
+    client_id: [u8; MAX_CLIENT_ID_BYTES],
+    client_id_len: u8,
+    /// Keep-alive or sleep duration
+    duration_s: u16,
+    asleep: bool,
+    last_seen: u32,
+}
+
+impl GatewayClient {
+    pub fn pipe(&self) -> u8 {
+        self.pipe
+    }
+
+    pub fn addr(&self) -> &[u8] {
+        &self.addr[0..self.addr_len as usize]
+    }
+
+    pub fn client_id(&self) -> &[u8] {
+        &self.client_id[0..self.client_id_len as usize]
+    }
+
+    pub fn is_asleep(&self) -> bool {
+        self.asleep
+    }
+}
+
+/// Slot of the gateway's topic table. Topic ids are the index plus
+/// one.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct GatewayTopic {
+    name: [u8; MAX_TOPIC_NAME_BYTES],
+    len: u8,
+}
+
+impl GatewayTopic {
+    pub fn name(&self) -> &[u8] {
+        &self.name[0..self.len as usize]
+    }
+}
+
+/// Slot of the gateway's subscription table
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct GatewaySubscription {
+    /// Index into the client table
+    client: u8,
+    topic_id: u16,
+    qos: QoS,
+}
+
+/// Slot of the gateway's outbox
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Outgoing {
+    /// Index into the client table
+    client: u8,
+    addr: [u8; MAX_ADDR_BYTES],
+    addr_len: u8,
+    packet: [u8; MAX_PAYLOAD_BYTES],
+    len: u8,
+    /// Buffered for a sleeping client
+    held: bool,
+    /// Order of queueing
+    seq: u32,
+    /// Message id of a QoS 1 publication, which stays in the outbox
+    /// until the client's PUBACK
+    msg_id: u16,
+    /// Last sent, while waiting for the PUBACK
+    sent_at: Option<u32>,
+    retries: u8,
+}
+
+impl Outgoing {
+    /// Destination address
+    pub fn addr(&self) -> &[u8] {
+        &self.addr[0..self.addr_len as usize]
+    }
+
+    pub fn packet(&self) -> &[u8] {
+        &self.packet[0..self.len as usize]
+    }
+
+    /// Not waiting for a PUBACK, or for longer than `retry_us`
+    fn is_due(&self, now: u32, retry_us: u32) -> bool {
+        match self.sent_at {
+            Some(sent_at) => now.wrapping_sub(sent_at) >= retry_us,
+            None => true,
+        }
+    }
+}
+
+/// Gateway endpoint, see the module documentation
+///
+/// Keeps its state in caller-provided tables. Replies and
+/// publications for the clients are queued in the outbox; send them
+/// with `send_next()` or `pop_outgoing()`. QoS 1 publications are
+/// retransmitted until the client acknowledges them.
+pub struct MqttSnGateway<'a, B: BrokerConnection> {
+    broker: B,
+    clients: &'a mut [Option<GatewayClient>],
+    topics: &'a mut [Option<GatewayTopic>],
+    subscriptions: &'a mut [Option<GatewaySubscription>],
+    outbox: &'a mut [Option<Outgoing>],
+    next_msg_id: u16,
+    next_seq: u32,
+    retry_us: u32,
+    max_retries: u8,
+}
+
+impl<'a, B: BrokerConnection> MqttSnGateway<'a, B> {
+    pub fn new(
+        broker: B,
+        clients: &'a mut [Option<GatewayClient>],
+        topics: &'a mut [Option<GatewayTopic>],
+        subscriptions: &'a mut [Option<GatewaySubscription>],
+        outbox: &'a mut [Option<Outgoing>],
+    ) -> Self {
+        MqttSnGateway {
+            broker,
+            clients,
+            topics,
+            subscriptions,
+            outbox,
+            next_msg_id: 1,
+            next_seq: 0,
+            retry_us: 1_000_000,
+            max_retries: 3,
+        }
+    }
+
+    /// Retransmit unacknowledged QoS 1 publications after `retry_us`,
+    /// up to `max_retries` times. Defaults to 1 s and 3 times.
+    pub fn set_retries(&mut self, retry_us: u32, max_retries: u8) {
+        self.retry_us = retry_us;
+        self.max_retries = max_retries;
+    }
+
+    pub fn broker(&mut self) -> &mut B {
+        &mut self.broker
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &GatewayClient> {
+        self.clients.iter().filter_map(|slot| slot.as_ref())
+    }
+
+    fn find_client(&self, pipe: u8, addr: &[u8]) -> Option<usize> {
+        self.clients.iter().position(|slot| {
+            matches!(*slot, Some(ref client) if client.pipe == pipe && client.addr() == addr)
+        })
+    }
+
+    fn topic_id(&self, topic_name: &[u8]) -> Option<u16> {
+        self.topics.iter()
+            .position(|slot| matches!(*slot, Some(ref topic) if topic.name() == topic_name))
+            .map(|i| i as u16 + 1)
+    }
+
+    /// Find or assign the id of `topic_name`
+    fn register_topic(&mut self, topic_name: &[u8]) -> Option<u16> {
+        if topic_name.is_empty() || topic_name.len() > MAX_TOPIC_NAME_BYTES {
+            return None;
+        }
+        if let Some(topic_id) = self.topic_id(topic_name) {
+            return Some(topic_id);
+        }
+        let i = self.topics.iter().position(|slot| slot.is_none())?;
+        let mut topic = GatewayTopic {
+            name: [0; MAX_TOPIC_NAME_BYTES],
+            len: topic_name.len() as u8,
+        };
+        topic.name[0..topic_name.len()].copy_from_slice(topic_name);
+        self.topics[i] = Some(topic);
+        Some(i as u16 + 1)
+    }
+
+    fn topic_name(&self, topic_id: u16) -> Option<GatewayTopic> {
+        let i = (topic_id as usize).checked_sub(1)?;
+        self.topics.get(i).and_then(|slot| *slot)
+    }
+
+    /// Queue `message` for client `client`. Returns `false` if the
+    /// outbox is full or the message does not fit into a packet.
+    fn queue(&mut self, client: usize, message: &Message, held: bool) -> bool {
+        if message.encoded_len() > MAX_PAYLOAD_BYTES {
+            return false;
+        }
+        let target = match self.clients[client] {
+            Some(target) => target,
+            None => return false,
+        };
+        let slot = match self.outbox.iter().position(|slot| slot.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        let mut outgoing = Outgoing {
+            client: client as u8,
+            addr: target.addr,
+            addr_len: target.addr_len,
+            packet: [0; MAX_PAYLOAD_BYTES],
+            len: 0,
+            held,
+            seq: self.next_seq,
+            msg_id: match *message {
+                Message::Publish { qos: QoS::AtLeastOnce, msg_id, .. } => msg_id,
+                _ => 0,
+            },
+            sent_at: None,
+            retries: 0,
+        };
+        outgoing.len = message.encode(&mut outgoing.packet).len() as u8;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.outbox[slot] = Some(outgoing);
+        true
+    }
+
+    /// Process a packet received on `pipe`
+    pub fn handle<C: Clock>(&mut self, pipe: u8, packet: &[u8], clock: &mut C) -> Result<(), B::Error> {
+        let (addr, message) = match decapsulate(packet) {
+            Some(decapsulated) => decapsulated,
+            None => return Ok(()),
+        };
+        let now = clock.now_us();
+
+        if let Message::Connect { clean_session, duration, client_id } = message {
+            return self.connect(pipe, addr, clean_session, duration, client_id, now);
+        }
+        let client = match self.find_client(pipe, addr) {
+            Some(client) => client,
+            // Also when a sleeping client sends PINGREQ after the
+            // gateway has forgotten it; it has to reconnect.
+            None => return Ok(()),
+        };
+        if let Some(ref mut entry) = self.clients[client] {
+            entry.last_seen = now;
+        }
+        let client_id = self.clients[client].map(|entry| entry.client_id).unwrap_or_default();
+        let client_id_len = self.clients[client].map_or(0, |entry| entry.client_id_len as usize);
+        let client_id = &client_id[0..client_id_len];
+
+        match message {
+            Message::Register { msg_id, topic_name, .. } => {
+                let reply = match self.register_topic(topic_name) {
+                    Some(topic_id) => Message::Regack { topic_id, msg_id, rc: ReturnCode::Accepted },
+                    None => Message::Regack { topic_id: 0, msg_id, rc: ReturnCode::Congestion },
+                };
+                self.queue(client, &reply, false);
+            }
+            Message::Publish { qos, retain, topic_id, msg_id, data, .. } => {
+                let rc = match self.topic_name(topic_id) {
+                    Some(topic) => {
+                        self.broker.publish(client_id, topic.name(), data, qos, retain)?;
+                        ReturnCode::Accepted
+                    }
+                    None => ReturnCode::InvalidTopicId,
+                };
+                if qos == QoS::AtLeastOnce || rc != ReturnCode::Accepted {
+                    self.queue(client, &Message::Puback { topic_id, msg_id, rc }, false);
+                }
+            }
+            Message::Puback { msg_id, .. } if msg_id != 0 => {
+                // Also a refusal ends the retransmits
+                for slot in self.outbox.iter_mut() {
+                    if matches!(*slot, Some(ref outgoing) if outgoing.client as usize == client && outgoing.msg_id == msg_id) {
+                        *slot = None;
+                    }
+                }
+            }
+            Message::Subscribe { qos, msg_id, topic_name, .. } => {
+                let reply = self.subscribe(client, client_id, topic_name, qos, msg_id)?;
+                self.queue(client, &reply, false);
+            }
+            Message::Pingreq { .. } => {
+                // Release what has been buffered for a sleeping client
+                for outgoing in self.outbox.iter_mut().filter_map(|slot| slot.as_mut()) {
+                    if outgoing.client as usize == client {
+                        outgoing.held = false;
+                    }
+                }
+                self.queue(client, &Message::Pingresp, false);
+            }
+            Message::Disconnect { duration: Some(duration) } => {
+                if let Some(ref mut entry) = self.clients[client] {
+                    entry.asleep = true;
+                    entry.duration_s = duration;
+                }
+                // Retransmit unacknowledged publications after waking
+                for outgoing in self.outbox.iter_mut().filter_map(|slot| slot.as_mut()) {
+                    if outgoing.client as usize == client && outgoing.sent_at.is_some() {
+                        outgoing.held = true;
+                        outgoing.retries = 0;
+                    }
+                }
+                self.queue(client, &Message::Disconnect { duration: None }, false);
+            }
+            Message::Disconnect { duration: None } => {
+                self.queue(client, &Message::Disconnect { duration: None }, false);
+                self.remove_client(client)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self, pipe: u8, addr: &[u8], clean_session: bool, duration: u16, client_id: &[u8], now: u32) -> Result<(), B::Error> {
+        if client_id.len() > MAX_CLIENT_ID_BYTES || addr.len() > MAX_ADDR_BYTES {
+            return Ok(());
+        }
+
+        // A client reconnecting from elsewhere replaces its old entry
+        let existing = self.clients.iter().position(|slot| {
+            matches!(*slot, Some(ref client) if client.client_id() == client_id)
+        }).or_else(|| self.find_client(pipe, addr));
+        let slot = match existing.or_else(|| self.clients.iter().position(|slot| slot.is_none())) {
+            Some(slot) => slot,
+            None => {
+                // Reply without a table entry
+                let mut reply = [0; MAX_PAYLOAD_BYTES];
+                let len = Message::Connack { rc: ReturnCode::Congestion }.encode(&mut reply).len();
+                return self.queue_raw(addr, &reply[0..len]);
+            }
+        };
+
+        let mut entry = GatewayClient {
+            pipe,
+            addr: [0; MAX_ADDR_BYTES],
+            addr_len: addr.len() as u8,
+            client_id: [0; MAX_CLIENT_ID_BYTES],
+            client_id_len: client_id.len() as u8,
+            duration_s: duration,
+            asleep: false,
+            last_seen: now,
+        };
+        entry.addr[0..addr.len()].copy_from_slice(addr);
+        entry.client_id[0..client_id.len()].copy_from_slice(client_id);
+        let old_addr = self.clients[slot].map(|client| client.addr);
+        self.clients[slot] = Some(entry);
+        // Redirect what is still queued for the old address
+        if old_addr.is_some() {
+            for outgoing in self.outbox.iter_mut().filter_map(|slot| slot.as_mut()) {
+                if outgoing.client as usize == slot {
+                    outgoing.addr = entry.addr;
+                    outgoing.addr_len = entry.addr_len;
+                    outgoing.held = false;
+                }
+            }
+        }
+        if clean_session {
+            self.remove_subscriptions(slot);
+        }
+
+        let accepted = self.broker.connect(client_id, clean_session, duration)?;
+        let rc = if accepted {
+            ReturnCode::Accepted
+        } else {
+            self.clients[slot] = None;
+            ReturnCode::NotSupported
+        };
+        let mut reply = [0; MAX_PAYLOAD_BYTES];
+        let len = Message::Connack { rc }.encode(&mut reply).len();
+        self.queue_raw(addr, &reply[0..len])
+    }
+
+    /// Queue a packet for an address without client entry
+    fn queue_raw(&mut self, addr: &[u8], packet: &[u8]) -> Result<(), B::Error> {
+        if let Some(slot) = self.outbox.iter().position(|slot| slot.is_none()) {
+            let mut outgoing = Outgoing {
+                client: u8::MAX,
+                addr: [0; MAX_ADDR_BYTES],
+                addr_len: addr.len() as u8,
+                packet: [0; MAX_PAYLOAD_BYTES],
+                len: packet.len() as u8,
+                held: false,
+                seq: self.next_seq,
+                msg_id: 0,
+                sent_at: None,
+                retries: 0,
+            };
+            outgoing.addr[0..addr.len()].copy_from_slice(addr);
+            outgoing.packet[0..packet.len()].copy_from_slice(packet);
+            self.next_seq = self.next_seq.wrapping_add(1);
+            self.outbox[slot] = Some(outgoing);
+        }
+        Ok(())
+    }
+
+    fn subscribe(&mut self, client: usize, client_id: &[u8], topic_name: &[u8], qos: QoS, msg_id: u16) -> Result<Message<'static>, B::Error> {
+        let refuse = |rc| Message::Suback { qos, topic_id: 0, msg_id, rc };
+        if topic_name.iter().any(|c| *c == b'+' || *c == b'#') {
+            return Ok(refuse(ReturnCode::NotSupported));
+        }
+        let topic_id = match self.register_topic(topic_name) {
+            Some(topic_id) => topic_id,
+            None => return Ok(refuse(ReturnCode::Congestion)),
+        };
+        let granted = match self.broker.subscribe(client_id, topic_name, qos)? {
+            Some(granted) => granted,
+            None => return Ok(refuse(ReturnCode::NotSupported)),
+        };
+
+        let subscription = GatewaySubscription {
+            client: client as u8,
+            topic_id,
+            qos: granted,
+        };
+        let existing = self.subscriptions.iter().position(|slot| {
+            matches!(*slot, Some(ref s) if s.client as usize == client && s.topic_id == topic_id)
+        });
+        match existing.or_else(|| self.subscriptions.iter().position(|slot| slot.is_none())) {
+            Some(slot) => self.subscriptions[slot] = Some(subscription),
+            None => return Ok(refuse(ReturnCode::Congestion)),
+        }
+        Ok(Message::Suback { qos: granted, topic_id, msg_id, rc: ReturnCode::Accepted })
+    }
+
+    fn remove_subscriptions(&mut self, client: usize) {
+        for slot in self.subscriptions.iter_mut() {
+            if matches!(*slot, Some(ref s) if s.client as usize == client) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn remove_client(&mut self, client: usize) -> Result<(), B::Error> {
+        let entry = match self.clients[client].take() {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        self.remove_subscriptions(client);
+        for slot in self.outbox.iter_mut() {
+            if matches!(*slot, Some(ref outgoing) if outgoing.client as usize == client && (outgoing.held || outgoing.sent_at.is_some())) {
+                *slot = None;
+            }
+        }
+        self.broker.disconnect(entry.client_id())
+    }
+
+    /// Pass a publication from the broker on to the subscribed
+    /// clients. Those asleep get it when they wake up. Returns the
+    /// number of clients it has been queued for, which excludes all
+    /// if it does not fit into a packet.
+    pub fn deliver(&mut self, topic_name: &[u8], data: &[u8], retain: bool) -> usize {
+        let topic_id = match self.topic_id(topic_name) {
+            Some(topic_id) => topic_id,
+            None => return 0,
+        };
+
+        let mut queued = 0;
+        for i in 0..self.subscriptions.len() {
+            let subscription = match self.subscriptions[i] {
+                Some(subscription) if subscription.topic_id == topic_id => subscription,
+                _ => continue,
+            };
+            let client = subscription.client as usize;
+            let asleep = matches!(self.clients[client], Some(entry) if entry.asleep);
+            let msg_id = match subscription.qos {
+                QoS::AtMostOnce => 0,
+                QoS::AtLeastOnce => {
+                    let msg_id = self.next_msg_id;
+                    self.next_msg_id = self.next_msg_id.wrapping_add(1).max(1);
+                    msg_id
+                }
+            };
+            let message = Message::Publish {
+                dup: false,
+                qos: subscription.qos,
+                retain,
+                topic_id,
+                msg_id,
+                data,
+            };
+            if self.queue(client, &message, asleep) {
+                queued += 1;
+            }
+        }
+        queued
+    }
+
+    /// Take the oldest packet that is ready to be sent
+    ///
+    /// QoS 1 publications stay in the outbox until the client's
+    /// PUBACK. They are returned again with the DUP flag after the
+    /// retry interval, and dropped after all retries.
+    pub fn pop_outgoing<C: Clock>(&mut self, clock: &mut C) -> Option<Outgoing> {
+        let now = clock.now_us();
+        let retry_us = self.retry_us;
+        let max_retries = self.max_retries;
+        for slot in self.outbox.iter_mut() {
+            if matches!(*slot, Some(ref outgoing) if !outgoing.held && outgoing.sent_at.is_some() &&
+                        outgoing.retries >= max_retries && outgoing.is_due(now, retry_us))
+            {
+                *slot = None;
+            }
+        }
+
+        let next_seq = self.next_seq;
+        let i = self.outbox.iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.as_ref().map(|outgoing| (i, outgoing)))
+            .filter(|&(_, outgoing)| !outgoing.held && outgoing.is_due(now, retry_us))
+            .min_by_key(|&(_, outgoing)| outgoing.seq.wrapping_sub(next_seq))
+            .map(|(i, _)| i)?;
+        let outgoing = match self.outbox[i] {
+            Some(ref mut outgoing) if outgoing.msg_id != 0 => outgoing,
+            _ => return self.outbox[i].take(),
+        };
+        if outgoing.sent_at.is_some() {
+            outgoing.retries += 1;
+            outgoing.packet[2] |= FLAG_DUP;
+        }
+        outgoing.sent_at = Some(now);
+        Some(*outgoing)
+    }
+
+    /// Send the oldest ready packet to its client. Returns `None` if
+    /// there is nothing to send, otherwise whether it was
+    /// acknowledged. Changes the TX address.
+    pub fn send_next<D: Device, C: Clock>(&mut self, tx: &mut TxMode<D>, clock: &mut C) -> Result<Option<bool>, D::Error> {
+        let outgoing = match self.pop_outgoing(clock) {
+            Some(outgoing) => outgoing,
+            None => return Ok(None),
+        };
+        tx.set_tx_addr(outgoing.addr())?;
+        tx.send_sync(outgoing.packet()).map(Some)
+    }
+
+    /// Drop clients whose keep-alive or sleep duration has passed by
+    /// half again, and disconnect them from the broker. Returns the
+    /// number of clients dropped.
+    pub fn expire<C: Clock>(&mut self, clock: &mut C) -> Result<usize, B::Error> {
+        let now = clock.now_us();
+        let mut expired = 0;
+        for client in 0..self.clients.len() {
+            let timed_out = match self.clients[client] {
+                Some(entry) if entry.duration_s > 0 => {
+                    let timeout_us = (entry.duration_s as u32)
+                        .saturating_mul(1_500_000);
+                    now.wrapping_sub(entry.last_seen) >= timeout_us
+                }
+                _ => false,
+            };
+            if timed_out {
+                self.remove_client(client)?;
+                expired += 1;
+            }
+        }
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use mock::TestClock;
+
+    const ADDR: &[u8] = b"node1";
+
+    fn encode(message: Message) -> Vec<u8> {
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        message.encode(&mut buf).to_vec()
+    }
+
+    /// Decapsulated message from a client packet
+    fn sent(packet: Option<&[u8]>) -> Message<'_> {
+        let (addr, message) = decapsulate(packet.unwrap()).unwrap();
+        assert_eq!(addr, ADDR);
+        message
+    }
+
+    fn connected(clock: &mut TestClock) -> MqttSnClient<'static> {
+        let mut client = MqttSnClient::new(b"client", ADDR, 0);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        client.connect(true, clock, &mut buf).unwrap();
+        let connack = encode(Message::Connack { rc: ReturnCode::Accepted });
+        assert_eq!(client.handle(&connack), ClientEvent::Connected);
+        client
+    }
+
+    #[derive(Default)]
+    struct TestBroker {
+        refuse: bool,
+        published: Vec<(Vec<u8>, Vec<u8>, QoS)>,
+        subscribed: Vec<Vec<u8>>,
+        disconnected: usize,
+    }
+
+    impl BrokerConnection for TestBroker {
+        type Error = ();
+
+        fn connect(&mut self, _client_id: &[u8], _clean_session: bool, _keep_alive_s: u16) -> Result<bool, ()> {
+            Ok(!self.refuse)
+        }
+
+        fn disconnect(&mut self, _client_id: &[u8]) -> Result<(), ()> {
+            self.disconnected += 1;
+            Ok(())
+        }
+
+        fn publish(&mut self, _client_id: &[u8], topic_name: &[u8], data: &[u8], qos: QoS, _retain: bool) -> Result<(), ()> {
+            self.published.push((topic_name.to_vec(), data.to_vec(), qos));
+            Ok(())
+        }
+
+        fn subscribe(&mut self, _client_id: &[u8], topic_name: &[u8], qos: QoS) -> Result<Option<QoS>, ()> {
+            self.subscribed.push(topic_name.to_vec());
+            Ok(Some(qos))
+        }
+    }
+
+    /// Messages in the gateway's outbox, in order
+    fn outgoing<B: BrokerConnection>(gateway: &mut MqttSnGateway<B>, clock: &mut TestClock) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        while let Some(outgoing) = gateway.pop_outgoing(clock) {
+            assert_eq!(outgoing.addr(), ADDR);
+            packets.push(outgoing.packet().to_vec());
+            if packets.len() > 8 {
+                break;
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let messages = [
+            Message::Connect { clean_session: true, duration: 60, client_id: b"client" },
+            Message::Connack { rc: ReturnCode::Congestion },
+            Message::Register { topic_id: 0, msg_id: 0x1234, topic_name: b"a/b" },
+            Message::Regack { topic_id: 7, msg_id: 0x1234, rc: ReturnCode::Accepted },
+            Message::Publish { dup: true, qos: QoS::AtLeastOnce, retain: true, topic_id: 0x0102, msg_id: 3, data: b"data" },
+            Message::Publish { dup: false, qos: QoS::AtMostOnce, retain: false, topic_id: 1, msg_id: 0, data: b"" },
+            Message::Puback { topic_id: 1, msg_id: 2, rc: ReturnCode::InvalidTopicId },
+            Message::Subscribe { dup: false, qos: QoS::AtLeastOnce, msg_id: 9, topic_name: b"t" },
+            Message::Suback { qos: QoS::AtMostOnce, topic_id: 4, msg_id: 9, rc: ReturnCode::NotSupported },
+            Message::Pingreq { client_id: b"" },
+            Message::Pingreq { client_id: b"client" },
+            Message::Pingresp,
+            Message::Disconnect { duration: None },
+            Message::Disconnect { duration: Some(600) },
+        ];
+        for message in messages.iter() {
+            let packet = encode(*message);
+            assert_eq!(packet.len(), message.encoded_len());
+            assert_eq!(Message::decode(&packet), Some(*message));
+
+            let mut buf = [0; MAX_PAYLOAD_BYTES + 8];
+            let packet = encapsulate(ADDR, message, &mut buf);
+            assert_eq!(decapsulate(packet), Some((ADDR, *message)));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_malformed() {
+        // Length mismatch
+        assert_eq!(Message::decode(&[3, PINGRESP]), None);
+        assert_eq!(Message::decode(&[2]), None);
+        // QoS 2
+        assert_eq!(Message::decode(&[7, PUBLISH, 0x40, 0, 1, 0, 1]), None);
+        // Predefined topic id
+        assert_eq!(Message::decode(&[7, PUBLISH, 0x01, 0, 1, 0, 1]), None);
+        assert_eq!(Message::decode(&[2, 0x42]), None);
+        // Not encapsulated, or address too long
+        assert_eq!(decapsulate(&[2, PINGRESP]), None);
+        assert_eq!(decapsulate(&[10, ENCAPSULATED, 0, 1, 2, 3, 4, 5, 6, 7, 2, PINGRESP]), None);
+    }
+
+    #[test]
+    fn client_requests() {
+        let mut clock = TestClock::new(0);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let mut client = MqttSnClient::new(b"client", ADDR, 0);
+        assert_eq!(client.register(b"t", &mut clock, &mut buf).err(), Some(MqttSnError::NotConnected));
+
+        let packet = client.connect(true, &mut clock, &mut buf).ok();
+        assert_eq!(sent(packet), Message::Connect { clean_session: true, duration: 0, client_id: b"client" });
+        assert_eq!(client.state(), ClientState::Connecting);
+        let connack = encode(Message::Connack { rc: ReturnCode::Accepted });
+        assert_eq!(client.handle(&connack), ClientEvent::Connected);
+        assert_eq!(client.state(), ClientState::Active);
+
+        let (msg_id, _) = client.register(b"t", &mut clock, &mut buf).unwrap();
+        assert!(client.is_busy());
+        assert_eq!(client.subscribe(b"u", QoS::AtMostOnce, &mut clock, &mut buf).err(), Some(MqttSnError::Busy));
+        // Not the expected message id
+        let regack = encode(Message::Regack { topic_id: 5, msg_id: msg_id + 1, rc: ReturnCode::Accepted });
+        assert_eq!(client.handle(&regack), ClientEvent::Ignored);
+        let regack = encode(Message::Regack { topic_id: 5, msg_id, rc: ReturnCode::Accepted });
+        assert_eq!(client.handle(&regack), ClientEvent::Registered { msg_id, topic_id: 5, rc: ReturnCode::Accepted });
+        assert!(!client.is_busy());
+
+        // QoS 0 needs no acknowledgement
+        let (msg_id, packet) = client.publish(5, b"x", QoS::AtMostOnce, false, &mut clock, &mut buf).unwrap();
+        assert_eq!(msg_id, 0);
+        assert!(matches!(sent(Some(packet)), Message::Publish { qos: QoS::AtMostOnce, data: b"x", .. }));
+        assert!(!client.is_busy());
+        let data = [0; 20];
+        assert_eq!(client.publish(5, &data, QoS::AtMostOnce, false, &mut clock, &mut buf).err(), Some(MqttSnError::TooLarge));
+    }
+
+    #[test]
+    fn client_retransmits() {
+        let mut clock = TestClock::new(0);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let mut client = connected(&mut clock);
+        client.set_retries(1_000, 2);
+
+        let (msg_id, _) = client.publish(5, b"x", QoS::AtLeastOnce, false, &mut clock, &mut buf).unwrap();
+        clock.now = 999;
+        assert_eq!(client.poll(&mut clock, &mut buf), None);
+        for _ in 0..2 {
+            clock.now += 1_000;
+            let packet = client.poll(&mut clock, &mut buf);
+            assert!(matches!(sent(packet), Message::Publish { dup: true, msg_id: id, .. } if id == msg_id));
+        }
+        clock.now += 1_000;
+        assert_eq!(client.poll(&mut clock, &mut buf), None);
+        assert_eq!(client.state(), ClientState::Lost);
+        assert!(!client.is_busy());
+    }
+
+    #[test]
+    fn client_queues_acks() {
+        let mut clock = TestClock::new(0);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let mut client = connected(&mut clock);
+
+        for msg_id in 1..=MAX_PENDING_ACKS as u16 {
+            let publish = encode(Message::Publish { dup: false, qos: QoS::AtLeastOnce, retain: false, topic_id: 3, msg_id, data: b"x" });
+            assert_eq!(client.handle(&publish), ClientEvent::Received { topic_id: 3, data: b"x", qos: QoS::AtLeastOnce, retain: false });
+        }
+        // No room, left to the gateway's retransmission
+        let register = encode(Message::Register { topic_id: 4, msg_id: 9, topic_name: b"t" });
+        assert_eq!(client.handle(&register), ClientEvent::TopicRegistered { topic_id: 4, topic_name: b"t" });
+
+        for msg_id in 1..=MAX_PENDING_ACKS as u16 {
+            let packet = client.poll(&mut clock, &mut buf);
+            assert_eq!(sent(packet), Message::Puback { topic_id: 3, msg_id, rc: ReturnCode::Accepted });
+        }
+        assert_eq!(client.poll(&mut clock, &mut buf), None);
+
+        assert!(matches!(client.handle(&register), ClientEvent::TopicRegistered { .. }));
+        let packet = client.poll(&mut clock, &mut buf);
+        assert_eq!(sent(packet), Message::Regack { topic_id: 4, msg_id: 9, rc: ReturnCode::Accepted });
+    }
+
+    #[test]
+    fn client_keep_alive() {
+        let mut clock = TestClock::new(0);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let mut client = MqttSnClient::new(b"client", ADDR, 1);
+        client.connect(false, &mut clock, &mut buf).unwrap();
+        client.handle(&encode(Message::Connack { rc: ReturnCode::Accepted }));
+
+        clock.now = 999_999;
+        assert_eq!(client.poll(&mut clock, &mut buf), None);
+        clock.now = 1_000_000;
+        assert_eq!(sent(client.poll(&mut clock, &mut buf)), Message::Pingreq { client_id: b"" });
+        assert_eq!(client.handle(&encode(Message::Pingresp)), ClientEvent::Pong);
+        assert!(!client.is_busy());
+    }
+
+    #[test]
+    fn client_sleeps() {
+        let mut clock = TestClock::new(0);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let mut client = connected(&mut clock);
+
+        let packet = client.sleep(600, &mut clock, &mut buf).ok();
+        assert_eq!(sent(packet), Message::Disconnect { duration: Some(600) });
+        assert_eq!(client.state(), ClientState::Sleeping);
+        assert_eq!(client.handle(&encode(Message::Disconnect { duration: None })), ClientEvent::Asleep);
+        assert_eq!(client.state(), ClientState::Asleep);
+
+        let packet = client.wake(&mut clock, &mut buf).ok();
+        assert_eq!(sent(packet), Message::Pingreq { client_id: b"client" });
+        let publish = encode(Message::Publish { dup: false, qos: QoS::AtMostOnce, retain: false, topic_id: 3, msg_id: 0, data: b"x" });
+        assert!(matches!(client.handle(&publish), ClientEvent::Received { .. }));
+        assert_eq!(client.handle(&encode(Message::Pingresp)), ClientEvent::Asleep);
+        assert_eq!(client.state(), ClientState::Asleep);
+
+        // Unsolicited DISCONNECT
+        client.reconnect(&mut clock, &mut buf).unwrap();
+        client.handle(&encode(Message::Connack { rc: ReturnCode::Accepted }));
+        assert_eq!(client.handle(&encode(Message::Disconnect { duration: None })), ClientEvent::Disconnected);
+        assert_eq!(client.state(), ClientState::Disconnected);
+    }
+
+    #[test]
+    fn gateway() {
+        let mut clock = TestClock::new(0);
+        let (mut clients, mut topics, mut subscriptions, mut outbox) = ([None; 2], [None; 2], [None; 2], [None; 4]);
+        let mut gateway = MqttSnGateway::new(TestBroker::default(), &mut clients, &mut topics, &mut subscriptions, &mut outbox);
+        let mut client = MqttSnClient::new(b"client", ADDR, 60);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+
+        let packet = client.connect(true, &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        assert_eq!(gateway.clients().count(), 1);
+        for packet in outgoing(&mut gateway, &mut clock) {
+            assert_eq!(client.handle(&packet), ClientEvent::Connected);
+        }
+
+        let (_, packet) = client.register(b"temp", &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        let packets = outgoing(&mut gateway, &mut clock);
+        let topic_id = match client.handle(&packets[0]) {
+            ClientEvent::Registered { topic_id, rc: ReturnCode::Accepted, .. } => topic_id,
+            event => panic!("{:?}", event),
+        };
+
+        let (msg_id, packet) = client.publish(topic_id, b"21", QoS::AtLeastOnce, false, &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        assert_eq!(gateway.broker().published, vec![(b"temp".to_vec(), b"21".to_vec(), QoS::AtLeastOnce)]);
+        let packets = outgoing(&mut gateway, &mut clock);
+        assert_eq!(client.handle(&packets[0]), ClientEvent::Published { msg_id, rc: ReturnCode::Accepted });
+
+        // Unknown topic id
+        let (_, packet) = client.publish(topic_id + 1, b"", QoS::AtMostOnce, false, &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        let packets = outgoing(&mut gateway, &mut clock);
+        assert!(matches!(Message::decode(&packets[0]), Some(Message::Puback { rc: ReturnCode::InvalidTopicId, .. })));
+
+        // Wildcards are refused
+        let (_, packet) = client.subscribe(b"a/#", QoS::AtMostOnce, &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        let packets = outgoing(&mut gateway, &mut clock);
+        assert!(matches!(client.handle(&packets[0]), ClientEvent::Subscribed { rc: ReturnCode::NotSupported, .. }));
+        let (msg_id, packet) = client.subscribe(b"temp", QoS::AtLeastOnce, &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        let packets = outgoing(&mut gateway, &mut clock);
+        assert_eq!(client.handle(&packets[0]), ClientEvent::Subscribed { msg_id, topic_id, qos: QoS::AtLeastOnce, rc: ReturnCode::Accepted });
+        assert_eq!(gateway.broker().subscribed, vec![b"temp".to_vec()]);
+
+        assert_eq!(gateway.deliver(b"other", b"", false), 0);
+        assert_eq!(gateway.deliver(b"temp", b"22", true), 1);
+        let packets = outgoing(&mut gateway, &mut clock);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(client.handle(&packets[0]), ClientEvent::Received { topic_id, data: b"22", qos: QoS::AtLeastOnce, retain: true });
+
+        // Retransmitted until acknowledged
+        clock.now = 1_000_000;
+        let packets = outgoing(&mut gateway, &mut clock);
+        assert!(matches!(Message::decode(&packets[0]), Some(Message::Publish { dup: true, data: b"22", .. })));
+        let puback = client.poll(&mut clock, &mut buf).unwrap();
+        gateway.handle(1, puback, &mut clock).unwrap();
+        clock.now = 2_000_000;
+        assert!(outgoing(&mut gateway, &mut clock).is_empty());
+
+        // Only known clients on their pipe
+        let (_, packet) = client.register(b"x", &mut clock, &mut buf).unwrap();
+        gateway.handle(2, packet, &mut clock).unwrap();
+        assert!(outgoing(&mut gateway, &mut clock).is_empty());
+    }
+
+    #[test]
+    fn gateway_holds_for_sleeping_clients() {
+        let mut clock = TestClock::new(0);
+        let (mut clients, mut topics, mut subscriptions, mut outbox) = ([None; 2], [None; 2], [None; 2], [None; 4]);
+        let mut gateway = MqttSnGateway::new(TestBroker::default(), &mut clients, &mut topics, &mut subscriptions, &mut outbox);
+        let mut client = MqttSnClient::new(b"client", ADDR, 60);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+
+        let packet = client.connect(true, &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        client.handle(&outgoing(&mut gateway, &mut clock)[0]);
+        let (_, packet) = client.subscribe(b"temp", QoS::AtMostOnce, &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        client.handle(&outgoing(&mut gateway, &mut clock)[0]);
+
+        let packet = client.sleep(10, &mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        assert_eq!(client.handle(&outgoing(&mut gateway, &mut clock)[0]), ClientEvent::Asleep);
+        assert!(gateway.clients().all(|client| client.is_asleep()));
+
+        assert_eq!(gateway.deliver(b"temp", b"1", false), 1);
+        assert_eq!(gateway.deliver(b"temp", b"2", false), 1);
+        assert!(outgoing(&mut gateway, &mut clock).is_empty());
+
+        let packet = client.wake(&mut clock, &mut buf).unwrap();
+        gateway.handle(1, packet, &mut clock).unwrap();
+        let events: Vec<_> = outgoing(&mut gateway, &mut clock).iter()
+            .map(|packet| match client.handle(packet) {
+                ClientEvent::Received { data, .. } => data.to_vec(),
+                event => {
+                    assert_eq!(event, ClientEvent::Asleep);
+                    Vec::new()
+                }
+            })
+            .collect();
+        assert_eq!(events, vec![b"1".to_vec(), b"2".to_vec(), Vec::new()]);
+
+        // Gone for longer than 1.5 times the sleep duration
+        clock.now = 15_000_000;
+        assert_eq!(gateway.expire(&mut clock), Ok(1));
+        assert_eq!(gateway.clients().count(), 0);
+        assert_eq!(gateway.broker().disconnected, 1);
+    }
+}
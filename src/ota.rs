@@ -0,0 +1,709 @@
+//! Block transfer of firmware images
+//!
+//! The `OtaSender` first sends a `Manifest` describing the image,
+//! then the image in blocks of `BLOCK_BYTES`, each carrying its
+//! offset. The `OtaReceiver` writes them to an `ImageStorage` and
+//! keeps a bitmap of received blocks. Its `report()` tells the sender
+//! which blocks are missing; the sender then repeats just those. Once
+//! all blocks are in, the receiver checks the CRC-32 of the image
+//! before calling `ImageStorage::finish()`.
+//!
+//! The receiver saves the manifest and bitmap to the storage
+//! regularly so that it can `resume()` after a power loss.
+//!
+//! Reports go in the opposite direction of the blocks, e.g. as ack
+//! payloads with `RxMode::write_ack_payload()`.
+
+use embedded_hal::blocking::delay::DelayUs;
+use device::Device;
+use tx::{TxMode, SEND_TIMEOUT_US};
+
+/// Image bytes per block packet
+pub const BLOCK_BYTES: usize = 27;
+
+const MANIFEST: u8 = 0x60;
+const BLOCK: u8 = 0x61;
+const REPORT: u8 = 0x62;
+const MANIFEST_LEN: usize = 13;
+const REPORT_HEADER_LEN: usize = 7;
+/// Bitmap bytes in a report
+const REPORT_BITMAP_BYTES: usize = 32 - REPORT_HEADER_LEN;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) |
+    ((buf[1] as u32) << 8) |
+    ((buf[2] as u32) << 16) |
+    ((buf[3] as u32) << 24)
+}
+
+fn write_u32(buf: &mut [u8], value: u32) {
+    buf[0] = value as u8;
+    buf[1] = (value >> 8) as u8;
+    buf[2] = (value >> 16) as u8;
+    buf[3] = (value >> 24) as u8;
+}
+
+/// CRC-32 as used by Ethernet and zlib
+///
+/// Start with `0`, feed the image in any number of parts.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Describes an image
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Manifest {
+    /// Distinguishes images, e.g. a firmware version
+    pub version: u32,
+    /// Image size in bytes
+    pub size: u32,
+    /// `crc32()` of the whole image
+    pub crc: u32,
+}
+
+impl Manifest {
+    /// Number of blocks
+    pub fn blocks(&self) -> u32 {
+        let block_bytes = BLOCK_BYTES as u32;
+        self.size / block_bytes + (self.size % block_bytes != 0) as u32
+    }
+
+    /// Bytes needed for the bitmap of blocks
+    pub fn bitmap_bytes(&self) -> usize {
+        (self.blocks() as usize + 7) / 8
+    }
+
+    fn encode<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        buf[0] = MANIFEST;
+        write_u32(&mut buf[1..5], self.version);
+        write_u32(&mut buf[5..9], self.size);
+        write_u32(&mut buf[9..13], self.crc);
+        &buf[0..MANIFEST_LEN]
+    }
+
+    fn decode(packet: &[u8]) -> Option<Self> {
+        if packet.len() != MANIFEST_LEN || packet[0] != MANIFEST {
+            return None;
+        }
+        Some(Manifest {
+            version: read_u32(&packet[1..5]),
+            size: read_u32(&packet[5..9]),
+            crc: read_u32(&packet[9..13]),
+        })
+    }
+}
+
+/// Where the `OtaReceiver` puts the image, e.g. a bootloader's
+/// update slot
+pub trait ImageStorage {
+    type Error;
+
+    /// Prepare for a new image, e.g. erase the slot
+    fn begin(&mut self, manifest: &Manifest) -> Result<(), Self::Error>;
+    /// Write image bytes at `offset`
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+    /// Read back image bytes at `offset` to verify the image
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+    /// Persist the transfer state so it survives a power loss
+    fn save_progress(&mut self, manifest: &Manifest, bitmap: &[u8]) -> Result<(), Self::Error>;
+    /// Restore what has been saved with `save_progress()` into
+    /// `bitmap`, if anything
+    fn load_progress(&mut self, bitmap: &mut [u8]) -> Result<Option<Manifest>, Self::Error>;
+    /// The image is complete and verified
+    fn finish(&mut self, manifest: &Manifest) -> Result<(), Self::Error>;
+}
+
+/// Result of `OtaReceiver::handle()`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum OtaEvent {
+    /// Not a transfer packet, or one of another image
+    Ignored,
+    /// A new image is being transferred
+    Started(Manifest),
+    /// The manifest has been refused because the bitmap is too small,
+    /// or the image exceeds 65535 blocks
+    TooLarge(Manifest),
+    /// The block at this offset has been written
+    Block(u32),
+    /// All blocks are in and the CRC matches. Follows the manifest
+    /// directly for an empty image.
+    Complete(Manifest),
+    /// All blocks are in but the CRC does not match. The transfer
+    /// starts over.
+    Corrupt(Manifest),
+}
+
+/// Receiving end of a transfer, see the module documentation
+pub struct OtaReceiver<'a, S: ImageStorage> {
+    storage: S,
+    /// Bit set for each block received
+    bitmap: &'a mut [u8],
+    manifest: Option<Manifest>,
+    complete: bool,
+    /// Blocks since the last `save_progress()`
+    unsaved: u16,
+    save_interval: u16,
+}
+
+impl<'a, S: ImageStorage> OtaReceiver<'a, S> {
+    /// `bitmap` limits the image size to `bitmap.len() * 8` blocks
+    pub fn new(storage: S, bitmap: &'a mut [u8]) -> Self {
+        OtaReceiver {
+            storage,
+            bitmap,
+            manifest: None,
+            complete: false,
+            unsaved: 0,
+            save_interval: 16,
+        }
+    }
+
+    /// Save progress after every `blocks` blocks, 16 by default
+    pub fn set_save_interval(&mut self, blocks: u16) {
+        self.save_interval = blocks.max(1);
+    }
+
+    pub fn storage(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    /// Image currently being received
+    pub fn manifest(&self) -> Option<Manifest> {
+        self.manifest
+    }
+
+    /// Continue a transfer saved before a power loss. Returns its
+    /// manifest, if any.
+    pub fn resume(&mut self) -> Result<Option<Manifest>, S::Error> {
+        for byte in self.bitmap.iter_mut() {
+            *byte = 0;
+        }
+        let manifest = self.storage.load_progress(self.bitmap)?;
+        self.manifest = manifest.filter(|manifest| manifest.bitmap_bytes() <= self.bitmap.len());
+        self.complete = false;
+        self.unsaved = 0;
+        Ok(self.manifest)
+    }
+
+    /// Number of blocks received of the current image
+    pub fn received_blocks(&self) -> u32 {
+        let bytes = self.manifest.map_or(0, |manifest| manifest.bitmap_bytes());
+        self.bitmap[0..bytes].iter()
+            .map(|byte| byte.count_ones())
+            .sum()
+    }
+
+    /// Process one received packet
+    pub fn handle(&mut self, packet: &[u8]) -> Result<OtaEvent, S::Error> {
+        if let Some(manifest) = Manifest::decode(packet) {
+            return self.start(manifest);
+        }
+
+        let manifest = match self.manifest {
+            Some(manifest) => manifest,
+            None => return Ok(OtaEvent::Ignored),
+        };
+        if packet.len() < 5 || packet[0] != BLOCK || self.complete {
+            return Ok(OtaEvent::Ignored);
+        }
+        let offset = read_u32(&packet[1..5]);
+        let data = &packet[5..];
+        if offset % BLOCK_BYTES as u32 != 0 ||
+            offset >= manifest.size ||
+            data.len() != (manifest.size - offset).min(BLOCK_BYTES as u32) as usize
+        {
+            return Ok(OtaEvent::Ignored);
+        }
+
+        let block = (offset / BLOCK_BYTES as u32) as usize;
+        let mask = 1 << (block % 8);
+        if self.bitmap[block / 8] & mask == 0 {
+            self.storage.write(offset, data)?;
+            self.bitmap[block / 8] |= mask;
+            self.unsaved += 1;
+        }
+
+        if self.received_blocks() == manifest.blocks() {
+            return self.verify(manifest);
+        }
+        if self.unsaved >= self.save_interval {
+            self.storage.save_progress(&manifest, self.bitmap)?;
+            self.unsaved = 0;
+        }
+        Ok(OtaEvent::Block(offset))
+    }
+
+    fn start(&mut self, manifest: Manifest) -> Result<OtaEvent, S::Error> {
+        if self.manifest == Some(manifest) {
+            // Repeated or resumed
+            return Ok(OtaEvent::Ignored);
+        }
+        // Reports count blocks in 16 bits
+        if manifest.bitmap_bytes() > self.bitmap.len() ||
+            manifest.blocks() >= 0x1_0000
+        {
+            return Ok(OtaEvent::TooLarge(manifest));
+        }
+
+        self.restart(manifest)?;
+        if manifest.blocks() == 0 {
+            // An empty image has no blocks to wait for
+            return self.verify(manifest);
+        }
+        Ok(OtaEvent::Started(manifest))
+    }
+
+    fn restart(&mut self, manifest: Manifest) -> Result<(), S::Error> {
+        for byte in self.bitmap.iter_mut() {
+            *byte = 0;
+        }
+        self.manifest = Some(manifest);
+        self.complete = false;
+        self.unsaved = 0;
+        self.storage.begin(&manifest)?;
+        self.storage.save_progress(&manifest, self.bitmap)
+    }
+
+    fn verify(&mut self, manifest: Manifest) -> Result<OtaEvent, S::Error> {
+        let mut crc = 0;
+        let mut buf = [0; 32];
+        let mut offset = 0;
+        while offset < manifest.size {
+            let len = (manifest.size - offset).min(buf.len() as u32) as usize;
+            self.storage.read(offset, &mut buf[0..len])?;
+            crc = crc32(crc, &buf[0..len]);
+            offset += len as u32;
+        }
+
+        if crc != manifest.crc {
+            self.restart(manifest)?;
+            return Ok(OtaEvent::Corrupt(manifest));
+        }
+        self.storage.save_progress(&manifest, self.bitmap)?;
+        self.storage.finish(&manifest)?;
+        self.complete = true;
+        Ok(OtaEvent::Complete(manifest))
+    }
+
+    /// Encode a report of the missing blocks into `buf`, which must
+    /// hold 32 bytes. Returns `None` before any manifest has been
+    /// received.
+    ///
+    /// Covers the first missing block and up to 199 after it.
+    pub fn report<'b>(&self, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        let manifest = self.manifest?;
+        let blocks = manifest.blocks();
+        let received = |block: u32| {
+            self.bitmap[block as usize / 8] & (1 << (block % 8)) != 0
+        };
+
+        let base = (0..blocks)
+            .find(|block| !received(*block))
+            .unwrap_or(blocks);
+        buf[0] = REPORT;
+        write_u32(&mut buf[1..5], manifest.version);
+        buf[5] = base as u8;
+        buf[6] = (base >> 8) as u8;
+        for i in 0..REPORT_BITMAP_BYTES {
+            let mut bits = 0;
+            for bit in 0..8 {
+                let block = base + (i * 8 + bit) as u32;
+                if block < blocks && !received(block) {
+                    bits |= 1 << bit;
+                }
+            }
+            buf[REPORT_HEADER_LEN + i] = bits;
+        }
+        Some(&buf[0..32])
+    }
+}
+
+/// Sending end of a transfer, see the module documentation
+#[derive(Debug)]
+pub struct OtaSender<'a> {
+    image: &'a [u8],
+    manifest: Manifest,
+    manifest_sent: bool,
+    /// Next block of the first pass over the image
+    next_block: u32,
+    /// Missing blocks from the last report, relative to `base`
+    missing: [u8; REPORT_BITMAP_BYTES],
+    base: u32,
+    done: bool,
+}
+
+impl<'a> OtaSender<'a> {
+    /// `image` must fit 65535 blocks
+    pub fn new(image: &'a [u8], version: u32) -> Self {
+        let manifest = Manifest {
+            version,
+            size: image.len() as u32,
+            crc: crc32(0, image),
+        };
+        assert!(manifest.blocks() < 0x1_0000);
+        OtaSender {
+            image,
+            manifest,
+            manifest_sent: false,
+            next_block: 0,
+            missing: [0; REPORT_BITMAP_BYTES],
+            base: 0,
+            done: false,
+        }
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        self.manifest
+    }
+
+    /// The receiver has reported having all blocks
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Send the manifest again with the next packet, e.g. after the
+    /// receiver has restarted
+    pub fn restart(&mut self) {
+        self.manifest_sent = false;
+    }
+
+    /// Encode the next packet to send into `buf`, which must hold 32
+    /// bytes. Returns `None` while waiting for a report.
+    pub fn next_packet<'b>(&mut self, buf: &'b mut [u8]) -> Option<&'b [u8]> {
+        if !self.manifest_sent {
+            self.manifest_sent = true;
+            return Some(self.manifest.encode(buf));
+        }
+
+        let block = if self.next_block < self.manifest.blocks() {
+            self.next_block += 1;
+            self.next_block - 1
+        } else {
+            let i = (0..REPORT_BITMAP_BYTES * 8)
+                .find(|i| self.missing[i / 8] & (1 << (i % 8)) != 0)?;
+            self.missing[i / 8] &= !(1 << (i % 8));
+            self.base + i as u32
+        };
+        Some(self.block_packet(block, buf))
+    }
+
+    fn block_packet<'b>(&self, block: u32, buf: &'b mut [u8]) -> &'b [u8] {
+        let offset = block as usize * BLOCK_BYTES;
+        let end = (offset + BLOCK_BYTES).min(self.image.len());
+        let data = &self.image[offset..end];
+        buf[0] = BLOCK;
+        write_u32(&mut buf[1..5], offset as u32);
+        buf[5..5 + data.len()].copy_from_slice(data);
+        &buf[0..5 + data.len()]
+    }
+
+    /// Process a packet from the receiver. Returns `false` if it is
+    /// no report for this image.
+    pub fn handle_report(&mut self, packet: &[u8]) -> bool {
+        if packet.len() != REPORT_HEADER_LEN + REPORT_BITMAP_BYTES ||
+            packet[0] != REPORT ||
+            read_u32(&packet[1..5]) != self.manifest.version
+        {
+            return false;
+        }
+
+        self.manifest_sent = true;
+        self.base = (packet[5] as u32) | ((packet[6] as u32) << 8);
+        self.missing.copy_from_slice(&packet[REPORT_HEADER_LEN..]);
+        self.done = self.base >= self.manifest.blocks();
+        true
+    }
+
+    /// Send the next packet with `send_sync_timeout()`. Returns
+    /// `None` if there is nothing to send, otherwise whether it was
+    /// acknowledged.
+    ///
+    /// Unacknowledged blocks show up as missing in the next report.
+    /// An unacknowledged manifest is sent again.
+    pub fn send_next<D: Device, DL: DelayUs<u32>>(&mut self, tx: &mut TxMode<D>, delay: &mut DL) -> Result<Option<bool>, D::Error> {
+        let mut buf = [0; 32];
+        let sent_manifest = !self.manifest_sent;
+        let result = match self.next_packet(&mut buf) {
+            Some(packet) => tx.send_sync_timeout(packet, delay, SEND_TIMEOUT_US),
+            None => return Ok(None),
+        };
+        if sent_manifest && !matches!(result, Ok(true)) {
+            self.manifest_sent = false;
+        }
+        result.map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use standby::StandbyMode;
+    use config::Configuration;
+    use mock::{MockRadio, NoDelay};
+
+    /// Keeps the image and progress in memory, surviving a "reboot"
+    /// through `into_storage()`
+    #[derive(Default)]
+    struct TestStorage {
+        image: Vec<u8>,
+        progress: Option<(Manifest, Vec<u8>)>,
+        begun: usize,
+        finished: Option<Manifest>,
+    }
+
+    impl ImageStorage for TestStorage {
+        type Error = ();
+
+        fn begin(&mut self, manifest: &Manifest) -> Result<(), ()> {
+            self.image = vec![0xFF; manifest.size as usize];
+            self.begun += 1;
+            self.finished = None;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            self.image[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.image[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn save_progress(&mut self, manifest: &Manifest, bitmap: &[u8]) -> Result<(), ()> {
+            self.progress = Some((*manifest, bitmap.to_vec()));
+            Ok(())
+        }
+
+        fn load_progress(&mut self, bitmap: &mut [u8]) -> Result<Option<Manifest>, ()> {
+            Ok(self.progress.as_ref().map(|&(manifest, ref saved)| {
+                let len = saved.len().min(bitmap.len());
+                bitmap[0..len].copy_from_slice(&saved[0..len]);
+                manifest
+            }))
+        }
+
+        fn finish(&mut self, manifest: &Manifest) -> Result<(), ()> {
+            self.finished = Some(*manifest);
+            Ok(())
+        }
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    /// Pass packets from `sender` to `receiver` until the sender waits
+    /// for a report, dropping those for which `lose` holds. Returns
+    /// the last event.
+    fn pass<S: ImageStorage<Error = ()>>(
+        sender: &mut OtaSender,
+        receiver: &mut OtaReceiver<S>,
+        lose: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> OtaEvent {
+        let mut buf = [0; 32];
+        let mut event = OtaEvent::Ignored;
+        while let Some(packet) = sender.next_packet(&mut buf) {
+            if !lose(packet) {
+                event = receiver.handle(packet).unwrap();
+            }
+        }
+        event
+    }
+
+    /// Hand the receiver's report to the sender
+    fn report<S: ImageStorage>(sender: &mut OtaSender, receiver: &OtaReceiver<S>) {
+        let mut buf = [0; 32];
+        assert!(sender.handle_report(receiver.report(&mut buf).unwrap()));
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+        assert_eq!(crc32(0, b""), 0);
+    }
+
+    #[test]
+    fn manifest() {
+        let manifest = Manifest { version: 0x0102_0304, size: 28, crc: 0xDEAD_BEEF };
+        let mut buf = [0; 32];
+        assert_eq!(Manifest::decode(manifest.encode(&mut buf)), Some(manifest));
+        assert_eq!(manifest.blocks(), 2);
+        assert_eq!(manifest.bitmap_bytes(), 1);
+        let block_sizes = [(0, 0), (27, 1), (27 * 8, 8), (27 * 8 + 1, 9)];
+        for &(size, blocks) in block_sizes.iter() {
+            assert_eq!(Manifest { size, ..manifest }.blocks(), blocks);
+        }
+        assert_eq!(Manifest { size: 27 * 9, ..manifest }.bitmap_bytes(), 2);
+    }
+
+    #[test]
+    fn transfer_with_losses() {
+        let image = image(27 * 40 + 5);
+        let mut sender = OtaSender::new(&image, 3);
+        let mut bitmap = [0; 8];
+        let mut receiver = OtaReceiver::new(TestStorage::default(), &mut bitmap);
+
+        let mut n = 0;
+        let event = pass(&mut sender, &mut receiver, &mut |_| {
+            n += 1;
+            n % 3 == 0
+        });
+        assert!(matches!(event, OtaEvent::Block(_)));
+        assert_eq!(receiver.received_blocks(), 41 - 14);
+        report(&mut sender, &receiver);
+        assert!(!sender.is_done());
+
+        assert_eq!(pass(&mut sender, &mut receiver, &mut |_| false), OtaEvent::Complete(sender.manifest()));
+        report(&mut sender, &receiver);
+        assert!(sender.is_done());
+        let storage = receiver.into_storage();
+        assert_eq!(storage.image, image);
+        assert_eq!(storage.finished, Some(sender.manifest()));
+    }
+
+    #[test]
+    fn report_beyond_255_blocks() {
+        let image = image(27 * 300);
+        let mut sender = OtaSender::new(&image, 1);
+        let mut bitmap = [0; 40];
+        let mut receiver = OtaReceiver::new(TestStorage::default(), &mut bitmap);
+
+        // Lose block 260 and 290
+        let lost = [260 * 27, 290 * 27];
+        pass(&mut sender, &mut receiver, &mut |packet| {
+            packet[0] == BLOCK && lost.contains(&read_u32(&packet[1..5]))
+        });
+        let mut buf = [0; 32];
+        let packet = receiver.report(&mut buf).unwrap();
+        assert_eq!(&packet[5..7], &[4, 1]);
+        assert_eq!(packet[REPORT_HEADER_LEN], 0x01);
+        assert_eq!(packet[REPORT_HEADER_LEN + 3], 0x40);
+
+        report(&mut sender, &receiver);
+        let mut resent = Vec::new();
+        while let Some(packet) = sender.next_packet(&mut buf) {
+            resent.push(read_u32(&packet[1..5]));
+        }
+        assert_eq!(resent, lost.to_vec());
+    }
+
+    #[test]
+    fn resume_after_power_loss() {
+        let image = image(27 * 20);
+        let mut sender = OtaSender::new(&image, 2);
+        let mut bitmap = [0; 4];
+        let mut receiver = OtaReceiver::new(TestStorage::default(), &mut bitmap);
+        receiver.set_save_interval(4);
+
+        let mut n = 0;
+        pass(&mut sender, &mut receiver, &mut |_| {
+            n += 1;
+            // Power lost after the manifest and 10 blocks
+            n > 11
+        });
+        let storage = receiver.into_storage();
+
+        let mut bitmap = [0; 4];
+        let mut receiver = OtaReceiver::new(storage, &mut bitmap);
+        assert_eq!(receiver.resume(), Ok(Some(sender.manifest())));
+        assert_eq!(receiver.received_blocks(), 8);
+        // The manifest again does not start over
+        let mut buf = [0; 32];
+        assert_eq!(receiver.handle(sender.manifest().encode(&mut buf)), Ok(OtaEvent::Ignored));
+
+        report(&mut sender, &receiver);
+        assert_eq!(pass(&mut sender, &mut receiver, &mut |_| false), OtaEvent::Complete(sender.manifest()));
+        let storage = receiver.into_storage();
+        assert_eq!(storage.begun, 1);
+        assert_eq!(storage.image, image);
+    }
+
+    #[test]
+    fn empty_image() {
+        let mut sender = OtaSender::new(&[], 4);
+        let mut bitmap = [0; 1];
+        let mut receiver = OtaReceiver::new(TestStorage::default(), &mut bitmap);
+
+        assert_eq!(pass(&mut sender, &mut receiver, &mut |_| false), OtaEvent::Complete(sender.manifest()));
+        report(&mut sender, &receiver);
+        assert!(sender.is_done());
+        assert_eq!(receiver.into_storage().finished, Some(sender.manifest()));
+    }
+
+    #[test]
+    fn too_large() {
+        let image = image(27 * 9);
+        let mut sender = OtaSender::new(&image, 5);
+        let mut bitmap = [0; 1];
+        let mut receiver = OtaReceiver::new(TestStorage::default(), &mut bitmap);
+        let mut buf = [0; 32];
+        let packet = sender.next_packet(&mut buf).unwrap();
+        assert_eq!(receiver.handle(packet), Ok(OtaEvent::TooLarge(sender.manifest())));
+        assert_eq!(receiver.manifest(), None);
+        assert_eq!(receiver.report(&mut buf), None);
+    }
+
+    #[test]
+    fn restart_after_corrupt_image() {
+        let image = image(27 * 5);
+        let mut sender = OtaSender::new(&image, 6);
+        let mut bitmap = [0; 1];
+        let mut receiver = OtaReceiver::new(TestStorage::default(), &mut bitmap);
+
+        let mut buf = [0; 32];
+        let mut events = Vec::new();
+        while let Some(packet) = sender.next_packet(&mut buf) {
+            let mut packet = packet.to_vec();
+            if packet[0] == BLOCK && read_u32(&packet[1..5]) == 27 {
+                packet[10] ^= 1;
+            }
+            events.push(receiver.handle(&packet).unwrap());
+        }
+        assert_eq!(events.last(), Some(&OtaEvent::Corrupt(sender.manifest())));
+        assert_eq!(receiver.received_blocks(), 0);
+
+        report(&mut sender, &receiver);
+        assert_eq!(pass(&mut sender, &mut receiver, &mut |_| false), OtaEvent::Complete(sender.manifest()));
+        let storage = receiver.into_storage();
+        assert_eq!(storage.begun, 2);
+        assert_eq!(storage.image, image);
+    }
+
+    #[test]
+    fn send_next_repeats_the_manifest() {
+        let image = image(27);
+        let mut sender = OtaSender::new(&image, 7);
+        let mut radio = MockRadio::new();
+        radio.acks.push_back(false);
+        let mut tx = StandbyMode::power_up(radio).unwrap().tx().unwrap();
+
+        assert_eq!(sender.send_next(&mut tx, &mut NoDelay).ok(), Some(Some(false)));
+        tx.device().stuck = true;
+        assert!(sender.send_next(&mut tx, &mut NoDelay).is_err());
+        tx.device().stuck = false;
+        assert_eq!(sender.send_next(&mut tx, &mut NoDelay).ok(), Some(Some(true)));
+        assert_eq!(sender.send_next(&mut tx, &mut NoDelay).ok(), Some(Some(true)));
+        assert_eq!(sender.send_next(&mut tx, &mut NoDelay).ok(), Some(None));
+
+        let sent: Vec<_> = tx.device().sent.iter().map(|sent| sent.data[0]).collect();
+        assert_eq!(sent, vec![MANIFEST, MANIFEST, BLOCK]);
+    }
+}
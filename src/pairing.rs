@@ -0,0 +1,272 @@
+//! Binding of new nodes to a hub at runtime
+//!
+//! Both ends meet on `PAIRING_CHANNEL` with `PAIRING_ADDR`, after
+//! `enter_pairing()`. The exchange is:
+//!
+//! 1. The node sends `PairingNode::request()`, then listens.
+//! 2. While its confirmation window is open, the hub accepts it with
+//!    `PairingHub::handle_request()` and answers with `offer()`
+//!    carrying the node's address, its own address, the operating
+//!    channel and a session key, then listens.
+//! 3. The node takes the offer with `handle_offer()` and sends
+//!    `confirm()`. Once that has been acknowledged, it applies its
+//!    `Binding`.
+//! 4. The hub receives the confirmation with `handle_confirm()` and
+//!    applies its `Binding` as well.
+//!
+//! Addresses and key are transmitted in the clear, at minimum power.
+//! Generating them, and using the key, is up to the application.
+
+use clock::Clock;
+use config::{Configuration, DataRate, PAControl};
+use device::Device;
+use PIPES_COUNT;
+
+/// Channel both ends meet on
+pub const PAIRING_CHANNEL: u8 = 2;
+/// Address both ends use while pairing
+pub const PAIRING_ADDR: [u8; 5] = *b"nPAIR";
+/// Length of the session key
+pub const KEY_BYTES: usize = 16;
+
+const PAIR_REQUEST: u8 = 0x50;
+const PAIR_OFFER: u8 = 0x51;
+const PAIR_CONFIRM: u8 = 0x52;
+const REQUEST_LEN: usize = 5;
+const OFFER_LEN: usize = 32;
+const CONFIRM_LEN: usize = 5;
+
+/// Unique identity of a node, e.g. derived from its MCU's serial
+/// number
+pub type NodeId = [u8; 4];
+
+/// Settings agreed on, seen from one end
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Binding {
+    pub channel: u8,
+    /// Address to receive on
+    pub local_addr: [u8; 5],
+    /// Address to send to
+    pub peer_addr: [u8; 5],
+    pub key: [u8; KEY_BYTES],
+}
+
+impl Binding {
+    /// Switch to the agreed channel and addresses, receiving on pipe
+    /// 1, and restore the `rate` and `power` of operation that
+    /// `enter_pairing()` has replaced. Both ends must use the same
+    /// `rate`.
+    pub fn apply<C: Configuration>(&self, radio: &mut C, rate: DataRate, power: PAControl) -> Result<(), <C::Inner as Device>::Error> {
+        radio.set_frequency(self.channel)?;
+        radio.set_rf(rate, power)?;
+        radio.set_tx_addr(&self.peer_addr)?;
+        radio.set_rx_addr(1, &self.local_addr)?;
+        Ok(())
+    }
+}
+
+/// Switch to the pairing channel and address, with dynamic payload
+/// lengths on all pipes, at 1 Mbps and minimum power
+pub fn enter_pairing<C: Configuration>(radio: &mut C) -> Result<(), <C::Inner as Device>::Error> {
+    radio.set_frequency(PAIRING_CHANNEL)?;
+    radio.set_rf(DataRate::R1Mbps, PAControl::PAMin)?;
+    radio.set_tx_addr(&PAIRING_ADDR)?;
+    radio.set_rx_addr(1, &PAIRING_ADDR)?;
+    radio.set_pipes_rx_enable(&[true, true, false, false, false, false])?;
+    radio.set_auto_ack(&[true; PIPES_COUNT])?;
+    radio.set_pipes_rx_lengths(&[None; PIPES_COUNT])?;
+    Ok(())
+}
+
+/// Time span started by a button press
+#[derive(Debug)]
+struct Window {
+    opened: Option<u32>,
+    duration_us: u32,
+}
+
+impl Window {
+    fn new() -> Self {
+        Window {
+            opened: None,
+            duration_us: 0,
+        }
+    }
+
+    fn open<C: Clock>(&mut self, clock: &mut C, duration_us: u32) {
+        self.opened = Some(clock.now_us());
+        self.duration_us = duration_us;
+    }
+
+    fn is_open<C: Clock>(&mut self, clock: &mut C) -> bool {
+        let opened = match self.opened {
+            Some(opened) => opened,
+            None => return false,
+        };
+        if clock.now_us().wrapping_sub(opened) < self.duration_us {
+            true
+        } else {
+            self.opened = None;
+            false
+        }
+    }
+
+    fn close(&mut self) {
+        self.opened = None;
+    }
+}
+
+/// Joining end of the pairing, see the module documentation
+#[derive(Debug)]
+pub struct PairingNode {
+    id: NodeId,
+    window: Window,
+    offer: Option<Binding>,
+}
+
+impl PairingNode {
+    pub fn new(id: NodeId) -> Self {
+        PairingNode {
+            id,
+            window: Window::new(),
+            offer: None,
+        }
+    }
+
+    /// Call on button press. Offers are only taken for `duration_us`.
+    pub fn start<C: Clock>(&mut self, clock: &mut C, duration_us: u32) {
+        self.offer = None;
+        self.window.open(clock, duration_us);
+    }
+
+    pub fn is_active<C: Clock>(&mut self, clock: &mut C) -> bool {
+        self.window.is_open(clock)
+    }
+
+    /// Encode the request into `buf`, which must hold 5 bytes
+    pub fn request<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        buf[0] = PAIR_REQUEST;
+        buf[1..REQUEST_LEN].copy_from_slice(&self.id);
+        &buf[0..REQUEST_LEN]
+    }
+
+    /// Returns `true` if `packet` is an offer to this node
+    pub fn handle_offer<C: Clock>(&mut self, packet: &[u8], clock: &mut C) -> bool {
+        if !self.window.is_open(clock) ||
+            packet.len() != OFFER_LEN ||
+            packet[0] != PAIR_OFFER ||
+            packet[1..5] != self.id
+        {
+            return false;
+        }
+
+        let mut binding = Binding {
+            channel: packet[5],
+            local_addr: [0; 5],
+            peer_addr: [0; 5],
+            key: [0; KEY_BYTES],
+        };
+        if binding.channel >= 126 {
+            return false;
+        }
+        binding.local_addr.copy_from_slice(&packet[6..11]);
+        binding.peer_addr.copy_from_slice(&packet[11..16]);
+        binding.key.copy_from_slice(&packet[16..OFFER_LEN]);
+        self.offer = Some(binding);
+        true
+    }
+
+    /// Encode the confirmation of the offer into `buf`, which must
+    /// hold 5 bytes
+    pub fn confirm<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        buf[0] = PAIR_CONFIRM;
+        buf[1..CONFIRM_LEN].copy_from_slice(&self.id);
+        &buf[0..CONFIRM_LEN]
+    }
+
+    /// Call once the confirmation has been acknowledged. Returns the
+    /// binding to apply.
+    pub fn finish(&mut self) -> Option<Binding> {
+        self.window.close();
+        self.offer.take()
+    }
+}
+
+/// Accepting end of the pairing, see the module documentation
+#[derive(Debug)]
+pub struct PairingHub {
+    window: Window,
+    /// Node id and binding offered to it
+    offered: Option<(NodeId, Binding)>,
+}
+
+impl Default for PairingHub {
+    fn default() -> Self {
+        PairingHub::new()
+    }
+}
+
+impl PairingHub {
+    pub fn new() -> Self {
+        PairingHub {
+            window: Window::new(),
+            offered: None,
+        }
+    }
+
+    /// Call on button press. Requests are only accepted for
+    /// `duration_us`.
+    pub fn open_window<C: Clock>(&mut self, clock: &mut C, duration_us: u32) {
+        self.offered = None;
+        self.window.open(clock, duration_us);
+    }
+
+    pub fn is_open<C: Clock>(&mut self, clock: &mut C) -> bool {
+        self.window.is_open(clock)
+    }
+
+    /// Returns the id of the requesting node if `packet` is a
+    /// request and the window is open
+    pub fn handle_request<C: Clock>(&mut self, packet: &[u8], clock: &mut C) -> Option<NodeId> {
+        if !self.window.is_open(clock) ||
+            packet.len() != REQUEST_LEN ||
+            packet[0] != PAIR_REQUEST
+        {
+            return None;
+        }
+
+        let mut id = [0; 4];
+        id.copy_from_slice(&packet[1..REQUEST_LEN]);
+        Some(id)
+    }
+
+    /// Encode an offer of `binding`, seen from the hub, to node `id`
+    /// into `buf`, which must hold 32 bytes
+    pub fn offer<'b>(&mut self, id: NodeId, binding: Binding, buf: &'b mut [u8]) -> &'b [u8] {
+        assert!(binding.channel < 126);
+        buf[0] = PAIR_OFFER;
+        buf[1..5].copy_from_slice(&id);
+        buf[5] = binding.channel;
+        // Addresses as seen from the node
+        buf[6..11].copy_from_slice(&binding.peer_addr);
+        buf[11..16].copy_from_slice(&binding.local_addr);
+        buf[16..OFFER_LEN].copy_from_slice(&binding.key);
+        self.offered = Some((id, binding));
+        &buf[0..OFFER_LEN]
+    }
+
+    /// Returns the node id and the binding to apply if `packet`
+    /// confirms the last offer
+    pub fn handle_confirm(&mut self, packet: &[u8]) -> Option<(NodeId, Binding)> {
+        let (id, _) = self.offered?;
+        if packet.len() != CONFIRM_LEN ||
+            packet[0] != PAIR_CONFIRM ||
+            packet[1..CONFIRM_LEN] != id
+        {
+            return None;
+        }
+
+        self.window.close();
+        self.offered.take()
+    }
+}
@@ -1,8 +1,11 @@
+use core::fmt;
 use core::ops::Deref;
 
+#[derive(Clone)]
 pub struct Payload {
     data: [u8; 32],
     len: usize,
+    pipe: u8,
 }
 
 impl Payload {
@@ -13,12 +16,21 @@ impl Payload {
         //     data[i] = source[i];
         // }
         data[0..len].copy_from_slice(&source[0..len]);
-        Payload { data, len }
+        Payload { data, len, pipe: 0 }
     }
 
     pub fn len(&self) -> usize {
         self.len
     }
+
+    /// Pipe number the payload was received on
+    pub fn pipe(&self) -> u8 {
+        self.pipe
+    }
+
+    pub(crate) fn set_pipe(&mut self, pipe: u8) {
+        self.pipe = pipe;
+    }
 }
 
 impl AsRef<[u8]> for Payload {
@@ -33,3 +45,36 @@ impl Deref for Payload {
         &self.as_ref()
     }
 }
+
+impl PartialEq for Payload {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.pipe == rhs.pipe &&
+            self.as_ref() == rhs.as_ref()
+    }
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Payload")
+            .field("pipe", &self.pipe)
+            .field("data", &self.as_ref())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Payload {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "Payload {{ pipe: {=u8}, data: {=[u8]} }}", self.pipe, self.as_ref())
+    }
+}
+
+#[cfg(feature = "ufmt")]
+impl ufmt::uDebug for Payload {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("Payload")?
+            .field("pipe", &self.pipe)?
+            .field("data", &self.as_ref())?
+            .finish()
+    }
+}
@@ -0,0 +1,257 @@
+use core::fmt;
+use core::mem::replace;
+use registers::{Status, FifoStatus};
+use device::Device;
+use tx::TxMode;
+use payload::Payload;
+use config::Configuration;
+use clock::Clock;
+
+/// Depth of the chip's TX FIFO
+const TX_FIFO_DEPTH: usize = 3;
+
+/// Identifies a packet queued with `PipelinedTx::queue()`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PacketHandle(u16);
+
+/// What happened to a queued packet
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Outcome {
+    /// Acknowledged by the receiver, or sent if auto-ack is disabled
+    Sent,
+    /// Reached the maximum number of retransmits, including the
+    /// retries configured with `set_head_retries()`
+    Failed,
+}
+
+/// Keeps all three slots of the TX FIFO busy and reports the outcome
+/// of each packet
+///
+/// Unlike `TxMode::wait_empty()`, a packet that hits `MAX_RT` does
+/// not take the other queued packets down with it: they are
+/// re-queued after the failed one has been removed.
+///
+/// Outcomes are inferred from the `TX_DS` and `MAX_RT` interrupts in
+/// FIFO order. Call `poll()` often enough to observe each of them, at
+/// least once per packet airtime.
+pub struct PipelinedTx<D: Device> {
+    tx: TxMode<D>,
+    /// Copies of the packets in the TX FIFO, oldest first
+    slots: [(PacketHandle, Payload); TX_FIFO_DEPTH],
+    head: usize,
+    len: usize,
+    next_handle: u16,
+    /// How often to restart the head packet after `MAX_RT`
+    head_retries: u8,
+    head_attempts: u8,
+}
+
+impl<D: Device> fmt::Debug for PipelinedTx<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PipelinedTx")
+    }
+}
+
+impl<D: Device> PipelinedTx<D> {
+    /// Expects an empty TX FIFO
+    pub fn new(tx: TxMode<D>) -> Self {
+        let empty = || (PacketHandle(0), Payload::new(&[]));
+        PipelinedTx {
+            tx,
+            slots: [empty(), empty(), empty()],
+            head: 0,
+            len: 0,
+            next_handle: 0,
+            head_retries: 0,
+            head_attempts: 0,
+        }
+    }
+
+    /// When the head packet hits `MAX_RT`, clear the interrupt up to
+    /// `retries` times to let the chip go through another round of
+    /// retransmits before reporting `Outcome::Failed`
+    pub fn set_head_retries(&mut self, retries: u8) {
+        self.head_retries = retries;
+    }
+
+    /// Number of packets without an outcome yet
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get back the `TxMode` once all outcomes have been polled
+    pub fn into_inner(self) -> TxMode<D> {
+        self.tx
+    }
+
+    /// Enqueue a packet, returns `WouldBlock` while three packets are
+    /// pending
+    pub fn queue(&mut self, packet: &[u8]) -> nb::Result<PacketHandle, D::Error> {
+        if self.len >= TX_FIFO_DEPTH {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        self.tx.send(packet)?;
+        let handle = PacketHandle(self.next_handle);
+        self.next_handle = self.next_handle.wrapping_add(1);
+        let tail = (self.head + self.len) % TX_FIFO_DEPTH;
+        self.slots[tail] = (handle, Payload::new(packet));
+        self.len += 1;
+        Ok(handle)
+    }
+
+    /// See `TxMode::check_dwell()`. The next `poll()` resumes
+    /// transmission.
+    pub fn check_dwell<C: Clock>(&mut self, clock: &mut C) -> bool {
+        self.tx.check_dwell(clock)
+    }
+
+    /// Check for the outcome of the oldest pending packet
+    ///
+    /// Returns at most one outcome per call.
+    pub fn poll(&mut self) -> Result<Option<(PacketHandle, Outcome)>, D::Error> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+
+        let (status, fifo_status) =
+            self.tx.device().read_register::<FifoStatus>()?;
+        if status.tx_ds() {
+            // Report success before a possible MAX_RT of the next one
+            let mut clear = Status(0);
+            clear.set_tx_ds(true);
+            self.tx.device().write_register(clear)?;
+            return Ok(Some(self.pop(Outcome::Sent)));
+        }
+
+        if status.max_rt() {
+            let mut clear = Status(0);
+            clear.set_max_rt(true);
+
+            if self.head_attempts < self.head_retries {
+                // Clearing MAX_RT restarts the head packet
+                self.head_attempts += 1;
+                self.tx.device().write_register(clear)?;
+                return Ok(None);
+            }
+
+            // Remove only the head by flushing and re-queueing the
+            // others
+            let failed = self.pop(Outcome::Failed);
+            self.tx.flush_tx()?;
+            self.tx.device().write_register(clear)?;
+            for i in 0..self.len {
+                let slot = (self.head + i) % TX_FIFO_DEPTH;
+                let packet = self.slots[slot].1.clone();
+                self.tx.send(&packet)?;
+            }
+            // Not counted twice
+            self.tx.set_in_flight(self.len as u8);
+            return Ok(Some(failed));
+        }
+
+        if fifo_status.tx_empty() {
+            // Sent, but its TX_DS has been coalesced with an earlier one
+            return Ok(Some(self.pop(Outcome::Sent)));
+        }
+
+        // Resume after `check_dwell()`
+        self.tx.ce_enable();
+        Ok(None)
+    }
+
+    fn pop(&mut self, outcome: Outcome) -> (PacketHandle, Outcome) {
+        let (handle, _) = replace(&mut self.slots[self.head], (PacketHandle(0), Payload::new(&[])));
+        self.head = (self.head + 1) % TX_FIFO_DEPTH;
+        self.len -= 1;
+        self.head_attempts = 0;
+        self.tx.set_in_flight(self.len as u8);
+        (handle, outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use standby::StandbyMode;
+    use mock::MockRadio;
+
+    fn pipelined(radio: MockRadio) -> PipelinedTx<MockRadio> {
+        let tx = StandbyMode::power_up(radio).unwrap().tx().unwrap();
+        PipelinedTx::new(tx)
+    }
+
+    /// Outcomes until all have been reported
+    fn outcomes(pipelined: &mut PipelinedTx<MockRadio>) -> Vec<(PacketHandle, Outcome)> {
+        let mut outcomes = Vec::new();
+        for _ in 0..10 {
+            if let Some(outcome) = pipelined.poll().unwrap() {
+                outcomes.push(outcome);
+            }
+        }
+        assert!(pipelined.is_empty());
+        outcomes
+    }
+
+    fn queue_three(pipelined: &mut PipelinedTx<MockRadio>) -> [PacketHandle; 3] {
+        let handles = [
+            pipelined.queue(&[0]).unwrap(),
+            pipelined.queue(&[1]).unwrap(),
+            pipelined.queue(&[2]).unwrap(),
+        ];
+        assert!(matches!(pipelined.queue(&[3]), Err(nb::Error::WouldBlock)));
+        handles
+    }
+
+    #[test]
+    fn reports_in_fifo_order() {
+        let mut pipelined = pipelined(MockRadio::new());
+        let [a, b, c] = queue_three(&mut pipelined);
+        assert_eq!(outcomes(&mut pipelined), vec![(a, Outcome::Sent), (b, Outcome::Sent), (c, Outcome::Sent)]);
+        assert_eq!(pipelined.into_inner().in_flight(), 0);
+    }
+
+    #[test]
+    fn coalesced_tx_ds() {
+        let mut radio = MockRadio::new();
+        // All three sent before the first poll sees TX_DS
+        radio.burst = 3;
+        let mut pipelined = pipelined(radio);
+        let [a, b, c] = queue_three(&mut pipelined);
+        assert_eq!(outcomes(&mut pipelined), vec![(a, Outcome::Sent), (b, Outcome::Sent), (c, Outcome::Sent)]);
+        assert_eq!(pipelined.into_inner().in_flight(), 0);
+    }
+
+    #[test]
+    fn failure_spares_the_others() {
+        let mut radio = MockRadio::new();
+        radio.acks.extend(&[true, false, true]);
+        let mut pipelined = pipelined(radio);
+        let [a, b, c] = queue_three(&mut pipelined);
+        assert_eq!(outcomes(&mut pipelined), vec![(a, Outcome::Sent), (b, Outcome::Failed), (c, Outcome::Sent)]);
+
+        let mut tx = pipelined.into_inner();
+        assert_eq!(tx.in_flight(), 0);
+        let sent: Vec<_> = tx.device().sent.iter().map(|sent| (sent.data[0], sent.acked)).collect();
+        assert_eq!(sent, vec![(0, true), (1, false), (2, true)]);
+    }
+
+    #[test]
+    fn head_retries() {
+        let mut radio = MockRadio::new();
+        radio.acks.extend(&[false, false, true, false, false, false]);
+        let mut pipelined = pipelined(radio);
+        pipelined.set_head_retries(2);
+        let a = pipelined.queue(&[0]).unwrap();
+        let b = pipelined.queue(&[1]).unwrap();
+        assert_eq!(outcomes(&mut pipelined), vec![(a, Outcome::Sent), (b, Outcome::Failed)]);
+        assert_eq!(pipelined.into_inner().device().sent.len(), 6);
+    }
+}
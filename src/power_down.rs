@@ -0,0 +1,66 @@
+use core::fmt;
+use embedded_hal::blocking::delay::DelayUs;
+use device::Device;
+use standby::StandbyMode;
+use config::Configuration;
+
+/// Start-up time of the crystal oscillator from **Power Down** to
+/// **Standby-I**
+pub const POWER_UP_DELAY_US: u32 = 1_500;
+
+/// Represents **Power Down** mode
+///
+/// The chip keeps its registers, which can still be configured, but
+/// neither sends nor receives.
+pub struct PowerDownMode<D: Device> {
+    device: D,
+}
+
+impl<D: Device> fmt::Debug for PowerDownMode<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PowerDownMode")
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<D: Device + defmt::Format> defmt::Format for PowerDownMode<D> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "PowerDownMode {{ device: {} }}", self.device)
+    }
+}
+
+#[cfg(feature = "ufmt")]
+impl<D: Device + ufmt::uDebug> ufmt::uDebug for PowerDownMode<D> {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("PowerDownMode")?
+            .field("device", &self.device)?
+            .finish()
+    }
+}
+
+impl<D: Device> PowerDownMode<D> {
+    /// Relies on `PWR_UP` being cleared by
+    /// `StandbyMode::power_down()`, from which it is called
+    pub(crate) fn new(device: D) -> Self {
+        PowerDownMode { device }
+    }
+
+    pub(crate) fn into_device(self) -> D {
+        self.device
+    }
+
+    /// Go into **Standby-I**, waiting `POWER_UP_DELAY_US` for the
+    /// oscillator
+    pub fn power_up<DL: DelayUs<u32>>(self, delay: &mut DL) -> Result<StandbyMode<D>, (D, D::Error)> {
+        let standby = StandbyMode::power_up(self.device)?;
+        delay.delay_us(POWER_UP_DELAY_US);
+        Ok(standby)
+    }
+}
+
+impl<D: Device> Configuration for PowerDownMode<D> {
+    type Inner = D;
+    fn device(&mut self) -> &mut Self::Inner {
+        &mut self.device
+    }
+}
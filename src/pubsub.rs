@@ -0,0 +1,437 @@
+//! Publish/subscribe with numeric topics through a broker node
+//!
+//! Nodes send `Subscribe` packets with their own address to the
+//! `Broker`. Published packets are fanned out by the broker to each
+//! subscriber of their topic, acknowledged or not as requested by the
+//! publisher. Packets published with `retain` are kept by the broker
+//! and replayed to new subscribers.
+//!
+//! The broker receives in RX mode. `Broker::handle()` tells what to
+//! send, which is done from TX mode with `fan_out()` and `replay()`.
+//! A send that does not complete makes them fail with
+//! `Error::Timeout`.
+
+use embedded_hal::blocking::delay::DelayUs;
+use device::Device;
+use tx::{TxMode, SEND_TIMEOUT_US};
+use config::Configuration;
+use {MIN_ADDR_BYTES, MAX_ADDR_BYTES, MAX_PAYLOAD_BYTES};
+
+const SUBSCRIBE: u8 = 0x80;
+const UNSUBSCRIBE: u8 = 0x81;
+const PUBLISH: u8 = 0x82;
+const FLAG_RETAIN: u8 = 0b01;
+const FLAG_ACKED: u8 = 0b10;
+const PUBLISH_HEADER_LEN: usize = 4;
+/// Bytes of data per published packet
+pub const PUBSUB_DATA_BYTES: usize = MAX_PAYLOAD_BYTES - PUBLISH_HEADER_LEN;
+
+pub type TopicId = u16;
+
+/// A message on a topic
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Publish<'p> {
+    pub topic: TopicId,
+    pub data: &'p [u8],
+    /// Keep as last value of the topic. Empty data clears it.
+    pub retain: bool,
+    /// Deliver with acks
+    pub acked: bool,
+}
+
+impl<'p> Publish<'p> {
+    /// Encode into `buf`, which must hold 32 bytes
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        assert!(self.data.len() <= PUBSUB_DATA_BYTES);
+        buf[0] = PUBLISH;
+        buf[1] = self.topic as u8;
+        buf[2] = (self.topic >> 8) as u8;
+        buf[3] = 0;
+        if self.retain {
+            buf[3] |= FLAG_RETAIN;
+        }
+        if self.acked {
+            buf[3] |= FLAG_ACKED;
+        }
+        let len = PUBLISH_HEADER_LEN + self.data.len();
+        buf[PUBLISH_HEADER_LEN..len].copy_from_slice(self.data);
+        &buf[0..len]
+    }
+
+    /// `None` if `packet` is not published
+    pub fn decode(packet: &'p [u8]) -> Option<Self> {
+        if packet.len() < PUBLISH_HEADER_LEN || packet[0] != PUBLISH {
+            return None;
+        }
+        Some(Publish {
+            topic: (packet[1] as u16) | ((packet[2] as u16) << 8),
+            data: &packet[PUBLISH_HEADER_LEN..],
+            retain: packet[3] & FLAG_RETAIN != 0,
+            acked: packet[3] & FLAG_ACKED != 0,
+        })
+    }
+}
+
+/// Request to the broker to start or stop sending a topic to `addr`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Subscribe<'p> {
+    pub topic: TopicId,
+    pub addr: &'p [u8],
+    /// `false` to unsubscribe
+    pub subscribe: bool,
+}
+
+impl<'p> Subscribe<'p> {
+    /// Encode into `buf`, which must hold 8 bytes
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        assert!(self.addr.len() >= MIN_ADDR_BYTES && self.addr.len() <= MAX_ADDR_BYTES);
+        buf[0] = if self.subscribe { SUBSCRIBE } else { UNSUBSCRIBE };
+        buf[1] = self.topic as u8;
+        buf[2] = (self.topic >> 8) as u8;
+        let len = 3 + self.addr.len();
+        buf[3..len].copy_from_slice(self.addr);
+        &buf[0..len]
+    }
+
+    /// `None` if `packet` is no subscription
+    pub fn decode(packet: &'p [u8]) -> Option<Self> {
+        if packet.len() < 3 + MIN_ADDR_BYTES || packet.len() > 3 + MAX_ADDR_BYTES {
+            return None;
+        }
+        let subscribe = match packet[0] {
+            SUBSCRIBE => true,
+            UNSUBSCRIBE => false,
+            _ => return None,
+        };
+        Some(Subscribe {
+            topic: (packet[1] as u16) | ((packet[2] as u16) << 8),
+            addr: &packet[3..],
+            subscribe,
+        })
+    }
+}
+
+/// Slot of the `Broker`'s subscription table
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Subscription {
+    pub topic: TopicId,
+    addr: [u8; MAX_ADDR_BYTES],
+    addr_len: u8,
+}
+
+impl Subscription {
+    fn new(topic: TopicId, addr: &[u8]) -> Self {
+        let mut subscription = Subscription {
+            topic,
+            addr: [0; MAX_ADDR_BYTES],
+            addr_len: addr.len() as u8,
+        };
+        subscription.addr[0..addr.len()].copy_from_slice(addr);
+        subscription
+    }
+
+    pub fn addr(&self) -> &[u8] {
+        &self.addr[0..self.addr_len as usize]
+    }
+}
+
+/// Slot of the `Broker`'s table of retained packets
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Retained {
+    pub topic: TopicId,
+    data: [u8; PUBSUB_DATA_BYTES],
+    len: u8,
+}
+
+impl Retained {
+    pub fn data(&self) -> &[u8] {
+        &self.data[0..self.len as usize]
+    }
+}
+
+/// Result of `Broker::handle()`
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BrokerEvent {
+    /// Not a pub/sub packet
+    Ignored,
+    /// A node has subscribed. Send it the retained packet with
+    /// `replay()`.
+    Subscribed(Subscription),
+    Unsubscribed(TopicId),
+    /// The subscription table is full
+    Full(TopicId),
+    /// Send the packet to the subscribers with `fan_out()`
+    Published(TopicId),
+}
+
+/// Keeps subscriptions and retained packets in caller-provided
+/// tables, see the module documentation
+#[derive(Debug)]
+pub struct Broker<'a> {
+    subscriptions: &'a mut [Option<Subscription>],
+    retained: &'a mut [Option<Retained>],
+}
+
+impl<'a> Broker<'a> {
+    /// The table sizes limit the number of subscriptions and
+    /// retained topics
+    pub fn new(subscriptions: &'a mut [Option<Subscription>], retained: &'a mut [Option<Retained>]) -> Self {
+        Broker {
+            subscriptions,
+            retained,
+        }
+    }
+
+    /// Subscriptions of all nodes
+    pub fn subscriptions(&self) -> impl Iterator<Item = &Subscription> {
+        self.subscriptions.iter().filter_map(|slot| slot.as_ref())
+    }
+
+    /// Process a packet received by the broker
+    pub fn handle(&mut self, packet: &[u8]) -> BrokerEvent {
+        if let Some(subscribe) = Subscribe::decode(packet) {
+            return self.subscribe(subscribe);
+        }
+        if let Some(publish) = Publish::decode(packet) {
+            if publish.retain {
+                self.retain(&publish);
+            }
+            return BrokerEvent::Published(publish.topic);
+        }
+        BrokerEvent::Ignored
+    }
+
+    fn subscribe(&mut self, subscribe: Subscribe) -> BrokerEvent {
+        let subscription = Subscription::new(subscribe.topic, subscribe.addr);
+        let existing = self.subscriptions.iter()
+            .position(|slot| *slot == Some(subscription));
+
+        if !subscribe.subscribe {
+            if let Some(i) = existing {
+                self.subscriptions[i] = None;
+            }
+            return BrokerEvent::Unsubscribed(subscribe.topic);
+        }
+
+        if existing.is_none() {
+            match self.subscriptions.iter().position(|slot| slot.is_none()) {
+                Some(i) => self.subscriptions[i] = Some(subscription),
+                None => return BrokerEvent::Full(subscribe.topic),
+            }
+        }
+        BrokerEvent::Subscribed(subscription)
+    }
+
+    fn retain(&mut self, publish: &Publish) {
+        let existing = self.retained.iter()
+            .position(|slot| matches!(*slot, Some(ref retained) if retained.topic == publish.topic));
+        if publish.data.is_empty() {
+            if let Some(i) = existing {
+                self.retained[i] = None;
+            }
+            return;
+        }
+
+        let mut retained = Retained {
+            topic: publish.topic,
+            data: [0; PUBSUB_DATA_BYTES],
+            len: publish.data.len() as u8,
+        };
+        retained.data[0..publish.data.len()].copy_from_slice(publish.data);
+        // Without a free slot, the value is not retained
+        if let Some(i) = existing.or_else(|| self.retained.iter().position(|slot| slot.is_none())) {
+            self.retained[i] = Some(retained);
+        }
+    }
+
+    /// The retained packet of `topic`, if any
+    pub fn retained(&self, topic: TopicId) -> Option<&Retained> {
+        self.retained.iter()
+            .filter_map(|slot| slot.as_ref())
+            .find(|retained| retained.topic == topic)
+    }
+
+    /// Send a published `packet` to all subscribers of its topic.
+    /// Returns the number of subscribers it was delivered to, which
+    /// counts packets sent without ack as delivered.
+    ///
+    /// Requires `set_dynamic_ack(true)` for packets published without
+    /// `acked`. Changes the TX address.
+    pub fn fan_out<D: Device, DL: DelayUs<u32>>(&self, tx: &mut TxMode<D>, delay: &mut DL, packet: &[u8]) -> Result<usize, D::Error> {
+        let publish = match Publish::decode(packet) {
+            Some(publish) => publish,
+            None => return Ok(0),
+        };
+
+        let mut delivered = 0;
+        for subscription in self.subscriptions() {
+            if subscription.topic != publish.topic {
+                continue;
+            }
+            if send(tx, delay, subscription.addr(), packet, publish.acked)? {
+                delivered += 1;
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Send the retained packet of its topic, if any, to a new
+    /// subscriber. Returns whether it was delivered.
+    pub fn replay<D: Device, DL: DelayUs<u32>>(&self, tx: &mut TxMode<D>, delay: &mut DL, subscription: &Subscription) -> Result<bool, D::Error> {
+        let topic = subscription.topic;
+        let retained = match self.retained(topic) {
+            Some(retained) => retained,
+            None => return Ok(false),
+        };
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let packet = Publish {
+            topic,
+            data: retained.data(),
+            retain: true,
+            acked: true,
+        }.encode(&mut buf);
+        send(tx, delay, subscription.addr(), packet, true)
+    }
+}
+
+fn send<D: Device, DL: DelayUs<u32>>(tx: &mut TxMode<D>, delay: &mut DL, addr: &[u8], packet: &[u8], acked: bool) -> Result<bool, D::Error> {
+    tx.set_tx_addr(addr)?;
+    if acked {
+        tx.send_sync_timeout(packet, delay, SEND_TIMEOUT_US)
+    } else {
+        tx.send_no_ack(packet)?;
+        tx.wait_empty_timeout(delay, SEND_TIMEOUT_US)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use mock::{MockRadio, NoDelay};
+    use standby::StandbyMode;
+
+    const A: &[u8] = b"node1";
+    const B: &[u8] = b"node2";
+
+    fn subscribe<'p>(topic: TopicId, addr: &[u8], subscribe: bool, buf: &'p mut [u8]) -> &'p [u8] {
+        Subscribe { topic, addr, subscribe }.encode(buf)
+    }
+
+    fn publish<'p>(topic: TopicId, data: &[u8], retain: bool, acked: bool, buf: &'p mut [u8]) -> &'p [u8] {
+        Publish { topic, data, retain, acked }.encode(buf)
+    }
+
+    #[test]
+    fn codec() {
+        let mut buf = [0; 32];
+        let packet = subscribe(0x1234, A, true, &mut buf);
+        assert_eq!(Subscribe::decode(packet), Some(Subscribe { topic: 0x1234, addr: A, subscribe: true }));
+        assert_eq!(Publish::decode(packet), None);
+
+        let packet = publish(7, b"data", true, false, &mut buf);
+        assert_eq!(Publish::decode(packet), Some(Publish { topic: 7, data: b"data", retain: true, acked: false }));
+        assert_eq!(Subscribe::decode(packet), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn short_addr() {
+        subscribe(1, b"ab", true, &mut [0; 8]);
+    }
+
+    #[test]
+    fn subscriptions() {
+        let mut subscriptions = [None; 2];
+        let mut retained = [None; 1];
+        let mut broker = Broker::new(&mut subscriptions, &mut retained);
+        let mut buf = [0; 32];
+
+        assert_eq!(broker.handle(subscribe(1, A, true, &mut buf)), BrokerEvent::Subscribed(Subscription::new(1, A)));
+        // Subscribing again keeps a single slot
+        assert_eq!(broker.handle(subscribe(1, A, true, &mut buf)), BrokerEvent::Subscribed(Subscription::new(1, A)));
+        assert_eq!(broker.handle(subscribe(1, B, true, &mut buf)), BrokerEvent::Subscribed(Subscription::new(1, B)));
+        assert_eq!(broker.handle(subscribe(2, A, true, &mut buf)), BrokerEvent::Full(2));
+        assert_eq!(broker.subscriptions().count(), 2);
+
+        assert_eq!(broker.handle(subscribe(1, A, false, &mut buf)), BrokerEvent::Unsubscribed(1));
+        let addrs: Vec<&[u8]> = broker.subscriptions().map(|s| s.addr()).collect();
+        assert_eq!(addrs, vec![B]);
+        assert_eq!(broker.handle(subscribe(2, A, true, &mut buf)), BrokerEvent::Subscribed(Subscription::new(2, A)));
+        assert_eq!(broker.handle(b"other"), BrokerEvent::Ignored);
+    }
+
+    #[test]
+    fn retain() {
+        let mut subscriptions = [None; 1];
+        let mut retained = [None; 2];
+        let mut broker = Broker::new(&mut subscriptions, &mut retained);
+        let mut buf = [0; 32];
+
+        assert_eq!(broker.handle(publish(1, b"old", true, true, &mut buf)), BrokerEvent::Published(1));
+        broker.handle(publish(1, b"new", true, true, &mut buf));
+        broker.handle(publish(2, b"two", true, true, &mut buf));
+        // Neither retained nor room for it
+        broker.handle(publish(3, b"not", false, true, &mut buf));
+        broker.handle(publish(4, b"full", true, true, &mut buf));
+        assert_eq!(broker.retained(1).map(Retained::data), Some(&b"new"[..]));
+        assert_eq!(broker.retained(2).map(Retained::data), Some(&b"two"[..]));
+        assert_eq!(broker.retained(3), None);
+        assert_eq!(broker.retained(4), None);
+
+        // Empty data clears the topic
+        broker.handle(publish(1, b"", true, true, &mut buf));
+        assert_eq!(broker.retained(1), None);
+        broker.handle(publish(4, b"four", true, true, &mut buf));
+        assert_eq!(broker.retained(4).map(Retained::data), Some(&b"four"[..]));
+    }
+
+    #[test]
+    fn fan_out() {
+        let mut subscriptions = [None; 3];
+        let mut retained = [None; 1];
+        let mut broker = Broker::new(&mut subscriptions, &mut retained);
+        let mut buf = [0; 32];
+        broker.handle(subscribe(1, A, true, &mut buf));
+        broker.handle(subscribe(2, A, true, &mut buf));
+        broker.handle(subscribe(1, B, true, &mut buf));
+        let mut tx = StandbyMode::power_up(MockRadio::new()).unwrap().tx().unwrap();
+
+        let packet = publish(1, b"x", false, true, &mut buf);
+        tx.device().acks.extend(&[true, false]);
+        assert_eq!(broker.fan_out(&mut tx, &mut NoDelay, packet).unwrap(), 1);
+        let addrs: Vec<&[u8]> = tx.device().sent.iter().map(|sent| &sent.addr[..]).collect();
+        assert_eq!(addrs, vec![A, B]);
+
+        // Without acks, every send counts as delivered
+        tx.device().sent.clear();
+        let packet = publish(1, b"x", false, false, &mut buf);
+        assert_eq!(broker.fan_out(&mut tx, &mut NoDelay, packet).unwrap(), 2);
+        assert_eq!(tx.device().sent.len(), 2);
+
+        tx.device().stuck = true;
+        assert!(broker.fan_out(&mut tx, &mut NoDelay, packet).is_err());
+    }
+
+    #[test]
+    fn replay() {
+        let mut subscriptions = [None; 1];
+        let mut retained = [None; 1];
+        let mut broker = Broker::new(&mut subscriptions, &mut retained);
+        let mut buf = [0; 32];
+        broker.handle(publish(1, b"value", true, false, &mut buf));
+        let mut tx = StandbyMode::power_up(MockRadio::new()).unwrap().tx().unwrap();
+
+        let subscription = match broker.handle(subscribe(1, A, true, &mut buf)) {
+            BrokerEvent::Subscribed(subscription) => subscription,
+            event => panic!("{:?}", event),
+        };
+        assert!(broker.replay(&mut tx, &mut NoDelay, &subscription).unwrap());
+        let expected = publish(1, b"value", true, true, &mut buf).to_vec();
+        assert_eq!(tx.device().sent[0].data, expected);
+        assert_eq!(tx.device().sent[0].addr, A.to_vec());
+
+        assert!(!broker.replay(&mut tx, &mut NoDelay, &Subscription::new(2, A)).unwrap());
+        assert_eq!(tx.device().sent.len(), 1);
+    }
+}
@@ -2,9 +2,42 @@
 
 use {PIPES_COUNT, MIN_ADDR_BYTES, MAX_ADDR_BYTES};
 
+/// Number of addresses in the register map
+pub const REGISTERS_COUNT: usize = 0x1E;
+
+/// Registers that the chip changes on its own: `STATUS`,
+/// `OBSERVE_TX`, `RPD` and `FIFO_STATUS`
+pub fn is_volatile(addr: u8) -> bool {
+    matches!(addr, 0x07 | 0x08 | 0x09 | 0x17)
+}
+
+/// Bits of a register that read back as they were written. Zero for
+/// `STATUS` (write 1 to clear) and read-only or reserved registers.
+pub fn writable_mask(addr: u8) -> u8 {
+    match addr {
+        // CONFIG, RF_CH
+        0x00 | 0x05 => 0b0111_1111,
+        // EN_AA, EN_RXADDR, RX_PW_Px, DYNPD
+        0x01 | 0x02 | 0x11..=0x16 | 0x1C => 0b0011_1111,
+        // SETUP_AW
+        0x03 => 0b0000_0011,
+        // SETUP_RETR, addresses
+        0x04 | 0x0A..=0x10 => 0b1111_1111,
+        // RF_SETUP
+        0x06 => 0b1011_1110,
+        // FEATURE
+        0x1D => 0b0000_0111,
+        _ => 0,
+    }
+}
+
 pub trait Register {
     /// Address in the register map
     fn addr() -> u8;
+    /// Whether the chip changes this register on its own
+    fn is_volatile() -> bool {
+        is_volatile(Self::addr())
+    }
 
     fn read_len() -> usize;
     fn write_len(&self) -> usize {
@@ -171,6 +204,31 @@ bitfield! {
     pub prim_rx, set_prim_rx: 0;
 }
 impl_register!(Config, 0x00);
+#[cfg(feature = "defmt")]
+impl defmt::Format for Config {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "Config {{ mask_rx_dr: {=bool}, mask_tx_ds: {=bool}, mask_max_rt: {=bool}, en_crc: {=bool}, crco: {=bool}, pwr_up: {=bool}, prim_rx: {=bool} }}",
+            self.mask_rx_dr(), self.mask_tx_ds(), self.mask_max_rt(),
+            self.en_crc(), self.crco(), self.pwr_up(), self.prim_rx()
+        )
+    }
+}
+#[cfg(feature = "ufmt")]
+impl ufmt::uDebug for Config {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("Config")?
+            .field("mask_rx_dr", &self.mask_rx_dr())?
+            .field("mask_tx_ds", &self.mask_tx_ds())?
+            .field("mask_max_rt", &self.mask_max_rt())?
+            .field("en_crc", &self.en_crc())?
+            .field("crco", &self.crco())?
+            .field("pwr_up", &self.pwr_up())?
+            .field("prim_rx", &self.prim_rx())?
+            .finish()
+    }
+}
 
 /// Enable Auto Acknowledgment
 #[derive(Debug)]
@@ -235,6 +293,26 @@ bitfield! {
     pub u8, rf_pwr, set_rf_pwr: 2, 1;
 }
 impl_register!(RfSetup, 0x06);
+#[cfg(feature = "defmt")]
+impl defmt::Format for RfSetup {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "RfSetup {{ rf_dr_low: {=bool}, rf_dr_high: {=bool}, rf_pwr: {=u8} }}",
+            self.rf_dr_low(), self.rf_dr_high(), self.rf_pwr()
+        )
+    }
+}
+#[cfg(feature = "ufmt")]
+impl ufmt::uDebug for RfSetup {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("RfSetup")?
+            .field("rf_dr_low", &self.rf_dr_low())?
+            .field("rf_dr_high", &self.rf_dr_high())?
+            .field("rf_pwr", &self.rf_pwr())?
+            .finish()
+    }
+}
 
 bitfield! {
     /// Status register, always received on MISO while command is sent
@@ -254,6 +332,28 @@ bitfield! {
     pub tx_full, _: 0;
 }
 impl_register!(Status, 0x07);
+#[cfg(feature = "defmt")]
+impl defmt::Format for Status {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "Status {{ rx_dr: {=bool}, tx_ds: {=bool}, max_rt: {=bool}, rx_p_no: {=u8}, tx_full: {=bool} }}",
+            self.rx_dr(), self.tx_ds(), self.max_rt(), self.rx_p_no(), self.tx_full()
+        )
+    }
+}
+#[cfg(feature = "ufmt")]
+impl ufmt::uDebug for Status {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("Status")?
+            .field("rx_dr", &self.rx_dr())?
+            .field("tx_ds", &self.tx_ds())?
+            .field("max_rt", &self.max_rt())?
+            .field("rx_p_no", &self.rx_p_no())?
+            .field("tx_full", &self.tx_full())?
+            .finish()
+    }
+}
 
 bitfield! {
     pub struct ObserveTx(u8);
@@ -263,6 +363,25 @@ bitfield! {
     pub u8, arc_cnt, _: 3, 0;
 }
 impl_register!(ObserveTx, 0x08);
+#[cfg(feature = "defmt")]
+impl defmt::Format for ObserveTx {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "ObserveTx {{ plos_cnt: {=u8}, arc_cnt: {=u8} }}",
+            self.plos_cnt(), self.arc_cnt()
+        )
+    }
+}
+#[cfg(feature = "ufmt")]
+impl ufmt::uDebug for ObserveTx {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("ObserveTx")?
+            .field("plos_cnt", &self.plos_cnt())?
+            .field("arc_cnt", &self.arc_cnt())?
+            .finish()
+    }
+}
 
 def_address_register!(RxAddrP0, 0x0A);
 def_address_register!(RxAddrP1, 0x0B);
@@ -315,6 +434,28 @@ bitfield! {
     pub rx_empty, _: 0;
 }
 impl_register!(FifoStatus, 0x17);
+#[cfg(feature = "defmt")]
+impl defmt::Format for FifoStatus {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "FifoStatus {{ tx_reuse: {=bool}, tx_full: {=bool}, tx_empty: {=bool}, rx_full: {=bool}, rx_empty: {=bool} }}",
+            self.tx_reuse(), self.tx_full(), self.tx_empty(), self.rx_full(), self.rx_empty()
+        )
+    }
+}
+#[cfg(feature = "ufmt")]
+impl ufmt::uDebug for FifoStatus {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("FifoStatus")?
+            .field("tx_reuse", &self.tx_reuse())?
+            .field("tx_full", &self.tx_full())?
+            .field("tx_empty", &self.tx_empty())?
+            .field("rx_full", &self.rx_full())?
+            .field("rx_empty", &self.rx_empty())?
+            .finish()
+    }
+}
 
 /// Enable Dynamic Payload length
 pub struct Dynpd(pub u8);
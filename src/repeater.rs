@@ -0,0 +1,402 @@
+//! Multi-hop store-and-forward networking
+//!
+//! Nodes have a one-byte `MeshNode` id. The address of a node is
+//! the id followed by the 4-byte network base, so that up to five
+//! neighbours share the upper address bytes of pipes 1 to 5.
+//!
+//! Packets carry their source, destination, a sequence number, a TTL
+//! and the last hop. A `Repeater` receives them in RX mode, delivers
+//! those addressed to itself and forwards the rest from TX mode along
+//! its route table. Routes are learned from received traffic: the
+//! source of a packet is reachable through its last hop. A send that
+//! does not complete makes `relay()` fail with `Error::Timeout`.
+
+use embedded_hal::blocking::delay::DelayUs;
+use device::Device;
+use tx::{TxMode, SEND_TIMEOUT_US};
+use payload::Payload;
+use config::Configuration;
+use {PIPES_COUNT, MAX_PAYLOAD_BYTES};
+
+const MESH: u8 = 0xB0;
+const HEADER_LEN: usize = 6;
+/// Bytes of data per packet
+pub const MESH_DATA_BYTES: usize = MAX_PAYLOAD_BYTES - HEADER_LEN;
+/// Recent packets remembered for duplicate suppression
+const SEEN_LEN: usize = 16;
+/// Next hops tried per packet
+pub const MAX_ATTEMPTS: usize = 4;
+
+pub type MeshNode = u8;
+
+/// Slot of the `Repeater`'s route table
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MeshRoute {
+    pub dst: MeshNode,
+    pub next_hop: MeshNode,
+    /// Hops to `dst` through `next_hop`
+    pub hops: u8,
+    /// Sends to `next_hop` that reached MAX_RT in a row
+    pub failures: u8,
+}
+
+/// What to do with a received packet, see `Repeater::handle()`
+#[derive(Debug, PartialEq)]
+pub enum RelayAction<'p> {
+    /// Not a mesh packet, or received on another pipe
+    Ignored,
+    /// Seen before
+    Duplicate,
+    /// Addressed to this node
+    Deliver { src: MeshNode, data: &'p [u8] },
+    /// Pass `packet` to `Repeater::relay()`
+    Forward { dst: MeshNode, packet: &'p [u8] },
+    /// Its TTL has run out
+    Expired,
+}
+
+/// Result of `Repeater::relay()` and `send()`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RelayOutcome {
+    /// Acknowledged by this next hop
+    Sent(MeshNode),
+    /// No route, all routes failed, or the data exceeds
+    /// `MESH_DATA_BYTES`
+    Undeliverable,
+}
+
+/// Repeater role, see the module documentation
+pub struct Repeater<'a> {
+    node: MeshNode,
+    base: [u8; 4],
+    pipes: [bool; PIPES_COUNT],
+    routes: &'a mut [Option<MeshRoute>],
+    default_route: Option<MeshNode>,
+    seen: [Option<(MeshNode, u8)>; SEEN_LEN],
+    next_seen: usize,
+    next_seq: u8,
+    max_ttl: u8,
+    max_failures: u8,
+}
+
+impl<'a> Repeater<'a> {
+    /// Sends with up to `max_ttl` hops. Routes are learned into the
+    /// `routes` table.
+    pub fn new(node: MeshNode, base: [u8; 4], routes: &'a mut [Option<MeshRoute>], max_ttl: u8) -> Self {
+        Repeater {
+            node,
+            base,
+            pipes: [false, true, false, false, false, false],
+            routes,
+            default_route: None,
+            seen: [None; SEEN_LEN],
+            next_seen: 0,
+            next_seq: 0,
+            max_ttl,
+            max_failures: 3,
+        }
+    }
+
+    pub fn node(&self) -> MeshNode {
+        self.node
+    }
+
+    /// Address of `node` in this network
+    pub fn address(&self, node: MeshNode) -> [u8; 5] {
+        [node, self.base[0], self.base[1], self.base[2], self.base[3]]
+    }
+
+    /// Receive mesh packets on own address, on pipe 1
+    pub fn configure<C: Configuration>(&self, radio: &mut C) -> Result<(), <<C as Configuration>::Inner as Device>::Error> {
+        radio.set_rx_addr(1, &self.address(self.node))?;
+        let mut enable = self.pipes;
+        // Pipe 0 receives acks
+        enable[0] = true;
+        radio.set_pipes_rx_enable(&enable)
+    }
+
+    /// Pipes on which mesh packets are accepted, pipe 1 by default
+    pub fn set_pipes(&mut self, pipes: [bool; PIPES_COUNT]) {
+        self.pipes = pipes;
+    }
+
+    /// Next hop for destinations without a route
+    pub fn set_default_route(&mut self, next_hop: Option<MeshNode>) {
+        self.default_route = next_hop;
+    }
+
+    /// Drop a route after `max_failures` sends in a row reached
+    /// MAX_RT. Defaults to 3.
+    pub fn set_max_failures(&mut self, max_failures: u8) {
+        self.max_failures = max_failures.max(1);
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &MeshRoute> {
+        self.routes.iter().filter_map(|slot| slot.as_ref())
+    }
+
+    /// Add a route, or update the one to `dst` through `next_hop`
+    pub fn add_route(&mut self, dst: MeshNode, next_hop: MeshNode, hops: u8) {
+        if dst == self.node {
+            return;
+        }
+        let route = MeshRoute { dst, next_hop, hops, failures: 0 };
+        let existing = self.routes.iter()
+            .position(|slot| matches!(*slot, Some(ref r) if r.dst == dst && r.next_hop == next_hop));
+        if let Some(i) = existing {
+            self.routes[i] = Some(route);
+            return;
+        }
+        if let Some(i) = self.routes.iter().position(|slot| slot.is_none()) {
+            self.routes[i] = Some(route);
+            return;
+        }
+        // Replace the longest route, if longer
+        let longest = self.routes.iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.map(|r| (i, r.hops)))
+            .max_by_key(|&(_, hops)| hops);
+        if let Some((i, longest_hops)) = longest {
+            if longest_hops > hops {
+                self.routes[i] = Some(route);
+            }
+        }
+    }
+
+    /// Forget all routes through `next_hop`
+    pub fn remove_next_hop(&mut self, next_hop: MeshNode) {
+        for slot in self.routes.iter_mut() {
+            if matches!(*slot, Some(ref r) if r.next_hop == next_hop) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Remember `(src, seq)`. Returns `false` if it has been seen.
+    fn check_seen(&mut self, src: MeshNode, seq: u8) -> bool {
+        if self.seen.contains(&Some((src, seq))) {
+            return false;
+        }
+        self.seen[self.next_seen] = Some((src, seq));
+        self.next_seen = (self.next_seen + 1) % SEEN_LEN;
+        true
+    }
+
+    /// Process a received packet, writing a packet to forward into
+    /// `buf`
+    pub fn handle<'p>(&mut self, payload: &'p Payload, buf: &'p mut [u8; MAX_PAYLOAD_BYTES]) -> RelayAction<'p> {
+        let pipe_no = payload.pipe() as usize;
+        if pipe_no >= PIPES_COUNT || !self.pipes[pipe_no] {
+            return RelayAction::Ignored;
+        }
+        let packet: &'p [u8] = payload;
+        if packet.len() < HEADER_LEN || packet[0] != MESH {
+            return RelayAction::Ignored;
+        }
+        let (src, dst, seq, ttl, hop) = (packet[1], packet[2], packet[3], packet[4], packet[5]);
+        if src == self.node || !self.check_seen(src, seq) {
+            return RelayAction::Duplicate;
+        }
+
+        // It came from `src` over this many hops
+        let hops = self.max_ttl.saturating_sub(ttl).saturating_add(1);
+        self.add_route(hop, hop, 1);
+        self.add_route(src, hop, hops);
+
+        if dst == self.node {
+            return RelayAction::Deliver { src, data: &packet[HEADER_LEN..] };
+        }
+        if ttl <= 1 {
+            return RelayAction::Expired;
+        }
+        buf[0..packet.len()].copy_from_slice(packet);
+        buf[4] = ttl - 1;
+        buf[5] = self.node;
+        RelayAction::Forward { dst, packet: &buf[0..packet.len()] }
+    }
+
+    /// Send a packet to its next hop from TX mode. On MAX_RT, fails
+    /// over to the next best route, trying up to `MAX_ATTEMPTS` next
+    /// hops. Changes the TX address.
+    pub fn relay<D: Device, DL: DelayUs<u32>>(&mut self, tx: &mut TxMode<D>, delay: &mut DL, dst: MeshNode, packet: &[u8]) -> Result<RelayOutcome, D::Error> {
+        let mut tried = [0; MAX_ATTEMPTS];
+        for attempt in 0..MAX_ATTEMPTS {
+            let untried = |hop: &MeshNode| !tried[0..attempt].contains(hop);
+            let next_hop = self.routes()
+                .filter(|r| r.dst == dst && untried(&r.next_hop))
+                .min_by_key(|r| (r.failures, r.hops))
+                .map(|r| r.next_hop)
+                .or_else(|| self.default_route.filter(|hop| untried(hop)));
+            let next_hop = match next_hop {
+                Some(next_hop) => next_hop,
+                None => break,
+            };
+            tried[attempt] = next_hop;
+
+            tx.set_tx_addr(&self.address(next_hop))?;
+            tx.send(packet)?;
+            let acked = tx.wait_empty_timeout(delay, SEND_TIMEOUT_US)?;
+            let max_failures = self.max_failures;
+            for slot in self.routes.iter_mut() {
+                if let Some(ref mut route) = *slot {
+                    if route.next_hop == next_hop {
+                        route.failures = if acked { 0 } else { route.failures.saturating_add(1) };
+                    }
+                }
+                // Dead next hop
+                if matches!(*slot, Some(ref r) if r.failures >= max_failures) {
+                    *slot = None;
+                }
+            }
+            if acked {
+                return Ok(RelayOutcome::Sent(next_hop));
+            }
+        }
+        Ok(RelayOutcome::Undeliverable)
+    }
+
+    /// Send `data` to `dst` from this node
+    pub fn send<D: Device, DL: DelayUs<u32>>(&mut self, tx: &mut TxMode<D>, delay: &mut DL, dst: MeshNode, data: &[u8]) -> Result<RelayOutcome, D::Error> {
+        if data.len() > MESH_DATA_BYTES {
+            return Ok(RelayOutcome::Undeliverable);
+        }
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        // Suppress echoes of our own packet
+        self.check_seen(self.node, seq);
+
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        buf[0] = MESH;
+        buf[1] = self.node;
+        buf[2] = dst;
+        buf[3] = seq;
+        buf[4] = self.max_ttl;
+        buf[5] = self.node;
+        let len = HEADER_LEN + data.len();
+        buf[HEADER_LEN..len].copy_from_slice(data);
+        self.relay(tx, delay, dst, &buf[0..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use mock::{MockRadio, NoDelay};
+    use standby::StandbyMode;
+
+    const BASE: [u8; 4] = [0xA1, 0xA2, 0xA3, 0xA4];
+
+    fn received(pipe: u8, src: MeshNode, dst: MeshNode, seq: u8, ttl: u8, hop: MeshNode) -> Payload {
+        let mut payload = Payload::new(&[MESH, src, dst, seq, ttl, hop, 0x42]);
+        payload.set_pipe(pipe);
+        payload
+    }
+
+    fn next_hops(tx: &mut TxMode<MockRadio>) -> Vec<MeshNode> {
+        tx.device().sent.iter().map(|sent| sent.addr[0]).collect()
+    }
+
+    #[test]
+    fn learn_routes() {
+        let mut routes = [None; 4];
+        let mut repeater = Repeater::new(1, BASE, &mut routes, 4);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+
+        // From 9 over 2 hops, last through 3
+        let payload = received(1, 9, 1, 0, 3, 3);
+        assert_eq!(repeater.handle(&payload, &mut buf), RelayAction::Deliver { src: 9, data: &[0x42] });
+        let routes: Vec<MeshRoute> = repeater.routes().cloned().collect();
+        assert_eq!(routes, vec![
+            MeshRoute { dst: 3, next_hop: 3, hops: 1, failures: 0 },
+            MeshRoute { dst: 9, next_hop: 3, hops: 2, failures: 0 },
+        ]);
+
+        let payload = received(2, 8, 1, 0, 4, 8);
+        assert_eq!(repeater.handle(&payload, &mut buf), RelayAction::Ignored);
+        assert_eq!(repeater.routes().count(), 2);
+    }
+
+    #[test]
+    fn replace_longest_route() {
+        let mut routes = [None; 2];
+        let mut repeater = Repeater::new(1, BASE, &mut routes, 4);
+        repeater.add_route(5, 2, 3);
+        repeater.add_route(6, 2, 1);
+        repeater.add_route(7, 3, 4);
+        repeater.add_route(8, 3, 2);
+        let dsts: Vec<MeshNode> = repeater.routes().map(|r| r.dst).collect();
+        assert_eq!(dsts, vec![8, 6]);
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut routes = [None; 4];
+        let mut repeater = Repeater::new(1, BASE, &mut routes, 4);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+
+        let payload = received(1, 9, 5, 7, 4, 9);
+        assert!(matches!(repeater.handle(&payload, &mut buf), RelayAction::Forward { dst: 5, .. }));
+        // Same packet through another hop
+        let payload = received(1, 9, 5, 7, 3, 3);
+        assert_eq!(repeater.handle(&payload, &mut buf), RelayAction::Duplicate);
+        let payload = received(1, 9, 5, 8, 4, 9);
+        assert!(matches!(repeater.handle(&payload, &mut buf), RelayAction::Forward { .. }));
+        // Echo of our own packet
+        let payload = received(1, 1, 5, 0, 3, 3);
+        assert_eq!(repeater.handle(&payload, &mut buf), RelayAction::Duplicate);
+    }
+
+    #[test]
+    fn ttl() {
+        let mut routes = [None; 4];
+        let mut repeater = Repeater::new(1, BASE, &mut routes, 4);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+
+        let payload = received(1, 9, 5, 0, 2, 9);
+        assert_eq!(repeater.handle(&payload, &mut buf), RelayAction::Forward {
+            dst: 5,
+            packet: &[MESH, 9, 5, 0, 1, 1, 0x42],
+        });
+        let payload = received(1, 9, 5, 1, 1, 9);
+        assert_eq!(repeater.handle(&payload, &mut buf), RelayAction::Expired);
+        // Delivered regardless
+        let payload = received(1, 9, 1, 2, 1, 9);
+        assert!(matches!(repeater.handle(&payload, &mut buf), RelayAction::Deliver { .. }));
+    }
+
+    #[test]
+    fn failover() {
+        let mut routes = [None; 4];
+        let mut repeater = Repeater::new(1, BASE, &mut routes, 4);
+        repeater.set_max_failures(2);
+        repeater.add_route(9, 2, 1);
+        repeater.add_route(9, 3, 2);
+        repeater.set_default_route(Some(4));
+        let mut tx = StandbyMode::power_up(MockRadio::new()).unwrap().tx().unwrap();
+
+        // Shortest route first, then the next best
+        tx.device().acks.extend(&[false]);
+        assert_eq!(repeater.send(&mut tx, &mut NoDelay, 9, b"a").unwrap(), RelayOutcome::Sent(3));
+        assert_eq!(next_hops(&mut tx), vec![2, 3]);
+        assert_eq!(tx.device().sent[0].addr, vec![2, 0xA1, 0xA2, 0xA3, 0xA4]);
+
+        // Fewest failures first; failing again drops the route through 2
+        tx.device().sent.clear();
+        tx.device().acks.extend(&[false, false]);
+        assert_eq!(repeater.send(&mut tx, &mut NoDelay, 9, b"b").unwrap(), RelayOutcome::Sent(4));
+        assert_eq!(next_hops(&mut tx), vec![3, 2, 4]);
+        let next: Vec<MeshNode> = repeater.routes().map(|r| r.next_hop).collect();
+        assert_eq!(next, vec![3]);
+
+        tx.device().sent.clear();
+        tx.device().acks.extend(&[false, false]);
+        assert_eq!(repeater.send(&mut tx, &mut NoDelay, 9, b"c").unwrap(), RelayOutcome::Undeliverable);
+        assert_eq!(repeater.send(&mut tx, &mut NoDelay, 9, &[0; MESH_DATA_BYTES + 1]).unwrap(), RelayOutcome::Undeliverable);
+
+        tx.device().stuck = true;
+        assert!(repeater.send(&mut tx, &mut NoDelay, 9, b"d").is_err());
+    }
+}
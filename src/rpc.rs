@@ -0,0 +1,474 @@
+//! Request/response calls between a client and a server node
+//!
+//! A request carries a method id and a correlation id, a response
+//! the correlation id and an `RpcStatus`. The `RpcClient` sends from
+//! TX mode and waits for the response in RX mode, retrying on
+//! timeout. The `RpcServer` answers a repeated request from its cache
+//! instead of running the handler again.
+//!
+//! Both ends reply to the configured TX address.
+
+use embedded_hal::blocking::delay::DelayUs;
+use device::Device;
+use tx::SEND_TIMEOUT_US;
+use standby::StandbyMode;
+use rx::RxMode;
+use payload::Payload;
+use clock::Clock;
+use {PIPES_COUNT, MAX_PAYLOAD_BYTES};
+
+const REQUEST: u8 = 0x70;
+const RESPONSE: u8 = 0x71;
+const HEADER_LEN: usize = 3;
+/// Bytes of arguments or response data per call
+pub const RPC_DATA_BYTES: usize = MAX_PAYLOAD_BYTES - HEADER_LEN;
+/// Handlers an `RpcServer` can hold
+pub const MAX_HANDLERS: usize = 8;
+
+/// Outcome of a call, as reported by the server
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum RpcStatus {
+    Ok = 0,
+    /// No handler registered for the method
+    UnknownMethod = 1,
+    /// The handler refused the arguments
+    InvalidArgs = 2,
+    /// The handler failed
+    Failed = 3,
+}
+
+impl RpcStatus {
+    fn from_u8(status: u8) -> Self {
+        match status {
+            0 => RpcStatus::Ok,
+            1 => RpcStatus::UnknownMethod,
+            2 => RpcStatus::InvalidArgs,
+            _ => RpcStatus::Failed,
+        }
+    }
+}
+
+/// Why an `RpcClient::call()` did not succeed
+#[derive(Debug)]
+pub enum RpcError<E> {
+    Radio(E),
+    /// No response after all retries
+    Timeout,
+    /// The server responded with an error status
+    Remote(RpcStatus),
+}
+
+/// Send one packet from TX mode, returning to **Standby-I** in any
+/// case. Fails with `Error::Timeout` if the chip never reports the
+/// outcome.
+fn send<D: Device, DL: DelayUs<u32>>(standby: StandbyMode<D>, delay: &mut DL, packet: &[u8]) -> (StandbyMode<D>, Result<bool, D::Error>) {
+    let mut tx = match standby.tx() {
+        Ok(tx) => tx,
+        Err((device, e)) => return (StandbyMode::from_rx_tx(device), Err(e)),
+    };
+    let result = tx.send_sync_timeout(packet, delay, SEND_TIMEOUT_US);
+    (tx.into_standby(), result)
+}
+
+/// Calling end, see the module documentation
+#[derive(Debug)]
+pub struct RpcClient {
+    next_id: u8,
+    timeout_us: u32,
+    retries: u8,
+}
+
+impl RpcClient {
+    /// Waits `timeout_us` for each response and repeats a request up
+    /// to `retries` times
+    pub fn new(timeout_us: u32, retries: u8) -> Self {
+        RpcClient {
+            next_id: 0,
+            timeout_us,
+            retries,
+        }
+    }
+
+    /// Call `method` with `args`, writing the response data into
+    /// `reply`. Returns the radio in **Standby-I** along with the
+    /// length of the response data.
+    ///
+    /// Longer responses are truncated to `reply.len()`.
+    pub fn call<D: Device, C: Clock, DL: DelayUs<u32>>(
+        &mut self,
+        standby: StandbyMode<D>,
+        clock: &mut C,
+        delay: &mut DL,
+        method: u8,
+        args: &[u8],
+        reply: &mut [u8],
+    ) -> (StandbyMode<D>, Result<usize, RpcError<D::Error>>) {
+        assert!(args.len() <= RPC_DATA_BYTES);
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        buf[0] = REQUEST;
+        buf[1] = id;
+        buf[2] = method;
+        buf[HEADER_LEN..HEADER_LEN + args.len()].copy_from_slice(args);
+        let request = &buf[0..HEADER_LEN + args.len()];
+
+        let mut standby = standby;
+        for _ in 0..=self.retries {
+            let (next, result) = send(standby, delay, request);
+            standby = next;
+            match result {
+                Ok(true) => {}
+                // Not acknowledged, try again
+                Ok(false) => continue,
+                Err(e) => return (standby, Err(RpcError::Radio(e))),
+            }
+
+            let (next, result) = self.wait_response(standby, clock, id, reply);
+            standby = next;
+            match result {
+                Err(RpcError::Timeout) => {}
+                result => return (standby, result),
+            }
+        }
+        (standby, Err(RpcError::Timeout))
+    }
+
+    /// Listen for the response to `id` until the timeout
+    fn wait_response<D: Device, C: Clock>(
+        &self,
+        standby: StandbyMode<D>,
+        clock: &mut C,
+        id: u8,
+        reply: &mut [u8],
+    ) -> (StandbyMode<D>, Result<usize, RpcError<D::Error>>) {
+        let mut rx = match standby.rx() {
+            Ok(rx) => rx,
+            Err((device, e)) => return (StandbyMode::from_rx_tx(device), Err(RpcError::Radio(e))),
+        };
+
+        let start = clock.now_us();
+        while clock.now_us().wrapping_sub(start) < self.timeout_us {
+            let payload = match rx.can_read() {
+                Ok(Some(_)) => rx.read(),
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            let payload = match payload {
+                Ok(payload) => payload,
+                Err(e) => return (rx.standby(), Err(RpcError::Radio(e))),
+            };
+            if payload.len() < HEADER_LEN || payload[0] != RESPONSE || payload[1] != id {
+                // Stale response or other traffic
+                continue;
+            }
+
+            let result = match RpcStatus::from_u8(payload[2]) {
+                RpcStatus::Ok => {
+                    let data = &payload[HEADER_LEN..];
+                    let len = data.len().min(reply.len());
+                    reply[0..len].copy_from_slice(&data[0..len]);
+                    Ok(len)
+                }
+                status => Err(RpcError::Remote(status)),
+            };
+            return (rx.standby(), result);
+        }
+        (rx.standby(), Err(RpcError::Timeout))
+    }
+}
+
+/// Called with the arguments and a buffer of `RPC_DATA_BYTES` for
+/// the response data. Returns the length of the response data.
+pub type RpcHandler<'a> = &'a mut dyn FnMut(&[u8], &mut [u8]) -> Result<usize, RpcStatus>;
+
+/// Answering end, see the module documentation
+pub struct RpcServer<'a> {
+    handlers: [Option<(u8, RpcHandler<'a>)>; MAX_HANDLERS],
+    /// Last request and response per pipe
+    last: [Option<(Payload, Payload)>; PIPES_COUNT],
+    requests: u32,
+}
+
+impl<'a> Default for RpcServer<'a> {
+    fn default() -> Self {
+        RpcServer::new()
+    }
+}
+
+impl<'a> RpcServer<'a> {
+    pub fn new() -> Self {
+        RpcServer {
+            handlers: [None, None, None, None, None, None, None, None],
+            last: [None, None, None, None, None, None],
+            requests: 0,
+        }
+    }
+
+    /// Register `handler` for `method`, replacing an earlier one.
+    /// Returns `false` if all `MAX_HANDLERS` slots are taken.
+    pub fn register(&mut self, method: u8, handler: RpcHandler<'a>) -> bool {
+        let slot = self.handlers.iter()
+            .position(|entry| matches!(*entry, Some((m, _)) if m == method))
+            .or_else(|| self.handlers.iter().position(|entry| entry.is_none()));
+        match slot {
+            Some(slot) => {
+                self.handlers[slot] = Some((method, handler));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unregister(&mut self, method: u8) {
+        for entry in self.handlers.iter_mut() {
+            if matches!(*entry, Some((m, _)) if m == method) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Process a received packet, returns the response to send if
+    /// it was a request
+    ///
+    /// A request identical to the last one on its pipe, including
+    /// the correlation id, method and arguments, gets the cached
+    /// response without running the handler again. Clients sharing a
+    /// pipe should therefore not send identical requests.
+    pub fn handle(&mut self, request: &Payload) -> Option<Payload> {
+        if request.len() < HEADER_LEN || request[0] != REQUEST {
+            return None;
+        }
+        let pipe = request.pipe() as usize % PIPES_COUNT;
+        let id = request[1];
+        let method = request[2];
+        self.requests = self.requests.wrapping_add(1);
+
+        if let Some((ref last, ref response)) = self.last[pipe] {
+            if *last == *request {
+                return Some(response.clone());
+            }
+        }
+
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let result = match self.handlers.iter_mut()
+            .filter_map(|entry| entry.as_mut())
+            .find(|&&mut (m, _)| m == method)
+        {
+            Some(&mut (_, ref mut handler)) =>
+                handler(&request[HEADER_LEN..], &mut buf[HEADER_LEN..]),
+            None => Err(RpcStatus::UnknownMethod),
+        };
+        let len = match result {
+            Ok(len) => {
+                buf[2] = RpcStatus::Ok as u8;
+                HEADER_LEN + len.min(RPC_DATA_BYTES)
+            }
+            Err(status) => {
+                buf[2] = status as u8;
+                HEADER_LEN
+            }
+        };
+        buf[0] = RESPONSE;
+        buf[1] = id;
+
+        let response = Payload::new(&buf[0..len]);
+        self.last[pipe] = Some((request.clone(), response.clone()));
+        Some(response)
+    }
+
+    /// Number of requests handled, including repeated ones
+    pub fn requests(&self) -> u32 {
+        self.requests
+    }
+
+    /// Handle one request if one has been received, sending the
+    /// response from TX mode. Returns to RX mode, or **Standby-I** on
+    /// error.
+    pub fn serve<D: Device, DL: DelayUs<u32>>(&mut self, mut rx: RxMode<D>, delay: &mut DL) -> Result<RxMode<D>, (StandbyMode<D>, D::Error)> {
+        let request = match rx.can_read() {
+            Ok(Some(_)) => rx.read(),
+            Ok(None) => return Ok(rx),
+            Err(e) => Err(e),
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => return Err((rx.standby(), e)),
+        };
+        let response = match self.handle(&request) {
+            Some(response) => response,
+            None => return Ok(rx),
+        };
+
+        let (standby, result) = send(rx.standby(), delay, &response);
+        if let Err(e) = result {
+            return Err((standby, e));
+        }
+        match standby.rx() {
+            Ok(rx) => Ok(rx),
+            Err((device, e)) => Err((StandbyMode::from_rx_tx(device), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::boxed::Box;
+    use std::vec::Vec;
+    use config::Configuration;
+    use mock::{MockRadio, TestClock, NoDelay};
+
+    fn request(pipe: u8, id: u8, method: u8, args: &[u8]) -> Payload {
+        let mut buf = vec![REQUEST, id, method];
+        buf.extend_from_slice(args);
+        let mut payload = Payload::new(&buf);
+        payload.set_pipe(pipe);
+        payload
+    }
+
+    /// Answers method 1 with its arguments plus one, and method 2
+    /// with `RpcStatus::InvalidArgs`
+    fn responder(request: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let mut response = vec![RESPONSE, request[1]];
+        match request[2] {
+            1 => {
+                response.push(RpcStatus::Ok as u8);
+                response.extend(request[HEADER_LEN..].iter().map(|b| b + 1));
+            }
+            _ => response.push(RpcStatus::InvalidArgs as u8),
+        }
+        Some((0, response))
+    }
+
+    fn radio() -> StandbyMode<MockRadio> {
+        StandbyMode::power_up(MockRadio::new()).unwrap()
+    }
+
+    #[test]
+    fn handle_runs_handler_once_per_request() {
+        let runs = Cell::new(0);
+        let mut double = |args: &[u8], out: &mut [u8]| {
+            runs.set(runs.get() + 1);
+            out[0] = args[0] * 2;
+            Ok(1)
+        };
+        let mut server = RpcServer::new();
+        assert!(server.register(1, &mut double));
+
+        let response = server.handle(&request(1, 5, 1, &[3])).unwrap();
+        assert_eq!(&response[..], &[RESPONSE, 5, RpcStatus::Ok as u8, 6]);
+        // Retransmitted request
+        assert_eq!(server.handle(&request(1, 5, 1, &[3])), Some(response));
+        assert_eq!(runs.get(), 1);
+
+        // Rebooted client reusing the id
+        let response = server.handle(&request(1, 5, 1, &[4])).unwrap();
+        assert_eq!(&response[..], &[RESPONSE, 5, RpcStatus::Ok as u8, 8]);
+        assert_eq!(runs.get(), 2);
+        let response = server.handle(&request(1, 5, 2, &[4])).unwrap();
+        assert_eq!(&response[..], &[RESPONSE, 5, RpcStatus::UnknownMethod as u8]);
+
+        // Same request on another pipe
+        server.handle(&request(2, 5, 1, &[4])).unwrap();
+        assert_eq!(runs.get(), 3);
+        assert_eq!(server.requests(), 5);
+    }
+
+    #[test]
+    fn handle_ignores_other_traffic() {
+        let mut server = RpcServer::new();
+        assert_eq!(server.handle(&Payload::new(&[RESPONSE, 0, 0])), None);
+        assert_eq!(server.handle(&Payload::new(&[REQUEST, 0])), None);
+        assert_eq!(server.requests(), 0);
+    }
+
+    #[test]
+    fn call() {
+        let mut standby = radio();
+        standby.device().responder = Some(Box::new(responder));
+        let mut client = RpcClient::new(1_000, 0);
+        let mut reply = [0; 4];
+
+        let (standby, result) = client.call(standby, &mut TestClock::new(10), &mut NoDelay, 1, &[1, 2], &mut reply);
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(&reply[0..2], &[2, 3]);
+        let (standby, result) = client.call(standby, &mut TestClock::new(10), &mut NoDelay, 2, &[], &mut reply);
+        assert!(matches!(result, Err(RpcError::Remote(RpcStatus::InvalidArgs))));
+
+        let mut standby = standby;
+        let sent = &standby.device().sent;
+        assert_eq!(sent[0].data, vec![REQUEST, 0, 1, 1, 2]);
+        assert_eq!(sent[1].data, vec![REQUEST, 1, 2]);
+    }
+
+    #[test]
+    fn call_retries() {
+        let mut standby = radio();
+        standby.device().responder = Some(Box::new(responder));
+        standby.device().acks.push_back(false);
+        let mut client = RpcClient::new(1_000, 1);
+        let mut reply = [0; 4];
+
+        let (mut standby, result) = client.call(standby, &mut TestClock::new(10), &mut NoDelay, 1, &[1], &mut reply);
+        assert_eq!(result.unwrap(), 1);
+        let sent = &standby.device().sent;
+        assert_eq!(sent.len(), 2);
+        assert!(!sent[0].acked && sent[1].acked);
+        assert_eq!(sent[0].data, sent[1].data);
+    }
+
+    #[test]
+    fn call_times_out() {
+        let mut client = RpcClient::new(1_000, 2);
+        let mut reply = [0; 4];
+
+        let (mut standby, result) = client.call(radio(), &mut TestClock::new(10), &mut NoDelay, 1, &[1], &mut reply);
+        assert!(matches!(result, Err(RpcError::Timeout)));
+        assert_eq!(standby.device().sent.len(), 3);
+    }
+
+    #[test]
+    fn call_skips_stale_responses() {
+        let mut standby = radio();
+        // Late response to an earlier call
+        standby.device().responder = Some(Box::new(|request: &[u8]| {
+            Some((0, vec![RESPONSE, request[1].wrapping_sub(1), RpcStatus::Ok as u8]))
+        }));
+        let mut client = RpcClient::new(1_000, 0);
+        let mut reply = [0; 4];
+
+        let (_, result) = client.call(standby, &mut TestClock::new(10), &mut NoDelay, 1, &[1], &mut reply);
+        assert!(matches!(result, Err(RpcError::Timeout)));
+    }
+
+    #[test]
+    fn serve() {
+        let mut negate = |args: &[u8], out: &mut [u8]| {
+            out[0] = !args[0];
+            Ok(1)
+        };
+        let mut server = RpcServer::new();
+        server.register(7, &mut negate);
+        let mut rx = radio().rx().unwrap();
+
+        // Nothing received
+        rx = server.serve(rx, &mut NoDelay).unwrap();
+        assert!(rx.device().sent.is_empty());
+
+        rx.device().receive(1, &[REQUEST, 9, 7, 0x0F]);
+        rx = server.serve(rx, &mut NoDelay).unwrap();
+        rx.device().receive(1, &[REQUEST, 9, 7, 0x0F]);
+        rx = server.serve(rx, &mut NoDelay).unwrap();
+
+        let response = vec![RESPONSE, 9, RpcStatus::Ok as u8, 0xF0];
+        let sent: Vec<_> = rx.device().sent.iter().map(|sent| sent.data.clone()).collect();
+        assert_eq!(sent, vec![response.clone(), response]);
+        assert_eq!(server.requests(), 2);
+        assert!(rx.is_empty().unwrap());
+    }
+}
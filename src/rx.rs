@@ -1,13 +1,48 @@
 use core::fmt;
-use command::{ReadRxPayloadWidth, ReadRxPayload};
+use command::{ReadRxPayloadWidth, ReadRxPayload, WriteAckPayload, FlushRx};
 use registers::FifoStatus;
 use device::Device;
 use standby::StandbyMode;
 use payload::Payload;
 use config::Configuration;
+use error::ModeError;
+#[cfg(feature = "serde")]
+use error::MessageError;
+use MAX_PAYLOAD_BYTES;
+#[cfg(feature = "serde")]
+use serde::de::DeserializeOwned;
+
+/// Width of the next payload in the RX FIFO
+///
+/// A width over 32 bytes means the packet is corrupt. As the
+/// datasheet requires, the RX FIFO is flushed then, and
+/// `corrupt_payloads` counted up.
+pub(crate) fn payload_width<D: Device>(device: &mut D, corrupt_payloads: &mut u32) -> Result<usize, D::Error> {
+    let (_, payload_width) =
+        device.send_command(&ReadRxPayloadWidth)?;
+    let payload_width = payload_width as usize;
+    if payload_width > MAX_PAYLOAD_BYTES {
+        device.send_command(&FlushRx)?;
+        *corrupt_payloads = corrupt_payloads.wrapping_add(1);
+        return Err(D::Error::corrupt_payload());
+    }
+    Ok(payload_width)
+}
+
+/// Where a packet read by `RxMode::read_into()` came from
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RxInfo {
+    /// Pipe number
+    pub pipe: u8,
+    /// Number of bytes written to the buffer
+    pub len: usize,
+}
 
 pub struct RxMode<D: Device> {
     device: D,
+    /// Invalid payload widths reported by the chip
+    corrupt_payloads: u32,
 }
 
 impl<D: Device> fmt::Debug for RxMode<D> {
@@ -16,11 +51,30 @@ impl<D: Device> fmt::Debug for RxMode<D> {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl<D: Device + defmt::Format> defmt::Format for RxMode<D> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "RxMode {{ device: {} }}", self.device)
+    }
+}
+
+#[cfg(feature = "ufmt")]
+impl<D: Device + ufmt::uDebug> ufmt::uDebug for RxMode<D> {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("RxMode")?
+            .field("device", &self.device)?
+            .finish()
+    }
+}
+
 impl<D: Device> RxMode<D> {
     /// Relies on everything being set up by `StandbyMode::rx()`, from
     /// which it is called
     pub(crate) fn new(device: D) -> Self {
-        RxMode { device }
+        RxMode {
+            device,
+            corrupt_payloads: 0,
+        }
     }
 
     /// Disable `CE` so that you can switch into TX mode.
@@ -54,13 +108,69 @@ impl<D: Device> RxMode<D> {
         )
     }
 
+    /// Receive without blocking, returns `WouldBlock` while the RX
+    /// FIFO is empty
+    pub fn try_receive(&mut self) -> nb::Result<(u8, Payload), D::Error> {
+        match self.can_read()? {
+            Some(pipe) => Ok((pipe, self.read()?)),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// How often the chip has reported an invalid payload width
+    pub fn corrupt_payloads(&self) -> u32 {
+        self.corrupt_payloads
+    }
+
+    fn payload_width(&mut self) -> Result<usize, D::Error> {
+        payload_width(&mut self.device, &mut self.corrupt_payloads)
+    }
+
+    /// Fails with `Error::CorruptPayload` if the chip reports an
+    /// invalid payload width
     pub fn read(&mut self) -> Result<Payload, D::Error> {
-        let (_, payload_width) =
-            self.device.send_command(&ReadRxPayloadWidth)?;
-        let (_, payload) =
-            self.device.send_command(&ReadRxPayload::new(payload_width as usize))?;
+        let payload_width = self.payload_width()?;
+        let (status, mut payload) =
+            self.device.send_command(&ReadRxPayload::new(payload_width))?;
+        payload.set_pipe(status.rx_p_no());
         Ok(payload)
     }
+
+    /// Read the next packet directly into `buf`, without copying
+    ///
+    /// A packet longer than `buf` is truncated. Fails like `read()`
+    /// on invalid payload widths.
+    pub fn read_into(&mut self, buf: &mut [u8]) -> Result<RxInfo, D::Error> {
+        let len = self.payload_width()?.min(buf.len());
+        let status =
+            self.device.read_rx_payload_into(&mut buf[0..len])?;
+        Ok(RxInfo {
+            pipe: status.rx_p_no(),
+            len,
+        })
+    }
+
+    /// Queue `data` to be sent with the ack of the next packet on
+    /// `pipe_no`. Requires `set_ack_payloads(true)`.
+    ///
+    /// Ack payloads of all pipes share the three slots of the TX
+    /// FIFO.
+    pub fn write_ack_payload(&mut self, pipe_no: u8, data: &[u8]) -> Result<(), D::Error> {
+        assert!(data.len() <= MAX_PAYLOAD_BYTES);
+        self.device.send_command(&WriteAckPayload::new(pipe_no, data))?;
+        Ok(())
+    }
+
+    /// Read a payload and deserialize it with postcard
+    #[cfg(feature = "serde")]
+    pub fn read_message<T: DeserializeOwned>(&mut self) -> Result<T, D::Error>
+    where
+        D::Error: MessageError,
+    {
+        let payload = self.read()?;
+        ::postcard::from_bytes(&payload)
+            .map_err(D::Error::message)
+    }
 }
 
 impl<D: Device> Configuration for RxMode<D> {
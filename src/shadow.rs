@@ -0,0 +1,43 @@
+use registers::{Register, REGISTERS_COUNT};
+use MAX_ADDR_BYTES;
+
+/// Copy of the registers that only change when written, so that
+/// reading them costs no SPI traffic
+pub struct Shadow {
+    values: [[u8; MAX_ADDR_BYTES]; REGISTERS_COUNT],
+    /// One bit per register address whose value is known
+    known: u32,
+}
+
+impl Shadow {
+    pub fn new() -> Self {
+        Shadow {
+            values: [[0; MAX_ADDR_BYTES]; REGISTERS_COUNT],
+            known: 0,
+        }
+    }
+
+    /// Cached value, unless unknown or volatile
+    pub fn get<R: Register>(&self) -> Option<R> {
+        let addr = R::addr() as usize;
+        if R::is_volatile() || self.known & (1 << addr) == 0 {
+            return None;
+        }
+
+        Some(R::decode(&self.values[addr][0..R::read_len()]))
+    }
+
+    /// Remember a value that has been written to or read from the
+    /// chip
+    pub fn set<R: Register>(&mut self, register: &R) {
+        let addr = R::addr() as usize;
+        if R::is_volatile() {
+            return;
+        }
+
+        let mut value = [0; MAX_ADDR_BYTES];
+        register.encode(&mut value[0..register.write_len()]);
+        self.values[addr] = value;
+        self.known |= 1 << addr;
+    }
+}
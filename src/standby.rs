@@ -3,6 +3,7 @@ use device::Device;
 use config::Configuration;
 use rx::RxMode;
 use tx::TxMode;
+use power_down::PowerDownMode;
 
 /// Represents **Standby-I** mode
 ///
@@ -18,6 +19,22 @@ impl<D: Device> fmt::Debug for StandbyMode<D> {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl<D: Device + defmt::Format> defmt::Format for StandbyMode<D> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "StandbyMode {{ device: {} }}", self.device)
+    }
+}
+
+#[cfg(feature = "ufmt")]
+impl<D: Device + ufmt::uDebug> ufmt::uDebug for StandbyMode<D> {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("StandbyMode")?
+            .field("device", &self.device)?
+            .finish()
+    }
+}
+
 impl<D: Device> StandbyMode<D> {
     pub fn power_up(mut device: D) -> Result<Self, (D, D::Error)> {
         match device.update_config(|config| config.set_pwr_up(true)) {
@@ -56,6 +73,16 @@ impl<D: Device> StandbyMode<D> {
             Err(e) => Err((device, e)),
         }
     }
+
+    /// Go into **Power Down** mode
+    pub fn power_down(self) -> Result<PowerDownMode<D>, (D, D::Error)> {
+        let mut device = self.device;
+
+        match device.update_config(|config| config.set_pwr_up(false)) {
+            Ok(()) => Ok(PowerDownMode::new(device)),
+            Err(e) => Err((device, e)),
+        }
+    }
 }
 
 impl<D: Device> Configuration for StandbyMode<D> {
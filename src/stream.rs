@@ -0,0 +1,521 @@
+//! Isochronous streaming, e.g. of audio
+//!
+//! A `StreamSender` prefixes each frame with a sequence number and
+//! the sender's timestamp. It sends without acks, or with acks and as
+//! many retransmits as `set_auto_retransmit()` allows; frames that
+//! find the TX FIFO full are dropped rather than delayed.
+//!
+//! On the receiving end, a `JitterBuffer` holds frames back by a fixed
+//! playout delay, puts them in order, conceals lost ones and estimates
+//! the drift of the sender's clock.
+
+use device::Device;
+use tx::TxMode;
+use clock::Clock;
+use MAX_PAYLOAD_BYTES;
+
+const STREAM: u8 = 0x90;
+const HEADER_LEN: usize = 7;
+/// Bytes of data per frame
+pub const STREAM_DATA_BYTES: usize = MAX_PAYLOAD_BYTES - HEADER_LEN;
+/// Sender time over which the lowest transit delay is tracked
+const DRIFT_WINDOW_US: u32 = 1_000_000;
+
+/// A received frame
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct StreamFrame {
+    pub seq: u16,
+    /// Sender's `Clock` when the frame was sent
+    pub timestamp: u32,
+    data: [u8; STREAM_DATA_BYTES],
+    len: u8,
+}
+
+impl StreamFrame {
+    /// Encode a frame into `buf`, which must hold 32 bytes
+    pub fn encode<'b>(seq: u16, timestamp: u32, data: &[u8], buf: &'b mut [u8]) -> &'b [u8] {
+        assert!(data.len() <= STREAM_DATA_BYTES);
+        buf[0] = STREAM;
+        buf[1] = seq as u8;
+        buf[2] = (seq >> 8) as u8;
+        buf[3] = timestamp as u8;
+        buf[4] = (timestamp >> 8) as u8;
+        buf[5] = (timestamp >> 16) as u8;
+        buf[6] = (timestamp >> 24) as u8;
+        let len = HEADER_LEN + data.len();
+        buf[HEADER_LEN..len].copy_from_slice(data);
+        &buf[0..len]
+    }
+
+    /// `None` if `packet` is no stream frame
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        if packet.len() < HEADER_LEN || packet.len() > MAX_PAYLOAD_BYTES || packet[0] != STREAM {
+            return None;
+        }
+        let data = &packet[HEADER_LEN..];
+        let mut frame = StreamFrame {
+            seq: (packet[1] as u16) | ((packet[2] as u16) << 8),
+            timestamp: (packet[3] as u32) |
+                ((packet[4] as u32) << 8) |
+                ((packet[5] as u32) << 16) |
+                ((packet[6] as u32) << 24),
+            data: [0; STREAM_DATA_BYTES],
+            len: data.len() as u8,
+        };
+        frame.data[0..data.len()].copy_from_slice(data);
+        Some(frame)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[0..self.len as usize]
+    }
+}
+
+/// Sending end, see the module documentation
+#[derive(Debug)]
+pub struct StreamSender {
+    next_seq: u16,
+    acked: bool,
+    dropped: u32,
+    failed: u32,
+}
+
+impl StreamSender {
+    /// Without `acked`, requires `set_dynamic_ack(true)`
+    pub fn new(acked: bool) -> Self {
+        StreamSender {
+            next_seq: 0,
+            acked,
+            dropped: 0,
+            failed: 0,
+        }
+    }
+
+    /// Queue `data` as the next frame. Returns `false` if it has been
+    /// dropped because the TX FIFO is full, or is longer than
+    /// `STREAM_DATA_BYTES`.
+    ///
+    /// Keeps `CE` within `MAX_TX_DWELL_US` like
+    /// `TxMode::check_dwell()`. Once that has dropped `CE`, it stays
+    /// low until the next frame.
+    pub fn send<D: Device, C: Clock>(&mut self, tx: &mut TxMode<D>, clock: &mut C, data: &[u8]) -> Result<bool, D::Error> {
+        if data.len() > STREAM_DATA_BYTES {
+            return Ok(false);
+        }
+        let resting = tx.check_dwell(clock);
+        if !resting {
+            // Collect the outcome of earlier frames, clearing MAX_RT.
+            // The flush takes the frames queued behind along.
+            let in_flight = tx.in_flight().max(1) as u32;
+            match tx.poll_tx_complete() {
+                Ok(false) => self.failed = self.failed.wrapping_add(in_flight),
+                Ok(true) | Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+
+        let seq = self.next_seq;
+        // Skipped on drop so the receiver sees the loss
+        self.next_seq = self.next_seq.wrapping_add(1);
+        if tx.is_full()? {
+            self.dropped = self.dropped.wrapping_add(1);
+            return Ok(false);
+        }
+
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let packet = StreamFrame::encode(seq, clock.now_us(), data, &mut buf);
+        match (self.acked, resting) {
+            (true, false) => tx.send(packet)?,
+            (false, false) => tx.send_no_ack(packet)?,
+            (true, true) => tx.write_payload(packet)?,
+            (false, true) => tx.write_payload_no_ack(packet)?,
+        }
+        Ok(true)
+    }
+
+    /// Frames dropped for a full TX FIFO
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Frames lost when one reached the maximum number of
+    /// retransmits, including those flushed along
+    pub fn failed(&self) -> u32 {
+        self.failed
+    }
+}
+
+/// Fills in for a lost frame
+pub trait Concealment {
+    /// Write a replacement for frame `seq` into `out`, given the data
+    /// of the last frame played. Returns its length.
+    fn conceal(&mut self, seq: u16, last: &[u8], out: &mut [u8]) -> usize;
+}
+
+/// Replaces lost frames with zeroes
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Silence;
+
+impl Concealment for Silence {
+    fn conceal(&mut self, _seq: u16, last: &[u8], out: &mut [u8]) -> usize {
+        let len = last.len().min(out.len());
+        for byte in out[0..len].iter_mut() {
+            *byte = 0;
+        }
+        len
+    }
+}
+
+/// Replaces lost frames with the last one played
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RepeatLast;
+
+impl Concealment for RepeatLast {
+    fn conceal(&mut self, _seq: u16, last: &[u8], out: &mut [u8]) -> usize {
+        let len = last.len().min(out.len());
+        out[0..len].copy_from_slice(&last[0..len]);
+        len
+    }
+}
+
+/// A frame returned by `JitterBuffer::pop()`
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Playout {
+    pub seq: u16,
+    /// Bytes written to the output buffer
+    pub len: usize,
+    /// Produced by the `Concealment`
+    pub concealed: bool,
+}
+
+/// Counters of a `JitterBuffer`
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct StreamStats {
+    pub received: u32,
+    /// Arrived after their playout time
+    pub late: u32,
+    pub duplicates: u32,
+    /// Dropped because the buffer was full
+    pub overflows: u32,
+    pub concealed: u32,
+}
+
+/// Lowest transit delay within a window of sender time
+#[derive(Debug, Copy, Clone)]
+struct Window {
+    start: u32,
+    min_offset: u32,
+}
+
+/// Receiving end, see the module documentation
+///
+/// Frames are kept in caller-provided slots; enough for the playout
+/// delay plus some headroom.
+pub struct JitterBuffer<'a> {
+    slots: &'a mut [Option<StreamFrame>],
+    delay_us: u32,
+    next_seq: Option<u16>,
+    /// Receiver time minus sender time of the fastest recent frame
+    offset: Option<u32>,
+    window: Option<Window>,
+    previous_window: Option<Window>,
+    drift_ppm: Option<i32>,
+    last: [u8; STREAM_DATA_BYTES],
+    last_len: usize,
+    /// Sender time of the frame last played or concealed
+    last_timestamp: Option<u32>,
+    /// Sender time between consecutive frames
+    interval_us: Option<u32>,
+    stats: StreamStats,
+}
+
+impl<'a> JitterBuffer<'a> {
+    /// Play frames `delay_us` after the fastest transit seen
+    pub fn new(slots: &'a mut [Option<StreamFrame>], delay_us: u32) -> Self {
+        JitterBuffer {
+            slots,
+            delay_us,
+            next_seq: None,
+            offset: None,
+            window: None,
+            previous_window: None,
+            drift_ppm: None,
+            last: [0; STREAM_DATA_BYTES],
+            last_len: 0,
+            last_timestamp: None,
+            interval_us: None,
+            stats: StreamStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> StreamStats {
+        self.stats
+    }
+
+    /// Frames waiting for playout
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How much faster the sender's clock runs than the receiver's,
+    /// in parts per million. Known after two seconds of streaming.
+    pub fn drift_ppm(&self) -> Option<i32> {
+        self.drift_ppm
+    }
+
+    /// Process a received packet. Returns `false` if it is no stream
+    /// frame or has been dropped.
+    pub fn push<C: Clock>(&mut self, packet: &[u8], clock: &mut C) -> bool {
+        let frame = match StreamFrame::decode(packet) {
+            Some(frame) => frame,
+            None => return false,
+        };
+        self.stats.received = self.stats.received.wrapping_add(1);
+        self.track_offset(frame.timestamp, clock.now_us());
+
+        if let Some(next_seq) = self.next_seq {
+            if (frame.seq.wrapping_sub(next_seq) as i16) < 0 {
+                self.stats.late = self.stats.late.wrapping_add(1);
+                return false;
+            }
+        }
+        if self.slots.iter().any(|slot| matches!(*slot, Some(ref f) if f.seq == frame.seq)) {
+            self.stats.duplicates = self.stats.duplicates.wrapping_add(1);
+            return false;
+        }
+        match self.slots.iter().position(|slot| slot.is_none()) {
+            Some(i) => {
+                self.slots[i] = Some(frame);
+                true
+            }
+            None => {
+                self.stats.overflows = self.stats.overflows.wrapping_add(1);
+                false
+            }
+        }
+    }
+
+    /// Follow the lowest transit delay, window by window, and derive
+    /// the drift from how it changes
+    fn track_offset(&mut self, timestamp: u32, now: u32) {
+        let offset = now.wrapping_sub(timestamp);
+        let lower = |a: u32, b: u32| if (a.wrapping_sub(b) as i32) < 0 { a } else { b };
+        self.offset = Some(self.offset.map_or(offset, |current| lower(offset, current)));
+
+        let mut window = match self.window {
+            Some(window) => window,
+            None => {
+                self.window = Some(Window { start: timestamp, min_offset: offset });
+                return;
+            }
+        };
+        window.min_offset = lower(offset, window.min_offset);
+        if timestamp.wrapping_sub(window.start) < DRIFT_WINDOW_US {
+            self.window = Some(window);
+            return;
+        }
+
+        if let Some(previous) = self.previous_window {
+            let elapsed = window.start.wrapping_sub(previous.start) as i64;
+            let change = window.min_offset.wrapping_sub(previous.min_offset) as i32 as i64;
+            // The receiver sees a fast sender's frames arrive earlier
+            let drift = (-change * 1_000_000 / elapsed) as i32;
+            self.drift_ppm = Some(self.drift_ppm.map_or(drift, |old| (3 * old + drift) / 4));
+        }
+        // Adopt the latest transit delay, so that playout follows the
+        // sender's clock
+        self.offset = Some(window.min_offset);
+        self.previous_window = Some(window);
+        self.window = Some(Window { start: timestamp, min_offset: offset });
+    }
+
+    /// Is a frame sent at `timestamp` due for playout at `now`?
+    fn is_due(&self, timestamp: u32, now: u32) -> bool {
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => return false,
+        };
+        let playout = timestamp.wrapping_add(offset).wrapping_add(self.delay_us);
+        (now.wrapping_sub(playout) as i32) >= 0
+    }
+
+    /// Take the next frame if its playout time has come, writing its
+    /// data into `out`
+    ///
+    /// A missing frame is given up on once it is an interval overdue
+    /// or a later one is due, and filled in by `concealment`. Call at
+    /// least once per frame interval.
+    pub fn pop<C: Clock, L: Concealment>(&mut self, clock: &mut C, concealment: &mut L, out: &mut [u8]) -> Option<Playout> {
+        let now = clock.now_us();
+        let next_seq = match self.next_seq {
+            Some(next_seq) => next_seq,
+            // Start with the earliest frame buffered
+            None => {
+                let first = self.slots.iter().filter_map(|slot| slot.as_ref()).next()?.seq;
+                self.slots.iter()
+                    .filter_map(|slot| slot.as_ref())
+                    .map(|frame| frame.seq)
+                    .min_by_key(|seq| seq.wrapping_sub(first) as i16)?
+            }
+        };
+
+        if let Some(i) = self.slots.iter().position(|slot| matches!(*slot, Some(ref f) if f.seq == next_seq)) {
+            let frame = match self.slots[i] {
+                Some(frame) if self.is_due(frame.timestamp, now) => frame,
+                _ => return None,
+            };
+            self.slots[i] = None;
+            self.next_seq = Some(next_seq.wrapping_add(1));
+            let data = frame.data();
+            let len = data.len().min(out.len());
+            out[0..len].copy_from_slice(&data[0..len]);
+            self.last[0..data.len()].copy_from_slice(data);
+            self.last_len = data.len();
+            if let Some(last_timestamp) = self.last_timestamp {
+                self.interval_us = Some(frame.timestamp.wrapping_sub(last_timestamp));
+            }
+            self.last_timestamp = Some(frame.timestamp);
+            return Some(Playout { seq: next_seq, len, concealed: false });
+        }
+
+        // Expected at one interval after the last frame
+        let expected = match (self.last_timestamp, self.interval_us) {
+            (Some(last_timestamp), Some(interval_us)) => Some(last_timestamp.wrapping_add(interval_us)),
+            _ => None,
+        };
+        let expected_due = match expected {
+            Some(expected) => self.is_due(expected, now),
+            None => false,
+        };
+        let due = expected_due ||
+            self.slots.iter()
+                .filter_map(|slot| slot.as_ref())
+                .any(|frame| self.is_due(frame.timestamp, now));
+        if !due {
+            return None;
+        }
+        self.last_timestamp = expected.or(self.last_timestamp);
+        self.next_seq = Some(next_seq.wrapping_add(1));
+        self.stats.concealed = self.stats.concealed.wrapping_add(1);
+        let len = concealment.conceal(next_seq, &self.last[0..self.last_len], out);
+        Some(Playout { seq: next_seq, len, concealed: true })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL_US: u32 = 10_000;
+    const DELAY_US: u32 = 5_000;
+
+    struct TestClock(u32);
+
+    impl Clock for TestClock {
+        fn now_us(&mut self) -> u32 {
+            self.0
+        }
+    }
+
+    /// Push frame `seq`, sent at `seq` intervals, arriving at `now`
+    fn push(buffer: &mut JitterBuffer, seq: u16, now: u32) -> bool {
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let timestamp = seq as u32 * INTERVAL_US;
+        let packet = StreamFrame::encode(seq, timestamp, &[seq as u8; 4], &mut buf);
+        buffer.push(packet, &mut TestClock(now))
+    }
+
+    fn pop(buffer: &mut JitterBuffer, now: u32) -> Option<(Playout, u8)> {
+        let mut out = [0; STREAM_DATA_BYTES];
+        buffer.pop(&mut TestClock(now), &mut RepeatLast, &mut out)
+            .map(|playout| (playout, out[0]))
+    }
+
+    fn played(seq: u16) -> Option<(Playout, u8)> {
+        Some((Playout { seq, len: 4, concealed: false }, seq as u8))
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        let packet = StreamFrame::encode(0x1234, 0xDEAD_BEEF, b"pcm", &mut buf);
+        let frame = StreamFrame::decode(packet).unwrap();
+        assert_eq!((frame.seq, frame.timestamp, frame.data()), (0x1234, 0xDEAD_BEEF, &b"pcm"[..]));
+        assert_eq!(StreamFrame::decode(&[STREAM; 33]), None);
+        assert_eq!(StreamFrame::decode(&packet[0..HEADER_LEN - 1]), None);
+    }
+
+    #[test]
+    fn reorders() {
+        let mut slots = [None; 4];
+        let mut buffer = JitterBuffer::new(&mut slots, DELAY_US);
+        // Transit of 1 ms, except for frame 0
+        assert!(push(&mut buffer, 1, INTERVAL_US + 1_000));
+        assert!(push(&mut buffer, 0, INTERVAL_US + 2_000));
+        assert!(push(&mut buffer, 2, 2 * INTERVAL_US + 1_000));
+
+        let now = 2 * INTERVAL_US + 1_000;
+        assert_eq!(pop(&mut buffer, now), played(0));
+        assert_eq!(pop(&mut buffer, now), played(1));
+        // Due 1 ms + DELAY_US after sending
+        assert_eq!(pop(&mut buffer, now), None);
+        assert_eq!(pop(&mut buffer, 2 * INTERVAL_US + 1_000 + DELAY_US), played(2));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn conceals_lost_frames() {
+        let mut slots = [None; 4];
+        let mut buffer = JitterBuffer::new(&mut slots, DELAY_US);
+        push(&mut buffer, 0, 1_000);
+        push(&mut buffer, 1, INTERVAL_US + 1_000);
+        push(&mut buffer, 3, 3 * INTERVAL_US + 1_000);
+        let playout = |seq: u16| seq as u32 * INTERVAL_US + 1_000 + DELAY_US;
+
+        assert_eq!(pop(&mut buffer, playout(0)), played(0));
+        assert_eq!(pop(&mut buffer, playout(1)), played(1));
+        // Frame 2 is given up on when it would have been due
+        assert_eq!(pop(&mut buffer, playout(2) - 1), None);
+        let concealed = Playout { seq: 2, len: 4, concealed: true };
+        assert_eq!(pop(&mut buffer, playout(2)), Some((concealed, 1)));
+        assert_eq!(pop(&mut buffer, playout(3)), played(3));
+        assert_eq!(buffer.stats().concealed, 1);
+    }
+
+    #[test]
+    fn drops_late_and_duplicate_frames() {
+        let mut slots = [None; 2];
+        let mut buffer = JitterBuffer::new(&mut slots, DELAY_US);
+        assert!(push(&mut buffer, 0, 1_000));
+        assert!(!push(&mut buffer, 0, 1_500));
+        assert!(push(&mut buffer, 1, INTERVAL_US + 1_000));
+        assert!(!push(&mut buffer, 2, 2 * INTERVAL_US + 1_000));
+        assert_eq!(pop(&mut buffer, 1_000 + DELAY_US), played(0));
+        assert!(!push(&mut buffer, 0, INTERVAL_US));
+
+        let stats = buffer.stats();
+        assert_eq!((stats.received, stats.duplicates, stats.overflows, stats.late), (5, 1, 1, 1));
+    }
+
+    #[test]
+    fn estimates_drift() {
+        let mut slots = [None; 4];
+        let mut buffer = JitterBuffer::new(&mut slots, DELAY_US);
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        for seq in 0..300u16 {
+            // The sender's clock runs 100 ppm fast
+            let now = seq as u32 * INTERVAL_US;
+            let timestamp = now + now / 10_000;
+            let packet = StreamFrame::encode(seq, timestamp, &[0], &mut buf);
+            buffer.push(packet, &mut TestClock(now + 1_000));
+            let mut out = [0; STREAM_DATA_BYTES];
+            buffer.pop(&mut TestClock(now + 1_000), &mut Silence, &mut out);
+        }
+        let drift = buffer.drift_ppm().unwrap();
+        assert!((drift - 100).abs() <= 2, "{}", drift);
+    }
+}
@@ -0,0 +1,527 @@
+//! Network time synchronization
+//!
+//! Network time is the `Clock` of a reference node. Other nodes
+//! estimate the offset of their own `Clock` from it, and its drift,
+//! from timestamped packets:
+//!
+//! * Beacons, flooded through the network. The reference sends them
+//!   at level 0. Synchronized nodes may send their own, one level up.
+//! * Two-way exchanges with a `TimeSyncServer`, which also measure the
+//!   remaining delay.
+//!
+//! Timestamps are taken when sending starts and when `can_read()`
+//! first reports a packet, so poll often. The 130 µs TX settling time
+//! and the air time given by the `LinkTiming` are accounted for. Send
+//! time packets without retransmits, or their delay is off.
+//!
+//! Sending gives up with `Error::Timeout` if the chip stops
+//! responding.
+
+use embedded_hal::blocking::delay::DelayUs;
+use device::Device;
+use standby::StandbyMode;
+use tx::{TxMode, SEND_TIMEOUT_US};
+use rx::RxMode;
+use payload::Payload;
+use clock::Clock;
+use config::{DataRate, CrcMode};
+use MAX_PAYLOAD_BYTES;
+
+const BEACON: u8 = 0xC0;
+const REQUEST: u8 = 0xC1;
+const RESPONSE: u8 = 0xC2;
+const BEACON_LEN: usize = 6;
+const REQUEST_LEN: usize = 6;
+const RESPONSE_LEN: usize = 14;
+/// Samples the estimate is fitted to
+const SAMPLES: usize = 8;
+/// Bound of the drift estimate. Samples close together in time can
+/// suggest any slope.
+const MAX_DRIFT_PPM: i64 = 100_000;
+
+/// From enabling `CE` until the packet goes on air
+pub const TX_SETTLING_US: u32 = 130;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) |
+    ((buf[1] as u32) << 8) |
+    ((buf[2] as u32) << 16) |
+    ((buf[3] as u32) << 24)
+}
+
+fn write_u32(buf: &mut [u8], value: u32) {
+    buf[0] = value as u8;
+    buf[1] = (value >> 8) as u8;
+    buf[2] = (value >> 16) as u8;
+    buf[3] = (value >> 24) as u8;
+}
+
+/// Send `packet` from TX mode, returning the local time when sending
+/// started and whether it was acknowledged
+fn send_timed<D: Device, C: Clock, DL: DelayUs<u32>>(
+    tx: &mut TxMode<D>,
+    clock: &mut C,
+    delay: &mut DL,
+    packet: &[u8],
+) -> Result<(u32, bool), D::Error> {
+    tx.write_payload(packet)?;
+    // Sending starts with CE, after the SPI transfer
+    let sent_at = clock.now_us();
+    tx.ce_enable();
+    let acked = tx.wait_empty_timeout(delay, SEND_TIMEOUT_US)?;
+    Ok((sent_at, acked))
+}
+
+/// Radio settings that determine how long a packet takes
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct LinkTiming {
+    pub rate: DataRate,
+    pub addr_bytes: u8,
+    pub crc: Option<CrcMode>,
+}
+
+impl LinkTiming {
+    /// Air time of an Enhanced ShockBurst packet with `payload_len`
+    /// bytes
+    pub fn air_time_us(&self, payload_len: usize) -> u32 {
+        let crc_bytes = match self.crc {
+            None => 0,
+            Some(CrcMode::OneByte) => 1,
+            Some(CrcMode::TwoBytes) => 2,
+        };
+        // Preamble, address, 9-bit packet control field, payload, CRC
+        let bits = 8 * (1 + self.addr_bytes as u32 + payload_len as u32 + crc_bytes) + 9;
+        match self.rate {
+            DataRate::R250Kbps => bits * 4,
+            DataRate::R1Mbps => bits,
+            DataRate::R2Mbps => (bits + 1) / 2,
+        }
+    }
+
+    /// From starting to send a packet of `payload_len` bytes until
+    /// the receiver reports it
+    pub fn delay_us(&self, payload_len: usize) -> u32 {
+        TX_SETTLING_US + self.air_time_us(payload_len)
+    }
+}
+
+/// Send a beacon with network time `now` from TX mode. Requires
+/// `set_dynamic_ack(true)`, as beacons go out without acks.
+///
+/// `level` is 0 for the reference, or `TimeSync::level()`.
+pub fn send_beacon<D: Device, DL: DelayUs<u32>>(tx: &mut TxMode<D>, delay: &mut DL, level: u8, now: u32) -> Result<(), D::Error> {
+    let mut buf = [0; BEACON_LEN];
+    buf[0] = BEACON;
+    buf[1] = level;
+    write_u32(&mut buf[2..6], now);
+    tx.send_no_ack(&buf)?;
+    tx.wait_empty_timeout(delay, SEND_TIMEOUT_US)?;
+    Ok(())
+}
+
+/// Result of a two-way exchange
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SyncSample {
+    /// Network time minus local time
+    pub offset_us: i32,
+    /// Round trip time not accounted for by settling and air time,
+    /// e.g. polling and SPI latency
+    pub residual_delay_us: i32,
+}
+
+/// Estimates network time, see the module documentation
+#[derive(Debug)]
+pub struct TimeSync {
+    timing: LinkTiming,
+    /// Local time and offset to network time
+    samples: [Option<(u32, i32)>; SAMPLES],
+    next_sample: usize,
+    level: Option<u8>,
+    next_seq: u8,
+}
+
+impl TimeSync {
+    pub fn new(timing: LinkTiming) -> Self {
+        TimeSync {
+            timing,
+            samples: [None; SAMPLES],
+            next_sample: 0,
+            level: None,
+            next_seq: 0,
+        }
+    }
+
+    /// Drop all samples, e.g. after a jump of the local clock
+    pub fn reset(&mut self) {
+        self.samples = [None; SAMPLES];
+        self.level = None;
+    }
+
+    /// Distance from the reference in beacon hops, once synchronized
+    /// by beacons
+    pub fn level(&self) -> Option<u8> {
+        self.level
+    }
+
+    fn add_sample(&mut self, local: u32, offset: i32) {
+        self.samples[self.next_sample] = Some((local, offset));
+        self.next_sample = (self.next_sample + 1) % SAMPLES;
+    }
+
+    /// Least-squares fit of offset over local time, relative to the
+    /// latest sample: offset there and slope in parts per million
+    fn fit(&self) -> Option<(u32, i64, i64)> {
+        let latest = self.samples[(self.next_sample + SAMPLES - 1) % SAMPLES]?;
+        let points = || self.samples.iter()
+            .filter_map(|sample| *sample)
+            .map(|(local, offset)| (local.wrapping_sub(latest.0) as i32 as i64, offset as i64));
+        let n = points().count() as i64;
+        let mean_x = points().map(|(x, _)| x).sum::<i64>() / n;
+        let mean_y = points().map(|(_, y)| y).sum::<i64>() / n;
+        let sxx: i128 = points().map(|(x, _)| ((x - mean_x) as i128).pow(2)).sum();
+        let sxy: i128 = points().map(|(x, y)| (x - mean_x) as i128 * (y - mean_y) as i128).sum();
+        let ppm = if sxx > 0 {
+            (sxy * 1_000_000 / sxx).clamp(-MAX_DRIFT_PPM as i128, MAX_DRIFT_PPM as i128) as i64
+        } else {
+            0
+        };
+        Some((latest.0, mean_y - ppm * mean_x / 1_000_000, ppm))
+    }
+
+    /// Network time minus local time, at the latest sample
+    pub fn offset_us(&self) -> Option<i32> {
+        self.fit().map(|(_, offset, _)| offset as i32)
+    }
+
+    /// How much faster network time runs than the local clock, in
+    /// parts per million, up to 100000. Needs two samples.
+    pub fn drift_ppm(&self) -> Option<i32> {
+        if self.samples.iter().filter(|sample| sample.is_some()).count() < 2 {
+            return None;
+        }
+        self.fit().map(|(_, _, ppm)| ppm as i32)
+    }
+
+    /// Network time at local time `local`
+    pub fn network_time(&self, local: u32) -> Option<u32> {
+        let (at, offset, ppm) = self.fit()?;
+        let elapsed = local.wrapping_sub(at) as i32 as i64;
+        let offset = offset + ppm * elapsed / 1_000_000;
+        Some(local.wrapping_add(offset as i32 as u32))
+    }
+
+    /// Network time now
+    pub fn now<C: Clock>(&self, clock: &mut C) -> Option<u32> {
+        self.network_time(clock.now_us())
+    }
+
+    /// Process a beacon received at local time `received_at`. Returns
+    /// `false` if `packet` is no beacon or from a level not closer to
+    /// the reference.
+    pub fn handle_beacon(&mut self, packet: &[u8], received_at: u32) -> bool {
+        if packet.len() != BEACON_LEN || packet[0] != BEACON {
+            return false;
+        }
+        let level = packet[1];
+        if matches!(self.level, Some(own) if level >= own) {
+            return false;
+        }
+        // Beacons from a closer level take over
+        if matches!(self.level, Some(own) if level + 1 < own) {
+            self.samples = [None; SAMPLES];
+        }
+        self.level = Some(level.saturating_add(1));
+
+        let sent_at = read_u32(&packet[2..6]);
+        let network = sent_at.wrapping_add(self.timing.delay_us(BEACON_LEN));
+        self.add_sample(received_at, network.wrapping_sub(received_at) as i32);
+        true
+    }
+
+    /// Receive one packet, if any, timestamping it. Beacons are
+    /// processed, other packets returned.
+    pub fn poll<D: Device, C: Clock>(&mut self, rx: &mut RxMode<D>, clock: &mut C) -> Result<Option<Payload>, D::Error> {
+        if rx.can_read()?.is_none() {
+            return Ok(None);
+        }
+        let received_at = clock.now_us();
+        let payload = rx.read()?;
+        if self.handle_beacon(&payload, received_at) {
+            return Ok(None);
+        }
+        Ok(Some(payload))
+    }
+
+    /// Exchange timestamps with a `TimeSyncServer` at the TX address,
+    /// waiting up to `timeout_us` for its response. Takes and returns
+    /// the radio in **Standby-I**.
+    ///
+    /// Returns `None` on timeout, or if the request was
+    /// retransmitted.
+    pub fn exchange<D: Device, C: Clock, DL: DelayUs<u32>>(
+        &mut self,
+        standby: StandbyMode<D>,
+        clock: &mut C,
+        delay: &mut DL,
+        timeout_us: u32,
+    ) -> (StandbyMode<D>, Result<Option<SyncSample>, D::Error>) {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        let mut tx = match standby.tx() {
+            Ok(tx) => tx,
+            Err((device, e)) => return (StandbyMode::from_rx_tx(device), Err(e)),
+        };
+        let mut buf = [0; REQUEST_LEN];
+        buf[0] = REQUEST;
+        buf[1] = seq;
+        // Echoed by the server, but t1 is taken when sending starts
+        write_u32(&mut buf[2..6], clock.now_us());
+        let sent = send_timed(&mut tx, clock, delay, &buf)
+            .and_then(|(t1, acked)| Ok((t1, acked && tx.observe()?.arc_cnt() == 0)));
+        let standby = tx.into_standby();
+        let t1 = match sent {
+            Ok((t1, true)) => t1,
+            Ok((_, false)) => return (standby, Ok(None)),
+            Err(e) => return (standby, Err(e)),
+        };
+
+        let mut rx = match standby.rx() {
+            Ok(rx) => rx,
+            Err((device, e)) => return (StandbyMode::from_rx_tx(device), Err(e)),
+        };
+        while clock.now_us().wrapping_sub(t1) < timeout_us {
+            let packet = match rx.can_read() {
+                Ok(Some(_)) => {
+                    let t4 = clock.now_us();
+                    rx.read().map(|packet| (packet, t4))
+                }
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+            let (packet, t4) = match packet {
+                Ok(packet) => packet,
+                Err(e) => return (rx.standby(), Err(e)),
+            };
+            if packet.len() != RESPONSE_LEN || packet[0] != RESPONSE || packet[1] != seq {
+                continue;
+            }
+
+            let t2 = read_u32(&packet[6..10]);
+            let t3 = read_u32(&packet[10..14]);
+            let request_delay = self.timing.delay_us(REQUEST_LEN);
+            let response_delay = self.timing.delay_us(RESPONSE_LEN);
+            let forward = t2.wrapping_sub(t1).wrapping_sub(request_delay) as i32 as i64;
+            let backward = t3.wrapping_add(response_delay).wrapping_sub(t4) as i32 as i64;
+            let offset_us = ((forward + backward) / 2) as i32;
+            let residual_delay_us = t4.wrapping_sub(t1)
+                .wrapping_sub(t3.wrapping_sub(t2))
+                .wrapping_sub(request_delay)
+                .wrapping_sub(response_delay) as i32;
+            self.add_sample(t4, offset_us);
+            return (rx.standby(), Ok(Some(SyncSample { offset_us, residual_delay_us })));
+        }
+        (rx.standby(), Ok(None))
+    }
+}
+
+/// Answers `TimeSync::exchange()` with its own `Clock` as network
+/// time
+#[derive(Debug, Default)]
+pub struct TimeSyncServer {
+    requests: u32,
+    /// Duration of the last response's SPI transfer, by which t3
+    /// precedes sending
+    write_us: u32,
+}
+
+impl TimeSyncServer {
+    pub fn new() -> Self {
+        TimeSyncServer::default()
+    }
+
+    /// Number of requests answered
+    pub fn requests(&self) -> u32 {
+        self.requests
+    }
+
+    /// Answer one request if one has been received, replying to the
+    /// TX address. Returns to RX mode, or **Standby-I** on error.
+    ///
+    /// The response is timestamped before it is written to the chip,
+    /// ahead by the duration of writing the previous response.
+    pub fn serve<D: Device, C: Clock, DL: DelayUs<u32>>(&mut self, mut rx: RxMode<D>, clock: &mut C, delay: &mut DL) -> Result<RxMode<D>, (StandbyMode<D>, D::Error)> {
+        let request = match rx.can_read() {
+            Ok(Some(_)) => {
+                let t2 = clock.now_us();
+                rx.read().map(|request| (request, t2))
+            }
+            Ok(None) => return Ok(rx),
+            Err(e) => Err(e),
+        };
+        let (request, t2) = match request {
+            Ok(request) => request,
+            Err(e) => return Err((rx.standby(), e)),
+        };
+        if request.len() != REQUEST_LEN || request[0] != REQUEST {
+            return Ok(rx);
+        }
+        self.requests = self.requests.wrapping_add(1);
+
+        let mut tx = match rx.standby().tx() {
+            Ok(tx) => tx,
+            Err((device, e)) => return Err((StandbyMode::from_rx_tx(device), e)),
+        };
+        let mut buf = [0; MAX_PAYLOAD_BYTES];
+        buf[0] = RESPONSE;
+        buf[1] = request[1];
+        buf[2..6].copy_from_slice(&request[2..6]);
+        write_u32(&mut buf[6..10], t2);
+        let before_write = clock.now_us();
+        write_u32(&mut buf[10..14], before_write.wrapping_add(self.write_us));
+        let result = send_timed(&mut tx, clock, delay, &buf[0..RESPONSE_LEN])
+            .map(|(t3, _)| self.write_us = t3.wrapping_sub(before_write));
+        let standby = tx.into_standby();
+        if let Err(e) = result {
+            return Err((standby, e));
+        }
+        match standby.rx() {
+            Ok(rx) => Ok(rx),
+            Err((device, e)) => Err((StandbyMode::from_rx_tx(device), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use config::Configuration;
+    use mock::{MockRadio, TestClock, NoDelay};
+
+    const TIMING: LinkTiming = LinkTiming {
+        rate: DataRate::R1Mbps,
+        addr_bytes: 5,
+        crc: Some(CrcMode::TwoBytes),
+    };
+
+    fn beacon(level: u8, now: u32) -> [u8; BEACON_LEN] {
+        let mut buf = [0; BEACON_LEN];
+        buf[0] = BEACON;
+        buf[1] = level;
+        write_u32(&mut buf[2..6], now);
+        buf
+    }
+
+    #[test]
+    fn air_time() {
+        // 8 * (1 + 5 + 32 + 2) + 9 bits
+        assert_eq!(TIMING.air_time_us(32), 329);
+        assert_eq!(LinkTiming { rate: DataRate::R2Mbps, ..TIMING }.air_time_us(32), 165);
+        assert_eq!(LinkTiming { rate: DataRate::R250Kbps, ..TIMING }.air_time_us(32), 1316);
+        let timing = LinkTiming { addr_bytes: 3, crc: None, ..TIMING };
+        assert_eq!(timing.air_time_us(0), 41);
+        assert_eq!(timing.delay_us(0), TX_SETTLING_US + 41);
+    }
+
+    #[test]
+    fn beacon_offset() {
+        let mut sync = TimeSync::new(TIMING);
+        assert!(sync.handle_beacon(&beacon(0, 10_000), 4_000));
+        // Sent at 10000, on air for 130 + 121 µs
+        assert_eq!(sync.offset_us(), Some(6_251));
+        assert_eq!(sync.drift_ppm(), None);
+        assert_eq!(sync.network_time(4_000), Some(10_251));
+        assert_eq!(sync.level(), Some(1));
+        // Not closer to the reference
+        assert!(!sync.handle_beacon(&beacon(1, 20_000), 14_000));
+    }
+
+    #[test]
+    fn beacon_drift() {
+        let mut sync = TimeSync::new(TIMING);
+        let delay = TIMING.delay_us(BEACON_LEN);
+        for i in 0..SAMPLES as u32 {
+            // Network time runs 100 ppm fast
+            let local = i * 1_000_000;
+            let network = 5_000 + local + 100 * i;
+            assert!(sync.handle_beacon(&beacon(0, network - delay), local));
+        }
+        assert_eq!(sync.drift_ppm(), Some(100));
+        assert_eq!(sync.offset_us(), Some(5_700));
+        assert_eq!(sync.network_time(8_000_000), Some(8_005_800));
+    }
+
+    #[test]
+    fn drift_is_bounded() {
+        let mut sync = TimeSync::new(TIMING);
+        let delay = TIMING.delay_us(BEACON_LEN);
+        sync.handle_beacon(&beacon(0, 1_000 - delay), 0);
+        sync.handle_beacon(&beacon(0, 1_000_001 - delay), 1);
+        assert_eq!(sync.drift_ppm(), Some(MAX_DRIFT_PPM as i32));
+        assert!(sync.network_time(0x7FFF_FFFF).is_some());
+        assert!(sync.network_time(0x8000_0000).is_some());
+    }
+
+    #[test]
+    fn exchange() {
+        let mut radio = MockRadio::new();
+        radio.responder = Some(Box::new(|request: &[u8]| {
+            let mut response = vec![RESPONSE, request[1]];
+            response.extend_from_slice(&request[2..6]);
+            // t2 and t3
+            response.extend_from_slice(&[0xE8, 0x03, 0, 0, 0xE8, 0x03, 0, 0]);
+            Some((0, response))
+        }));
+        let standby = StandbyMode::power_up(radio).unwrap();
+        let mut sync = TimeSync::new(TIMING);
+        // Every reading advances by 10 µs
+        let mut clock = TestClock::new(10);
+
+        let (mut standby, sample) = sync.exchange(standby, &mut clock, &mut NoDelay, 1_000);
+        let sample = sample.unwrap().unwrap();
+        // t1 after writing the request at 10, t4 at 30
+        let delays = (TIMING.delay_us(REQUEST_LEN) + TIMING.delay_us(RESPONSE_LEN)) as i32;
+        assert_eq!(sample.residual_delay_us, 20 - delays);
+        let forward = 1_000 - 10 - TIMING.delay_us(REQUEST_LEN) as i32;
+        let backward = 1_000 + TIMING.delay_us(RESPONSE_LEN) as i32 - 30;
+        assert_eq!(sample.offset_us, (forward + backward) / 2);
+        assert_eq!(sync.offset_us(), Some(sample.offset_us));
+
+        standby.device().acks.push_back(false);
+        let (mut standby, sample) = sync.exchange(standby, &mut clock, &mut NoDelay, 1_000);
+        assert_eq!(sample.ok(), Some(None));
+        standby.device().stuck = true;
+        let (_, sample) = sync.exchange(standby, &mut clock, &mut NoDelay, 1_000);
+        assert!(sample.is_err());
+    }
+
+    #[test]
+    fn serve() {
+        let mut rx = StandbyMode::power_up(MockRadio::new()).unwrap().rx().unwrap();
+        let mut server = TimeSyncServer::new();
+        let mut clock = TestClock::new(10);
+
+        for seq in 0..2 {
+            rx.device().receive(1, &[REQUEST, seq, 1, 2, 3, 4]);
+            rx = server.serve(rx, &mut clock, &mut NoDelay).unwrap();
+        }
+        assert_eq!(server.requests(), 2);
+        let sent = &rx.device().sent;
+        // t2 on reading, t3 before writing, sending after writing
+        assert_eq!(sent[0].data, vec![RESPONSE, 0, 1, 2, 3, 4, 0, 0, 0, 0, 10, 0, 0, 0]);
+        // Ahead by the 10 µs it took to write the first response
+        assert_eq!(sent[1].data, vec![RESPONSE, 1, 1, 2, 3, 4, 30, 0, 0, 0, 50, 0, 0, 0]);
+    }
+
+    #[test]
+    fn send_beacons() {
+        let mut tx = StandbyMode::power_up(MockRadio::new()).unwrap().tx().unwrap();
+        send_beacon(&mut tx, &mut NoDelay, 2, 0x1234).unwrap();
+        assert_eq!(tx.device().sent[0].data, beacon(2, 0x1234).to_vec());
+
+        tx.device().stuck = true;
+        assert!(send_beacon(&mut tx, &mut NoDelay, 2, 0x1234).is_err());
+    }
+}
@@ -1,17 +1,51 @@
 use core::fmt;
-use command::WriteTxPayload;
+use embedded_hal::blocking::delay::DelayUs;
+use command::{WriteTxPayload, WriteTxPayloadNoAck, ReadRxPayload};
 use registers::{Status, FifoStatus, ObserveTx};
 use device::Device;
 use standby::StandbyMode;
 use config::Configuration;
+use error::ModeError;
+#[cfg(feature = "serde")]
+use error::MessageError;
+use rx::payload_width;
+use payload::Payload;
+use clock::Clock;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// Packets the TX FIFO holds
+const TX_FIFO_DEPTH: u8 = 3;
+/// Interval for polling the chip in `wait_empty_timeout()`
+const POLL_INTERVAL_US: u32 = 50;
+
+/// Longest time the chip may stay in TX mode, per datasheet
+pub const MAX_TX_DWELL_US: u32 = 4000;
+/// Shortest `CE` pulse that starts a transmission
+pub const MIN_CE_PULSE_US: u32 = 10;
+/// Outlasts 15 auto retransmits with the longest delay, for
+/// `send_sync_timeout()` in the protocol modules
+pub(crate) const SEND_TIMEOUT_US: u32 = 100_000;
 
 /// Represents **TX Mode** and the associated **TX Settling** and
 /// **Standby-II** states
 ///
 /// **" It is important to never keep the nRF24L01 in TX mode for more
 /// than 4ms at a time."**
+///
+/// `send_pulse()` and `wait_empty_dwell()` take care of that, and
+/// `check_dwell()` does after any method that enables `CE`.
 pub struct TxMode<D: Device> {
     device: D,
+    ce_enabled: bool,
+    /// When `CE` was enabled, as far as known from the `Clock`
+    ce_since: Option<u32>,
+    /// Latest reading of the `Clock` passed to a timed method
+    last_now: Option<u32>,
+    /// Packets written to the TX FIFO but not yet reported as done
+    in_flight: u8,
+    /// Invalid ack payload widths reported by the chip
+    corrupt_payloads: u32,
 }
 
 impl<D: Device> fmt::Debug for TxMode<D> {
@@ -20,11 +54,35 @@ impl<D: Device> fmt::Debug for TxMode<D> {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl<D: Device + defmt::Format> defmt::Format for TxMode<D> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "TxMode {{ device: {}, in_flight: {=u8} }}", self.device, self.in_flight)
+    }
+}
+
+#[cfg(feature = "ufmt")]
+impl<D: Device + ufmt::uDebug> ufmt::uDebug for TxMode<D> {
+    fn fmt<W: ufmt::uWrite + ?Sized>(&self, f: &mut ufmt::Formatter<W>) -> Result<(), W::Error> {
+        f.debug_struct("TxMode")?
+            .field("device", &self.device)?
+            .field("in_flight", &self.in_flight)?
+            .finish()
+    }
+}
+
 impl<D: Device> TxMode<D> {
     /// Relies on everything being set up by `StandbyMode::tx()`, from
     /// which it is called
     pub(crate) fn new(device: D) -> Self {
-        TxMode { device }
+        TxMode {
+            device,
+            ce_enabled: false,
+            ce_since: None,
+            last_now: None,
+            in_flight: 0,
+            corrupt_payloads: 0,
+        }
     }
 
     /// Disable `CE` so that you can switch into RX mode.
@@ -35,6 +93,11 @@ impl<D: Device> TxMode<D> {
         }
     }
 
+    /// Disable `CE` without waiting for the TX FIFO
+    pub(crate) fn into_standby(self) -> StandbyMode<D> {
+        StandbyMode::from_rx_tx(self.device)
+    }
+
     /// Is TX FIFO empty?
     pub fn is_empty(&mut self) -> Result<bool, D::Error> {
         let (_, fifo_status) =
@@ -55,51 +118,322 @@ impl<D: Device> TxMode<D> {
         Ok(!full)
     }
 
+    /// Number of packets sent but not yet reported done by
+    /// `poll_tx_complete()` or one of the `wait_empty*()` methods
+    pub fn in_flight(&self) -> u8 {
+        self.in_flight
+    }
+
     /// Send asynchronously
     pub fn send(&mut self, packet: &[u8]) -> Result<(), D::Error> {
-        self.device.send_command(&WriteTxPayload::new(packet))?;
-        self.device.ce_enable();
+        self.write_payload(packet)?;
+        self.ce_enable();
+        Ok(())
+    }
+
+    /// Send asynchronously without asking the receiver for an ack.
+    /// Requires `set_dynamic_ack(true)`.
+    pub fn send_no_ack(&mut self, packet: &[u8]) -> Result<(), D::Error> {
+        self.write_payload_no_ack(packet)?;
+        self.ce_enable();
+        Ok(())
+    }
+
+    /// Send without blocking, returns `WouldBlock` while the TX FIFO
+    /// is full
+    pub fn try_send(&mut self, packet: &[u8]) -> nb::Result<(), D::Error> {
+        if self.is_full()? {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.send(packet)?;
+        Ok(())
+    }
+
+    /// Check on the packets in flight without blocking
+    ///
+    /// Returns `WouldBlock` while the TX FIFO is still being sent,
+    /// `true` once it is empty, and `false` if a packet reached the
+    /// maximum number of retransmits. In that case the TX FIFO has
+    /// been flushed.
+    pub fn poll_tx_complete(&mut self) -> nb::Result<bool, D::Error> {
+        let (status, fifo_status) =
+            self.device.read_register::<FifoStatus>()?;
+        if status.tx_ds() || status.max_rt() {
+            let mut clear = Status(0);
+            clear.set_tx_ds(true);
+            clear.set_max_rt(true);
+            self.device.write_register(clear)?;
+        }
+
+        if status.max_rt() {
+            self.flush_tx()?;
+            self.ce_disable();
+            self.in_flight = 0;
+            Ok(false)
+        } else if fifo_status.tx_empty() {
+            self.ce_disable();
+            self.in_flight = 0;
+            Ok(true)
+        } else {
+            if status.tx_ds() {
+                self.in_flight = self.in_flight.saturating_sub(1);
+            }
+            self.bound_in_flight(&fifo_status);
+            // Resume after `check_dwell()`
+            self.ce_enable();
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Serialize `message` with postcard and send it asynchronously
+    ///
+    /// Fails with `Error::MessageTooLarge` if the encoding exceeds
+    /// the 32 bytes of a payload.
+    #[cfg(feature = "serde")]
+    pub fn send_message<T: Serialize>(&mut self, message: &T) -> Result<(), D::Error>
+    where
+        D::Error: MessageError,
+    {
+        let mut buf = [0; 32];
+        let packet = ::postcard::to_slice(message, &mut buf)
+            .map_err(D::Error::message)?;
+        self.send(packet)
+    }
+
+    /// Send one packet with a `CE` pulse of `MIN_CE_PULSE_US`
+    ///
+    /// The chip transmits the packet and falls back to **Standby-I**
+    /// on its own, so it never exceeds the maximum TX dwell time.
+    pub fn send_pulse<DL: DelayUs<u32>>(&mut self, packet: &[u8], delay: &mut DL) -> Result<(), D::Error> {
+        self.write_payload(packet)?;
+        self.ce_enable();
+        delay.delay_us(MIN_CE_PULSE_US);
+        self.ce_disable();
+        Ok(())
+    }
+
+    /// Send asynchronously, remembering when `CE` went high
+    ///
+    /// Call `check_dwell()` regularly afterwards.
+    pub fn send_timed<C: Clock>(&mut self, packet: &[u8], clock: &mut C) -> Result<(), D::Error> {
+        self.write_payload(packet)?;
+        self.ce_enable_timed(clock);
         Ok(())
     }
 
+    /// Drop back to **Standby-I** if `CE` has been enabled for
+    /// `MAX_TX_DWELL_US`. Returns whether it did so.
+    ///
+    /// Methods without a `Clock` date `CE` to the latest reading of
+    /// one passed before, which errs on the early side. Without any,
+    /// the first call starts timing.
+    ///
+    /// Pending packets stay in the TX FIFO; send the next packet or
+    /// use `wait_empty_dwell()` to resume.
+    pub fn check_dwell<C: Clock>(&mut self, clock: &mut C) -> bool {
+        let now = clock.now_us();
+        self.last_now = Some(now);
+        if !self.ce_enabled {
+            return false;
+        }
+        match self.ce_since {
+            Some(since) if now.wrapping_sub(since) >= MAX_TX_DWELL_US => {
+                self.ce_disable();
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.ce_since = Some(now);
+                false
+            }
+        }
+    }
+
     pub fn send_sync(&mut self, packet: &[u8]) -> Result<bool, D::Error> {
-        self.device.send_command(&WriteTxPayload::new(packet))?;
-        self.device.ce_enable();
+        self.write_payload(packet)?;
+        self.ce_enable();
         self.wait_empty()
     }
 
+    /// Like `send_sync()`, but gives up after at least `timeout_us`
+    /// with `Error::Timeout`
+    pub fn send_sync_timeout<DL: DelayUs<u32>>(&mut self, packet: &[u8], delay: &mut DL, timeout_us: u32) -> Result<bool, D::Error> {
+        self.write_payload(packet)?;
+        self.ce_enable();
+        self.wait_empty_timeout(delay, timeout_us)
+    }
+
     /// Wait until FX FIFO is empty
     pub fn wait_empty(&mut self) -> Result<bool, D::Error> {
-        let mut empty = false;
-        let mut result = true;
-        while ! empty {
-            let (status, fifo_status) =
-                self.device.read_register::<FifoStatus>()?;
-            empty = fifo_status.tx_empty();
-            if ! empty {
-                self.device.ce_enable();
+        let result = loop {
+            if let Some(result) = self.poll_empty()? {
+                break result;
             }
+        };
+        // Can save power now
+        self.ce_disable();
+
+        Ok(result)
+    }
 
-            // TX won't continue while MAX_RT is set
-            if status.tx_ds() || status.max_rt() {
-                result = !status.max_rt();
-                let mut clear = Status(0);
-                // Clear TX interrupts
-                clear.set_tx_ds(true);
-                clear.set_max_rt(true);
-                clear.set_rx_dr(true);
-                self.device.write_register(clear)?;
-                if status.max_rt() {
-                    self.flush_tx()?;
-                }
-                break;
+    /// Like `wait_empty()`, but briefly drops back to **Standby-I**
+    /// whenever `CE` has been enabled for `MAX_TX_DWELL_US`
+    pub fn wait_empty_dwell<C: Clock>(&mut self, clock: &mut C) -> Result<bool, D::Error> {
+        loop {
+            if let Some(result) = self.poll_empty()? {
+                // Can save power now
+                self.ce_disable();
+                return Ok(result);
             }
 
+            // The next `poll_empty()` resumes transmission
+            self.check_dwell(clock);
         }
-        // Can save power now
+    }
+
+    /// Like `wait_empty()`, but gives up after at least `timeout_us`
+    /// with `Error::Timeout`
+    ///
+    /// Before timing out, `CE` is disabled, the TX FIFO is flushed
+    /// and all interrupts are cleared so that the radio can be used
+    /// again right away.
+    pub fn wait_empty_timeout<DL: DelayUs<u32>>(&mut self, delay: &mut DL, timeout_us: u32) -> Result<bool, D::Error> {
+        let mut elapsed = 0;
+        loop {
+            if let Some(result) = self.poll_empty()? {
+                // Can save power now
+                self.ce_disable();
+                return Ok(result);
+            }
+
+            if elapsed >= timeout_us {
+                self.ce_disable();
+                self.flush_tx()?;
+                self.clear_interrupts()?;
+                return Err(D::Error::timeout());
+            }
+            delay.delay_us(POLL_INTERVAL_US);
+            elapsed = elapsed.saturating_add(POLL_INTERVAL_US);
+        }
+    }
+
+    /// Queue `packet` without touching `CE`
+    pub(crate) fn write_payload(&mut self, packet: &[u8]) -> Result<(), D::Error> {
+        self.device.send_command(&WriteTxPayload::new(packet))?;
+        self.in_flight = self.in_flight.saturating_add(1);
+        Ok(())
+    }
+
+    /// Queue `packet` without ack, and without touching `CE`
+    pub(crate) fn write_payload_no_ack(&mut self, packet: &[u8]) -> Result<(), D::Error> {
+        self.device.send_command(&WriteTxPayloadNoAck::new(packet))?;
+        self.in_flight = self.in_flight.saturating_add(1);
+        Ok(())
+    }
+
+    /// For callers that track the outcomes themselves, like
+    /// `PipelinedTx`
+    pub(crate) fn set_in_flight(&mut self, in_flight: u8) {
+        self.in_flight = in_flight;
+    }
+
+    /// `TX_DS` may stand for several packets, so keep the count
+    /// within what FIFO_STATUS allows for a TX FIFO that is not empty
+    fn bound_in_flight(&mut self, fifo_status: &FifoStatus) {
+        self.in_flight = if fifo_status.tx_full() {
+            TX_FIFO_DEPTH
+        } else {
+            self.in_flight.clamp(1, TX_FIFO_DEPTH - 1)
+        };
+    }
+
+    /// Enable `CE`, dating it for `check_dwell()`
+    pub(crate) fn ce_enable(&mut self) {
+        self.device.ce_enable();
+        if !self.ce_enabled {
+            self.ce_enabled = true;
+            self.ce_since = self.last_now;
+        }
+    }
+
+    fn ce_enable_timed<C: Clock>(&mut self, clock: &mut C) {
+        let now = clock.now_us();
+        self.last_now = Some(now);
+        self.ce_enable();
+        if self.ce_since.is_none() {
+            self.ce_since = Some(now);
+        }
+    }
+
+    fn ce_disable(&mut self) {
         self.device.ce_disable();
+        self.ce_enabled = false;
+        self.ce_since = None;
+    }
 
-        Ok(result)
+    /// One step of `wait_empty()`, returns `Some(success)` when done
+    fn poll_empty(&mut self) -> Result<Option<bool>, D::Error> {
+        let (status, fifo_status) =
+            self.device.read_register::<FifoStatus>()?;
+        let empty = fifo_status.tx_empty();
+        if ! empty {
+            self.ce_enable();
+        }
+
+        // TX won't continue while MAX_RT is set
+        if status.tx_ds() || status.max_rt() {
+            let mut clear = Status(0);
+            // Clear TX interrupts
+            clear.set_tx_ds(true);
+            clear.set_max_rt(true);
+            clear.set_rx_dr(true);
+            self.device.write_register(clear)?;
+            if status.max_rt() {
+                self.flush_tx()?;
+                self.in_flight = 0;
+            } else {
+                self.in_flight = self.in_flight.saturating_sub(1);
+            }
+            return Ok(Some(!status.max_rt()));
+        }
+
+        if empty {
+            self.in_flight = 0;
+            Ok(Some(true))
+        } else {
+            self.bound_in_flight(&fifo_status);
+            Ok(None)
+        }
+    }
+
+    /// Read a payload that the receiver has sent along with an ack,
+    /// if any. Requires `set_ack_payloads(true)`.
+    ///
+    /// Fails with `Error::CorruptPayload` if the chip reports an
+    /// invalid payload width, after flushing the RX FIFO.
+    pub fn read_ack_payload(&mut self) -> Result<Option<Payload>, D::Error> {
+        let (status, fifo_status) =
+            self.device.read_register::<FifoStatus>()?;
+        if fifo_status.rx_empty() {
+            return Ok(None);
+        }
+        if status.rx_dr() {
+            let mut clear = Status(0);
+            clear.set_rx_dr(true);
+            self.device.write_register(clear)?;
+        }
+
+        let payload_width =
+            payload_width(&mut self.device, &mut self.corrupt_payloads)?;
+        let (status, mut payload) =
+            self.device.send_command(&ReadRxPayload::new(payload_width))?;
+        payload.set_pipe(status.rx_p_no());
+        Ok(Some(payload))
+    }
+
+    /// How often the chip has reported an invalid ack payload width
+    pub fn corrupt_payloads(&self) -> u32 {
+        self.corrupt_payloads
     }
 
     pub fn observe(&mut self) -> Result<ObserveTx, D::Error> {